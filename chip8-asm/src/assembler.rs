@@ -0,0 +1,1005 @@
+use std::collections::HashMap;
+
+use chip8::instruction::{Instruction, Register};
+
+use crate::error::AsmError;
+use crate::lexer::Token;
+use crate::program::{Program, SourceLine};
+
+const PROGRAM_START: usize = 0x200;
+const MEMORY_END: usize = 0x10000;
+/// Guards against macros that expand into themselves
+const MAX_EXPANSIONS: usize = 10_000;
+
+type Result<T> = std::result::Result<T, AsmError>;
+
+/// How an address is written into an already emitted instruction
+#[derive(Debug, Clone, Copy)]
+enum Fixup {
+    /// The low 12 bits of the instruction
+    Nnn,
+    /// The word following the instruction
+    Word,
+    /// The byte operand gets the high bits, behind an optional nibble
+    High(Option<u8>),
+    /// The byte operand gets the low byte
+    Low,
+}
+
+struct Macro {
+    args: Vec<String>,
+    body: Vec<Token>,
+}
+
+struct Loop {
+    start: usize,
+    /// Jumps out of the loop emitted by `while`
+    exits: Vec<usize>,
+    /// How many branches were open when the loop started
+    branches: usize,
+    token: Token,
+}
+
+/// A `begin` or `else` waiting for the jump past its body to be patched
+struct Branch {
+    jump: usize,
+    is_else: bool,
+    /// How many loops were open when the branch started
+    loops: usize,
+    token: Token,
+}
+
+pub struct Assembler {
+    tokens: Vec<Token>,
+    pos: usize,
+    here: usize,
+    program: Program,
+    consts: HashMap<String, i32>,
+    aliases: HashMap<String, Register>,
+    macros: HashMap<String, Macro>,
+    expansions: usize,
+    fixups: Vec<(usize, Fixup, Token)>,
+    loops: Vec<Loop>,
+    branches: Vec<Branch>,
+}
+
+impl Assembler {
+    pub fn new(tokens: Vec<Token>) -> Assembler {
+        Assembler {
+            tokens,
+            pos: 0,
+            here: PROGRAM_START,
+            program: Program::default(),
+            consts: HashMap::new(),
+            aliases: HashMap::new(),
+            macros: HashMap::new(),
+            expansions: 0,
+            fixups: Vec::new(),
+            loops: Vec::new(),
+            branches: Vec::new(),
+        }
+    }
+
+    pub fn assemble(mut self) -> Result<Program> {
+        // Execution starts at `main`, a jump to it is only needed when
+        // it isn't the first thing in the program
+        let starts_with_main =
+            self.tokens.len() >= 2 && self.tokens[0].is(":") && self.tokens[1].is("main");
+        if !starts_with_main {
+            let main = Token {
+                text: "main".to_string(),
+                line: 1,
+                column: 1,
+            };
+            self.fixups.push((self.here, Fixup::Nnn, main.clone()));
+            self.emit(Instruction::Jump(0), &main)?;
+        }
+
+        while let Some(token) = self.tokens.get(self.pos).cloned() {
+            self.pos += 1;
+            self.statement(token)?;
+        }
+
+        if !starts_with_main && !self.program.labels.contains_key("main") {
+            return Err(AsmError::new(
+                &self.fixups[0].2,
+                "the program has no `main` label",
+            ));
+        }
+        if let Some(unclosed) = self.loops.pop() {
+            return Err(AsmError::new(
+                &unclosed.token,
+                "`loop` without a matching `again`",
+            ));
+        }
+        if let Some(unclosed) = self.branches.pop() {
+            return Err(AsmError::new(
+                &unclosed.token,
+                format!("`{}` without a matching `end`", unclosed.token.text),
+            ));
+        }
+        for (address, fixup, token) in std::mem::take(&mut self.fixups) {
+            let target = match self.program.labels.get(&token.text) {
+                Some(&target) => target,
+                None => {
+                    return Err(AsmError::new(
+                        &token,
+                        format!("undefined label `{}`", token.text),
+                    ))
+                }
+            };
+            self.patch(address, fixup, target as i32, &token)?;
+        }
+        Ok(self.program)
+    }
+
+    fn statement(&mut self, token: Token) -> Result<()> {
+        match token.text.as_str() {
+            ":" => {
+                let name = self.name()?;
+                self.define_label(name, self.here)
+            }
+            ":next" => {
+                // Points at the operand of the following instruction,
+                // for self-modifying code
+                let name = self.name()?;
+                self.define_label(name, self.here + 1)
+            }
+            ":const" => {
+                let name = self.name()?;
+                let value = self.number()?;
+                self.consts.insert(name.text, value);
+                Ok(())
+            }
+            ":alias" => {
+                let name = self.name()?;
+                let register = self.register()?;
+                self.aliases.insert(name.text, register);
+                Ok(())
+            }
+            ":macro" => self.define_macro(),
+            ":org" => {
+                let address = self.number()?;
+                if address < PROGRAM_START as i32 || address >= MEMORY_END as i32 {
+                    return Err(AsmError::new(
+                        &self.tokens[self.pos - 1],
+                        format!("`:org` address {:#X} is outside the program", address),
+                    ));
+                }
+                self.here = address as usize;
+                Ok(())
+            }
+            ":byte" => {
+                let byte = self.byte()?;
+                self.data(byte, &token)
+            }
+            ":call" => self.address_instruction(Instruction::Call(0), &token),
+            ":unpack" => self.unpack(&token),
+            ":breakpoint" => {
+                let name = self.name()?;
+                self.program.breakpoints.insert(name.text, self.here as u16);
+                Ok(())
+            }
+            ":monitor" => {
+                self.next()?;
+                self.next()?;
+                Ok(())
+            }
+            ";" | "return" => self.emit(Instruction::Return, &token),
+            "clear" => self.emit(Instruction::ClearDisplay, &token),
+            "hires" => self.emit(Instruction::HighRes, &token),
+            "lores" => self.emit(Instruction::LowRes, &token),
+            "exit" => self.emit(Instruction::Exit, &token),
+            "scroll-left" => self.emit(Instruction::ScrollLeft, &token),
+            "scroll-right" => self.emit(Instruction::ScrollRight, &token),
+            "scroll-down" => {
+                let n = self.nibble(15)?;
+                self.emit(Instruction::ScrollDown(n), &token)
+            }
+            "scroll-up" => {
+                let n = self.nibble(15)?;
+                self.emit(Instruction::ScrollUp(n), &token)
+            }
+            "plane" => {
+                let n = self.nibble(3)?;
+                self.emit(Instruction::SelectPlanes(n), &token)
+            }
+            "audio" => self.emit(Instruction::LoadAudio, &token),
+            "bcd" => {
+                let x = self.register()?;
+                self.emit(Instruction::BCDRepresentation(x), &token)
+            }
+            "save" | "load" => {
+                let x = self.register()?;
+                let range = self.peek().is_some_and(|next| next.is("-"));
+                let instruction = if range {
+                    self.next()?;
+                    let y = self.register()?;
+                    if token.is("save") {
+                        Instruction::SaveRange(x, y)
+                    } else {
+                        Instruction::LoadRange(x, y)
+                    }
+                } else if token.is("save") {
+                    Instruction::StoreRegisters(x)
+                } else {
+                    Instruction::LoadRegisters(x)
+                };
+                self.emit(instruction, &token)
+            }
+            "saveflags" => {
+                let x = self.register()?;
+                self.emit(Instruction::StoreFlags(x), &token)
+            }
+            "loadflags" => {
+                let x = self.register()?;
+                self.emit(Instruction::LoadFlags(x), &token)
+            }
+            "sprite" => {
+                let x = self.register()?;
+                let y = self.register()?;
+                let n = self.nibble(15)?;
+                self.emit(Instruction::Draw(x, y, n), &token)
+            }
+            "jump" => self.address_instruction(Instruction::Jump(0), &token),
+            "jump0" => self.address_instruction(Instruction::JumpPlusZero(0), &token),
+            "delay" | "buzzer" | "pitch" => {
+                self.expect(":=")?;
+                let x = self.register()?;
+                let instruction = match token.text.as_str() {
+                    "delay" => Instruction::SetDelayTimer(x),
+                    "buzzer" => Instruction::SetSoundTimer(x),
+                    _ => Instruction::SetPitch(x),
+                };
+                self.emit(instruction, &token)
+            }
+            "i" => self.i_statement(&token),
+            "if" => self.if_statement(token),
+            "else" => {
+                let branch = match self.branches.pop() {
+                    Some(branch) if !branch.is_else => branch,
+                    _ => return Err(AsmError::new(&token, "`else` without a matching `begin`")),
+                };
+                self.check_loops(&branch, &token)?;
+                let jump = self.here;
+                self.emit(Instruction::Jump(0), &token)?;
+                self.patch(branch.jump, Fixup::Nnn, self.here as i32, &token)?;
+                self.branches.push(Branch {
+                    jump,
+                    is_else: true,
+                    loops: branch.loops,
+                    token,
+                });
+                Ok(())
+            }
+            "end" => {
+                let branch = self
+                    .branches
+                    .pop()
+                    .ok_or_else(|| AsmError::new(&token, "`end` without a matching `begin`"))?;
+                self.check_loops(&branch, &token)?;
+                self.patch(branch.jump, Fixup::Nnn, self.here as i32, &token)
+            }
+            "loop" => {
+                self.loops.push(Loop {
+                    start: self.here,
+                    exits: Vec::new(),
+                    branches: self.branches.len(),
+                    token,
+                });
+                Ok(())
+            }
+            "while" => {
+                if self.loops.is_empty() {
+                    return Err(AsmError::new(&token, "`while` outside of a loop"));
+                }
+                self.condition(true)?;
+                let jump = self.here;
+                self.emit(Instruction::Jump(0), &token)?;
+                self.loops.last_mut().unwrap().exits.push(jump);
+                Ok(())
+            }
+            "again" => {
+                let repeated = self
+                    .loops
+                    .pop()
+                    .ok_or_else(|| AsmError::new(&token, "`again` without a matching `loop`"))?;
+                if self.branches.len() > repeated.branches {
+                    let open = &self.branches[self.branches.len() - 1].token;
+                    return Err(AsmError::new(
+                        &token,
+                        format!(
+                            "`again` before the `end` of the `{}` at line {}",
+                            open.text, open.line
+                        ),
+                    ));
+                }
+                let jump = self.here;
+                self.emit(Instruction::Jump(0), &token)?;
+                self.patch(jump, Fixup::Nnn, repeated.start as i32, &token)?;
+                for exit in repeated.exits {
+                    self.patch(exit, Fixup::Nnn, self.here as i32, &token)?;
+                }
+                Ok(())
+            }
+            _ => {
+                if let Some(x) = self.as_register(&token) {
+                    self.register_statement(x, &token)
+                } else if self.macros.contains_key(&token.text) {
+                    self.expand_macro(&token)
+                } else if let Some(value) =
+                    parse_number(&token.text).or_else(|| self.consts.get(&token.text).copied())
+                {
+                    let byte = self.check_byte(value, &token)?;
+                    self.data(byte, &token)
+                } else if token.text.starts_with(':') {
+                    Err(AsmError::new(
+                        &token,
+                        format!("unsupported directive `{}`", token.text),
+                    ))
+                } else if is_identifier(&token.text) {
+                    // A bare label is a call
+                    self.pos -= 1;
+                    self.address_instruction(Instruction::Call(0), &token)
+                } else {
+                    Err(AsmError::new(
+                        &token,
+                        format!("unexpected `{}`", token.text),
+                    ))
+                }
+            }
+        }
+    }
+
+    fn register_statement(&mut self, x: Register, token: &Token) -> Result<()> {
+        let op = self.next()?;
+        let instruction = match op.text.as_str() {
+            ":=" => {
+                let source = self.next()?;
+                match source.text.as_str() {
+                    "random" => Instruction::Random(x, self.byte()?),
+                    "key" => Instruction::WaitForKeyPress(x),
+                    "delay" => Instruction::LoadDelayTimer(x),
+                    _ => match self.as_register(&source) {
+                        Some(y) => Instruction::Move(x, y),
+                        None => Instruction::LoadByte(x, self.byte_token(&source)?),
+                    },
+                }
+            }
+            "+=" => {
+                let source = self.next()?;
+                match self.as_register(&source) {
+                    Some(y) => Instruction::Add(x, y),
+                    None => Instruction::AddByte(x, self.byte_token(&source)?),
+                }
+            }
+            "-=" => {
+                let source = self.next()?;
+                match self.as_register(&source) {
+                    Some(y) => Instruction::Sub(x, y),
+                    None => Instruction::AddByte(x, self.byte_token(&source)?.wrapping_neg()),
+                }
+            }
+            "=-" => Instruction::ReverseSub(x, self.register()?),
+            "|=" => Instruction::Or(x, self.register()?),
+            "&=" => Instruction::And(x, self.register()?),
+            "^=" => Instruction::Xor(x, self.register()?),
+            ">>=" => Instruction::ShiftRight(x, self.register()?),
+            "<<=" => Instruction::ShiftLeft(x, self.register()?),
+            _ => {
+                return Err(AsmError::new(
+                    &op,
+                    format!("unknown register operation `{}`", op.text),
+                ))
+            }
+        };
+        self.emit(instruction, token)
+    }
+
+    fn i_statement(&mut self, token: &Token) -> Result<()> {
+        let op = self.next()?;
+        match op.text.as_str() {
+            ":=" => {
+                let source = self.peek().cloned();
+                match source.as_ref().map(|source| source.text.as_str()) {
+                    Some("long") => {
+                        self.next()?;
+                        let at = self.here;
+                        self.emit(Instruction::LoadLongI(0), token)?;
+                        self.address(at, Fixup::Word)
+                    }
+                    Some("hex") | Some("bighex") => {
+                        self.next()?;
+                        let x = self.register()?;
+                        if source.unwrap().is("hex") {
+                            self.emit(Instruction::LoadSprite(x), token)
+                        } else {
+                            self.emit(Instruction::LoadBigSprite(x), token)
+                        }
+                    }
+                    _ => self.address_instruction(Instruction::LoadI(0), token),
+                }
+            }
+            "+=" => {
+                let x = self.register()?;
+                self.emit(Instruction::AddToI(x), token)
+            }
+            _ => Err(AsmError::new(
+                &op,
+                format!("unknown `i` operation `{}`", op.text),
+            )),
+        }
+    }
+
+    fn if_statement(&mut self, token: Token) -> Result<()> {
+        // The condition is compiled differently for `then` and `begin`,
+        // so look past it first
+        let is_key = self
+            .tokens
+            .get(self.pos + 1)
+            .is_some_and(|op| op.is("key") || op.is("-key"));
+        let length = if is_key { 2 } else { 3 };
+        match self.tokens.get(self.pos + length).map(|t| t.text.as_str()) {
+            Some("then") => {
+                self.condition(false)?;
+                self.next()?;
+                Ok(())
+            }
+            Some("begin") => {
+                self.condition(true)?;
+                self.next()?;
+                let jump = self.here;
+                self.emit(Instruction::Jump(0), &token)?;
+                self.branches.push(Branch {
+                    jump,
+                    is_else: false,
+                    loops: self.loops.len(),
+                    token,
+                });
+                Ok(())
+            }
+            _ => Err(AsmError::new(
+                &token,
+                "expected `then` or `begin` after the condition",
+            )),
+        }
+    }
+
+    /// Fails when a loop started inside the branch is still open, blocks
+    /// have to nest
+    fn check_loops(&self, branch: &Branch, token: &Token) -> Result<()> {
+        match self.loops.get(branch.loops) {
+            Some(open) => Err(AsmError::new(
+                token,
+                format!(
+                    "`{}` before the `again` of the `loop` at line {}",
+                    token.text, open.token.line
+                ),
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// Emits a skip over the next instruction when the condition doesn't hold,
+    /// or when it does if `negated` is set
+    fn condition(&mut self, negated: bool) -> Result<()> {
+        let x = self.register()?;
+        let op = self.next()?;
+        let comparison = match (op.text.as_str(), negated) {
+            ("==", false) | ("!=", true) => "==",
+            ("!=", false) | ("==", true) => "!=",
+            ("key", false) | ("-key", true) => "key",
+            ("-key", false) | ("key", true) => "-key",
+            ("<", false) | (">=", true) => "<",
+            (">=", false) | ("<", true) => ">=",
+            (">", false) | ("<=", true) => ">",
+            ("<=", false) | (">", true) => "<=",
+            _ => {
+                return Err(AsmError::new(
+                    &op,
+                    format!("unknown comparison `{}`", op.text),
+                ))
+            }
+        };
+        match comparison {
+            "key" => return self.emit(Instruction::SkipIfNotPressed(x), &op),
+            "-key" => return self.emit(Instruction::SkipIfPressed(x), &op),
+            _ => {}
+        }
+        let rhs = self.next()?;
+        let register = self.as_register(&rhs);
+        let byte = match register {
+            Some(_) => 0,
+            None => self.byte_token(&rhs)?,
+        };
+        match (comparison, register) {
+            ("==", Some(y)) => self.emit(Instruction::SkipIfNotEqual(x, y), &op),
+            ("==", None) => self.emit(Instruction::SkipIfNotEqualsByte(x, byte), &op),
+            ("!=", Some(y)) => self.emit(Instruction::SkipIfEqual(x, y), &op),
+            ("!=", None) => self.emit(Instruction::SkipIfEqualsByte(x, byte), &op),
+            _ => {
+                // Compare through VF: load it with the right hand side,
+                // subtract and check the borrow flag
+                match register {
+                    Some(y) => self.emit(Instruction::Move(0xF, y), &op)?,
+                    None => self.emit(Instruction::LoadByte(0xF, byte), &op)?,
+                }
+                if comparison == "<" || comparison == ">=" {
+                    // VF = 1 when x >= rhs
+                    self.emit(Instruction::ReverseSub(0xF, x), &op)?;
+                } else {
+                    // VF = 1 when x <= rhs
+                    self.emit(Instruction::Sub(0xF, x), &op)?;
+                }
+                let skip_on = if comparison == "<" || comparison == ">" {
+                    1
+                } else {
+                    0
+                };
+                self.emit(Instruction::SkipIfEqualsByte(0xF, skip_on), &op)
+            }
+        }
+    }
+
+    fn unpack(&mut self, token: &Token) -> Result<()> {
+        let prefix = self.next()?;
+        let nibble = if prefix.is("long") {
+            None
+        } else {
+            self.pos -= 1;
+            Some(self.nibble(15)?)
+        };
+        let at = self.here;
+        self.emit(Instruction::LoadByte(0, 0), token)?;
+        self.emit(Instruction::LoadByte(1, 0), token)?;
+        let target = self.next()?;
+        match self.value(&target) {
+            Some(value) => {
+                self.patch(at, Fixup::High(nibble), value, &target)?;
+                self.patch(at + 2, Fixup::Low, value, &target)
+            }
+            None => {
+                self.forward(&target)?;
+                self.fixups.push((at, Fixup::High(nibble), target.clone()));
+                self.fixups.push((at + 2, Fixup::Low, target));
+                Ok(())
+            }
+        }
+    }
+
+    fn define_macro(&mut self) -> Result<()> {
+        let name = self.name()?;
+        let mut args = Vec::new();
+        loop {
+            let token = self.next()?;
+            if token.is("{") {
+                break;
+            }
+            args.push(token.text);
+        }
+        let mut body = Vec::new();
+        let mut depth = 1;
+        loop {
+            let token = self
+                .next()
+                .map_err(|_| AsmError::new(&name, format!("unterminated macro `{}`", name.text)))?;
+            if token.is("{") {
+                depth += 1;
+            } else if token.is("}") {
+                depth -= 1;
+                if depth == 0 {
+                    break;
+                }
+            }
+            body.push(token);
+        }
+        self.macros.insert(name.text, Macro { args, body });
+        Ok(())
+    }
+
+    fn expand_macro(&mut self, token: &Token) -> Result<()> {
+        self.expansions += 1;
+        if self.expansions > MAX_EXPANSIONS {
+            return Err(AsmError::new(
+                token,
+                format!("macro `{}` expands too often, is it recursive?", token.text),
+            ));
+        }
+        let arg_count = self.macros[&token.text].args.len();
+        let mut values = Vec::with_capacity(arg_count);
+        for _ in 0..arg_count {
+            values.push(self.next()?);
+        }
+        let definition = &self.macros[&token.text];
+        let expanded: Vec<Token> = definition
+            .body
+            .iter()
+            .map(|body_token| {
+                match definition
+                    .args
+                    .iter()
+                    .position(|arg| *arg == body_token.text)
+                {
+                    Some(index) => values[index].clone(),
+                    None => body_token.clone(),
+                }
+            })
+            .collect();
+        self.tokens.splice(self.pos..self.pos, expanded);
+        Ok(())
+    }
+
+    /// Emits an instruction taking an address operand from the next token
+    fn address_instruction(&mut self, instruction: Instruction, token: &Token) -> Result<()> {
+        let at = self.here;
+        self.emit(instruction, token)?;
+        self.address(at, Fixup::Nnn)
+    }
+
+    /// Fills in the address operand of the instruction at `at`, now or
+    /// once the label it refers to is defined
+    fn address(&mut self, at: usize, fixup: Fixup) -> Result<()> {
+        let target = self.next()?;
+        match self.value(&target) {
+            Some(value) => self.patch(at, fixup, value, &target),
+            None => {
+                self.forward(&target)?;
+                self.fixups.push((at, fixup, target));
+                Ok(())
+            }
+        }
+    }
+
+    /// Checks that a token can be a reference to a label defined later on
+    fn forward(&self, token: &Token) -> Result<()> {
+        if is_identifier(&token.text) && self.as_register(token).is_none() {
+            Ok(())
+        } else {
+            Err(AsmError::new(
+                token,
+                format!("expected an address, found `{}`", token.text),
+            ))
+        }
+    }
+
+    fn patch(&mut self, at: usize, fixup: Fixup, value: i32, token: &Token) -> Result<()> {
+        let limit = match fixup {
+            Fixup::Nnn | Fixup::High(Some(_)) => 0xFFF,
+            Fixup::Word | Fixup::High(None) | Fixup::Low => 0xFFFF,
+        };
+        if value < 0 || value > limit {
+            return Err(AsmError::new(
+                token,
+                format!("address {:#X} is out of range", value),
+            ));
+        }
+        let value = value as u16;
+        let index = at - PROGRAM_START;
+        let rom = &mut self.program.rom;
+        match fixup {
+            Fixup::Nnn => {
+                rom[index] = (rom[index] & 0xF0) | (value >> 8) as u8;
+                rom[index + 1] = value as u8;
+            }
+            Fixup::Word => {
+                rom[index + 2] = (value >> 8) as u8;
+                rom[index + 3] = value as u8;
+            }
+            Fixup::High(nibble) => {
+                rom[index + 1] = (nibble.unwrap_or(0) << 4) | (value >> 8) as u8;
+            }
+            Fixup::Low => rom[index + 1] = value as u8,
+        }
+        Ok(())
+    }
+
+    fn emit(&mut self, instruction: Instruction, token: &Token) -> Result<()> {
+        let raw = instruction.to_raw();
+        let [high, low] = raw.value().to_be_bytes();
+        self.mark_line(token);
+        self.write(high, token)?;
+        self.write(low, token)?;
+        if let Some(operand) = raw.operand() {
+            let [high, low] = operand.to_be_bytes();
+            self.write(high, token)?;
+            self.write(low, token)?;
+        }
+        Ok(())
+    }
+
+    fn data(&mut self, byte: u8, token: &Token) -> Result<()> {
+        self.mark_line(token);
+        self.write(byte, token)
+    }
+
+    fn mark_line(&mut self, token: &Token) {
+        let lines = &mut self.program.lines;
+        if lines.last().is_none_or(|last| last.line != token.line) {
+            lines.push(SourceLine {
+                address: self.here as u16,
+                line: token.line,
+            });
+        }
+    }
+
+    fn write(&mut self, byte: u8, token: &Token) -> Result<()> {
+        if self.here >= MEMORY_END {
+            return Err(AsmError::new(token, "the program doesn't fit into memory"));
+        }
+        let index = self.here - PROGRAM_START;
+        let rom = &mut self.program.rom;
+        if rom.len() <= index {
+            rom.resize(index + 1, 0);
+        }
+        rom[index] = byte;
+        self.here += 1;
+        Ok(())
+    }
+
+    fn define_label(&mut self, name: Token, address: usize) -> Result<()> {
+        if self.program.labels.contains_key(&name.text) {
+            return Err(AsmError::new(
+                &name,
+                format!("label `{}` is already defined", name.text),
+            ));
+        }
+        self.program.labels.insert(name.text, address as u16);
+        Ok(())
+    }
+
+    fn next(&mut self) -> Result<Token> {
+        match self.tokens.get(self.pos) {
+            Some(token) => {
+                self.pos += 1;
+                Ok(token.clone())
+            }
+            None => {
+                let last = self.tokens.last().cloned().unwrap_or(Token {
+                    text: String::new(),
+                    line: 1,
+                    column: 1,
+                });
+                Err(AsmError::new(&last, "unexpected end of file"))
+            }
+        }
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn expect(&mut self, text: &str) -> Result<()> {
+        let token = self.next()?;
+        if token.is(text) {
+            Ok(())
+        } else {
+            Err(AsmError::new(
+                &token,
+                format!("expected `{}`, found `{}`", text, token.text),
+            ))
+        }
+    }
+
+    fn name(&mut self) -> Result<Token> {
+        let token = self.next()?;
+        if is_identifier(&token.text) && self.as_register(&token).is_none() {
+            Ok(token)
+        } else {
+            Err(AsmError::new(
+                &token,
+                format!("`{}` is not a valid name", token.text),
+            ))
+        }
+    }
+
+    fn register(&mut self) -> Result<Register> {
+        let token = self.next()?;
+        self.as_register(&token).ok_or_else(|| {
+            AsmError::new(
+                &token,
+                format!("expected a register, found `{}`", token.text),
+            )
+        })
+    }
+
+    fn as_register(&self, token: &Token) -> Option<Register> {
+        if let Some(&register) = self.aliases.get(&token.text) {
+            return Some(register);
+        }
+        let mut chars = token.text.chars();
+        match (chars.next(), chars.next(), chars.next()) {
+            (Some('v'), Some(digit), None) | (Some('V'), Some(digit), None) => {
+                digit.to_digit(16).map(|register| register as Register)
+            }
+            _ => None,
+        }
+    }
+
+    /// A number, constant or label that's already defined
+    fn value(&self, token: &Token) -> Option<i32> {
+        parse_number(&token.text)
+            .or_else(|| self.consts.get(&token.text).copied())
+            .or_else(|| {
+                self.program
+                    .labels
+                    .get(&token.text)
+                    .map(|&address| address as i32)
+            })
+    }
+
+    fn number(&mut self) -> Result<i32> {
+        let token = self.next()?;
+        self.value(&token).ok_or_else(|| {
+            AsmError::new(&token, format!("expected a number, found `{}`", token.text))
+        })
+    }
+
+    fn byte(&mut self) -> Result<u8> {
+        let token = self.next()?;
+        self.byte_token(&token)
+    }
+
+    fn byte_token(&self, token: &Token) -> Result<u8> {
+        let value = self.value(token).ok_or_else(|| {
+            AsmError::new(token, format!("expected a number, found `{}`", token.text))
+        })?;
+        self.check_byte(value, token)
+    }
+
+    fn check_byte(&self, value: i32, token: &Token) -> Result<u8> {
+        if (-128..=255).contains(&value) {
+            Ok(value as u8)
+        } else {
+            Err(AsmError::new(
+                token,
+                format!("{} doesn't fit into a byte", value),
+            ))
+        }
+    }
+
+    fn nibble(&mut self, max: i32) -> Result<u8> {
+        let token = self.next()?;
+        match self.value(&token) {
+            Some(value) if (0..=max).contains(&value) => Ok(value as u8),
+            Some(value) => Err(AsmError::new(
+                &token,
+                format!("{} is out of range, expected 0 to {}", value, max),
+            )),
+            None => Err(AsmError::new(
+                &token,
+                format!("expected a number, found `{}`", token.text),
+            )),
+        }
+    }
+}
+
+fn parse_number(text: &str) -> Option<i32> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, text),
+    };
+    let value = if let Some(hex) = digits.strip_prefix("0x") {
+        i32::from_str_radix(hex, 16).ok()?
+    } else if let Some(binary) = digits.strip_prefix("0b") {
+        i32::from_str_radix(binary, 2).ok()?
+    } else if digits.starts_with(|c: char| c.is_ascii_digit()) {
+        digits.parse().ok()?
+    } else {
+        return None;
+    };
+    Some(if negative { -value } else { value })
+}
+
+fn is_identifier(text: &str) -> bool {
+    let mut chars = text.chars();
+    chars
+        .next()
+        .is_some_and(|first| first.is_ascii_alphabetic() || first == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assemble;
+
+    fn rom(source: &str) -> Vec<u8> {
+        assemble(source).unwrap().rom
+    }
+
+    fn error(source: &str) -> String {
+        assemble(source).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn instructions() {
+        let source = ": main v0 := 5 v1 += 2 i := 0x300 sprite v0 v1 3 return";
+        assert_eq!(
+            rom(source),
+            [0x60, 0x05, 0x71, 0x02, 0xA3, 0x00, 0xD0, 0x13, 0x00, 0xEE]
+        );
+    }
+
+    #[test]
+    fn jumps_to_main_after_data() {
+        let source = ": data 0x12 0x34 : main jump data";
+        assert_eq!(rom(source), [0x12, 0x04, 0x12, 0x34, 0x12, 0x02]);
+    }
+
+    #[test]
+    fn if_then() {
+        assert_eq!(
+            rom(": main if v0 == 3 then v1 := 1"),
+            [0x40, 0x03, 0x61, 0x01]
+        );
+    }
+
+    #[test]
+    fn if_begin_else_end() {
+        let source = ": main if v0 == 3 begin v1 := 1 else v1 := 2 end";
+        assert_eq!(
+            rom(source),
+            [0x30, 0x03, 0x12, 0x08, 0x61, 0x01, 0x12, 0x0A, 0x61, 0x02]
+        );
+    }
+
+    #[test]
+    fn loop_while_again() {
+        let source = ": main loop v0 += 1 while v0 != 10 again";
+        assert_eq!(
+            rom(source),
+            [0x70, 0x01, 0x40, 0x0A, 0x12, 0x08, 0x12, 0x00]
+        );
+    }
+
+    #[test]
+    fn nested_blocks() {
+        let source = ": main loop if v0 == 1 begin v1 := 1 end again";
+        assert_eq!(
+            rom(source),
+            [0x30, 0x01, 0x12, 0x06, 0x61, 0x01, 0x12, 0x00]
+        );
+    }
+
+    #[test]
+    fn macros() {
+        assert_eq!(
+            rom(":macro inc r { r += 1 } : main inc v3"),
+            [0x12, 0x02, 0x73, 0x01]
+        );
+    }
+
+    #[test]
+    fn again_inside_begin() {
+        let source = ": main\nloop\nif v0 == 1 begin\nagain\nend";
+        assert_eq!(
+            error(source),
+            "4:1: `again` before the `end` of the `if` at line 3"
+        );
+    }
+
+    #[test]
+    fn end_inside_loop() {
+        let source = ": main\nif v0 == 1 begin\nloop\nend\nagain";
+        assert_eq!(
+            error(source),
+            "4:1: `end` before the `again` of the `loop` at line 3"
+        );
+    }
+
+    #[test]
+    fn else_inside_loop() {
+        let source = ": main\nif v0 == 1 begin\nloop\nelse\nagain\nend";
+        assert_eq!(
+            error(source),
+            "4:1: `else` before the `again` of the `loop` at line 3"
+        );
+    }
+
+    #[test]
+    fn unclosed_loop() {
+        assert_eq!(
+            error(": main\nloop"),
+            "2:1: `loop` without a matching `again`"
+        );
+    }
+}
@@ -0,0 +1,30 @@
+use std::error::Error;
+use std::fmt;
+
+use crate::lexer::Token;
+
+/// An error in the source, pointing at the token that caused it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AsmError {
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl AsmError {
+    pub fn new(token: &Token, message: impl Into<String>) -> AsmError {
+        AsmError {
+            line: token.line,
+            column: token.column,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.column, self.message)
+    }
+}
+
+impl Error for AsmError {}
@@ -0,0 +1,39 @@
+/// A whitespace separated word of the source, along with where it starts
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Token {
+    pub text: String,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Token {
+    pub fn is(&self, text: &str) -> bool {
+        self.text == text
+    }
+}
+
+/// Splits the source into tokens, dropping `#` comments
+pub fn tokenize(source: &str) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    for (index, line) in source.lines().enumerate() {
+        let mut chars = line.chars().enumerate().peekable();
+        while let Some((column, c)) = chars.next() {
+            if c.is_whitespace() {
+                continue;
+            }
+            if c == '#' {
+                break;
+            }
+            let mut text = c.to_string();
+            while let Some((_, c)) = chars.next_if(|(_, c)| !c.is_whitespace()) {
+                text.push(c);
+            }
+            tokens.push(Token {
+                text,
+                line: index + 1,
+                column: column + 1,
+            });
+        }
+    }
+    tokens
+}
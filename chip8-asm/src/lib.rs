@@ -0,0 +1,19 @@
+//! An assembler for the Octo dialect of CHIP-8 assembly.
+//!
+//! Supports labels, `:const`, `:alias`, `:macro`, `loop`/`while`/`again`,
+//! `if ... then`, `if ... begin ... else ... end`, data bytes, `:byte`,
+//! `:org`, `:call`, `:unpack`, `:next` and `:breakpoint`. `:calc` and
+//! strings aren't supported.
+
+mod assembler;
+mod error;
+mod lexer;
+mod program;
+
+pub use crate::error::AsmError;
+pub use crate::program::{Program, SourceLine};
+
+/// Assembles Octo source into a program that's loaded at `0x200`
+pub fn assemble(source: &str) -> Result<Program, AsmError> {
+    assembler::Assembler::new(lexer::tokenize(source)).assemble()
+}
@@ -0,0 +1,44 @@
+use std::fs::{self, File};
+use std::io::BufWriter;
+use std::path::PathBuf;
+use std::process;
+
+fn main() {
+    let mut args = std::env::args().skip(1);
+    let source_path = match args.next() {
+        Some(path) => PathBuf::from(path),
+        None => {
+            eprintln!("usage: chip8-asm SOURCE [OUTPUT]");
+            process::exit(2);
+        }
+    };
+    let rom_path = args
+        .next()
+        .map(PathBuf::from)
+        .unwrap_or_else(|| source_path.with_extension("ch8"));
+    // The label and source map is written next to the ROM
+    let map_path = rom_path.with_extension("map");
+
+    let source = fs::read_to_string(&source_path).unwrap_or_else(|err| {
+        eprintln!("Couldn't read {}: {}", source_path.display(), err);
+        process::exit(1);
+    });
+    let program = chip8_asm::assemble(&source).unwrap_or_else(|err| {
+        eprintln!("{}:{}", source_path.display(), err);
+        process::exit(1);
+    });
+
+    let written = fs::write(&rom_path, &program.rom).and_then(|()| {
+        let map = BufWriter::new(File::create(&map_path)?);
+        program.write_map(map)
+    });
+    if let Err(err) = written {
+        eprintln!("Couldn't write {}: {}", rom_path.display(), err);
+        process::exit(1);
+    }
+    println!(
+        "Assembled {} bytes into {}",
+        program.rom.len(),
+        rom_path.display()
+    );
+}
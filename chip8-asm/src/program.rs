@@ -0,0 +1,45 @@
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+
+/// The address a source line was assembled to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SourceLine {
+    pub address: u16,
+    pub line: usize,
+}
+
+/// An assembled program
+#[derive(Debug, Clone, Default)]
+pub struct Program {
+    /// The ROM image, to be loaded at `0x200`
+    pub rom: Vec<u8>,
+    pub labels: BTreeMap<String, u16>,
+    /// Addresses marked with `:breakpoint`
+    pub breakpoints: BTreeMap<String, u16>,
+    /// Where each source line ended up, in the order they were assembled
+    pub lines: Vec<SourceLine>,
+}
+
+impl Program {
+    /// Writes the labels, breakpoints and source lines, one per line:
+    ///
+    /// ```text
+    /// label main 0x202
+    /// breakpoint draw-done 0x21C
+    /// line 0x202 7
+    /// ```
+    pub fn write_map<W: Write>(&self, mut out: W) -> io::Result<()> {
+        let mut labels: Vec<_> = self.labels.iter().collect();
+        labels.sort_by_key(|&(name, address)| (*address, name));
+        for (name, address) in labels {
+            writeln!(out, "label {} {:#05X}", name, address)?;
+        }
+        for (name, address) in &self.breakpoints {
+            writeln!(out, "breakpoint {} {:#05X}", name, address)?;
+        }
+        for line in &self.lines {
+            writeln!(out, "line {:#05X} {}", line.address, line.line)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,128 @@
+use chip8::error::{Chip8Error, StepOutcome};
+use chip8::quirks::{Preset, Quirks};
+use chip8::random::XorShift;
+use chip8::trace::Tracer;
+use chip8::{Chip8, Mode, INSTRUCTION_RATE, TIMER_RATE};
+
+use crate::input::InputScript;
+
+/// How long to run a machine for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limit {
+    Cycles(u64),
+    Frames(u64),
+}
+
+/// Runs a `Chip8` without a window, ticking the timers at `TIMER_RATE`
+/// relative to `INSTRUCTION_RATE` and replaying scripted input. A frame ends
+/// with each timer tick.
+pub struct Headless {
+    pub chip8: Chip8<XorShift>,
+    input: InputScript,
+    next_event: usize,
+    frame: u64,
+    instructions: u64,
+    /// Progress towards the next timer tick, in units of `TIMER_RATE`
+    progress: u32,
+}
+
+impl Headless {
+    pub fn new(chip8: Chip8<XorShift>, input: InputScript) -> Headless {
+        let mut headless = Headless {
+            chip8,
+            input,
+            next_event: 0,
+            frame: 0,
+            instructions: 0,
+            progress: 0,
+        };
+        headless.apply_input();
+        headless
+    }
+
+    /// Loads a ROM with the mode and quirks of `preset`, or as plain CHIP-8
+    pub fn load(
+        rom: &[u8],
+        preset: Option<Preset>,
+        seed: u64,
+        input: InputScript,
+    ) -> Result<Headless, Chip8Error> {
+        let (mode, quirks) = match preset {
+            Some(preset) => (preset.mode(), preset.quirks()),
+            None => (Mode::Chip8, Quirks::default()),
+        };
+        let chip8 = Chip8::with_mode(rom, XorShift::new(seed), mode, quirks)?;
+        Ok(Headless::new(chip8, input))
+    }
+
+    /// The number of frames that ended so far
+    pub fn frames(&self) -> u64 {
+        self.frame
+    }
+
+    /// The number of instructions executed so far, unlike `Chip8::cycles`
+    /// leaving out the cycles spent waiting
+    pub fn instructions(&self) -> u64 {
+        self.instructions
+    }
+
+    /// Runs until the limit is reached or the machine halts, returning the
+    /// outcome of the last cycle
+    pub fn run(&mut self, limit: Limit) -> Result<StepOutcome, Chip8Error> {
+        self.run_with(limit, |headless| headless.chip8.run_cycle())
+    }
+
+    /// Like `run`, passing every instruction to `tracer`
+    pub fn run_traced<T: Tracer>(
+        &mut self,
+        limit: Limit,
+        tracer: &mut T,
+    ) -> Result<StepOutcome, Chip8Error> {
+        self.run_with(limit, |headless| headless.chip8.run_cycle_traced(tracer))
+    }
+
+    fn run_with<F>(&mut self, limit: Limit, mut cycle: F) -> Result<StepOutcome, Chip8Error>
+    where
+        F: FnMut(&mut Headless) -> Result<StepOutcome, Chip8Error>,
+    {
+        let mut outcome = StepOutcome::Ran;
+        while !self.reached(limit) {
+            outcome = cycle(self)?;
+            match outcome {
+                StepOutcome::Ran => self.instructions += 1,
+                StepOutcome::Halted => break,
+                StepOutcome::WaitingForKey | StepOutcome::WaitingForVBlank => {}
+            }
+            self.progress += TIMER_RATE;
+            if self.progress >= INSTRUCTION_RATE {
+                self.progress -= INSTRUCTION_RATE;
+                self.chip8.timer_tick();
+                self.frame += 1;
+                self.apply_input();
+            }
+        }
+        Ok(outcome)
+    }
+
+    fn reached(&self, limit: Limit) -> bool {
+        match limit {
+            Limit::Cycles(cycles) => self.chip8.cycles() >= cycles,
+            Limit::Frames(frames) => self.frame >= frames,
+        }
+    }
+
+    /// Presses and releases the keys scripted for the current frame
+    fn apply_input(&mut self) {
+        while let Some(event) = self.input.events.get(self.next_event) {
+            if event.frame > self.frame {
+                break;
+            }
+            if event.pressed {
+                self.chip8.handle_key_press(event.key);
+            } else {
+                self.chip8.handle_key_release(event.key);
+            }
+            self.next_event += 1;
+        }
+    }
+}
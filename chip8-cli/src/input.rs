@@ -0,0 +1,90 @@
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+/// A key press or release at the start of a frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InputEvent {
+    pub frame: u64,
+    pub key: u8,
+    pub pressed: bool,
+}
+
+/// Key input to replay, one event per line:
+///
+/// ```text
+/// # frame, press or release, key in hex
+/// 30 press 5
+/// 34 release 5
+/// ```
+///
+/// Events are sorted by frame, events in the same frame stay in order.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct InputScript {
+    pub events: Vec<InputEvent>,
+}
+
+/// A line of an input script that couldn't be parsed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InputError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for InputError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.line, self.message)
+    }
+}
+
+impl Error for InputError {}
+
+impl InputScript {
+    /// Reads a script, errors name the file
+    pub fn read(path: &Path) -> Result<InputScript, String> {
+        fs::read_to_string(path)
+            .map_err(|err| err.to_string())
+            .and_then(|text| text.parse::<InputScript>().map_err(|err| err.to_string()))
+            .map_err(|err| format!("{}: {}", path.display(), err))
+    }
+}
+
+impl FromStr for InputScript {
+    type Err = InputError;
+
+    fn from_str(text: &str) -> Result<InputScript, InputError> {
+        let mut events = Vec::new();
+        for (index, line) in text.lines().enumerate() {
+            let error = |message: &str| InputError {
+                line: index + 1,
+                message: message.to_string(),
+            };
+            let line = line.split('#').next().unwrap_or_default();
+            let fields: Vec<_> = line.split_whitespace().collect();
+            let (frame, action, key) = match fields[..] {
+                [] => continue,
+                [frame, action, key] => (frame, action, key),
+                _ => return Err(error("expected a frame, press or release, and a key")),
+            };
+            let frame = frame.parse().map_err(|_| error("invalid frame"))?;
+            let pressed = match action {
+                "press" => true,
+                "release" => false,
+                _ => return Err(error("expected press or release")),
+            };
+            let key = u8::from_str_radix(key, 16)
+                .ok()
+                .filter(|&key| key <= 0xF)
+                .ok_or_else(|| error("keys go from 0 to F"))?;
+            events.push(InputEvent {
+                frame,
+                key,
+                pressed,
+            });
+        }
+        events.sort_by_key(|event| event.frame);
+        Ok(InputScript { events })
+    }
+}
@@ -0,0 +1,10 @@
+//! Running ROMs without a window, for scripts and CI.
+
+mod headless;
+mod input;
+pub mod regress;
+mod screenshot;
+
+pub use crate::headless::{Headless, Limit};
+pub use crate::input::{InputError, InputEvent, InputScript};
+pub use crate::screenshot::{Format, Screenshot};
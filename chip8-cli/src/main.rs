@@ -0,0 +1,323 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::thread;
+use std::time::Instant;
+
+use chip8::disasm::{Disassembler, LineKind, Syntax};
+use chip8::error::StepOutcome;
+use chip8::quirks::Preset;
+use chip8::trace::TraceEntry;
+use chip8::Mode;
+use chip8_cli::regress::{self, Verdict};
+use chip8_cli::{Format, Headless, InputScript, Limit, Screenshot};
+
+const USAGE: &str = "usage: chip8-cli COMMAND ROM [OPTIONS]
+
+commands:
+  run ROM            run the ROM and print where it stopped
+  shot ROM OUTPUT    run the ROM and save the screen, - prints it as text
+  disasm ROM         print a listing of the ROM
+  info ROM           print the size, hash and instructions used
+  bench ROM          measure how many instructions run per second
+  regress PATH       run the tests in a manifest, or all manifests in a directory
+
+options:
+  --preset NAME      vip, chip48, schip or octo
+  --cycles N         stop after N cycles
+  --frames N         stop after N frames, the default is 600
+  --input FILE       replay the key presses in FILE
+  --seed N           seed for the random number generator
+  --trace FILE       write every instruction run to FILE
+  --format FORMAT    png, pbm or ascii, otherwise taken from OUTPUT
+  --scale N          enlarge each pixel N times in PNG and PBM screenshots
+  --syntax SYNTAX    cowgod or octo for disasm
+  --out DIR          where regress writes images of failed tests
+  --threads N        how many tests regress runs at once";
+
+/// Ten seconds at `TIMER_RATE`
+const DEFAULT_FRAMES: u64 = 600;
+const DEFAULT_BENCH_CYCLES: u64 = 10_000_000;
+const DEFAULT_FAILURES_DIR: &str = "regress-failures";
+
+struct Options {
+    command: String,
+    paths: Vec<PathBuf>,
+    preset: Option<Preset>,
+    limit: Option<Limit>,
+    input: Option<PathBuf>,
+    seed: u64,
+    trace: Option<PathBuf>,
+    format: Option<Format>,
+    scale: usize,
+    syntax: Syntax,
+    out: PathBuf,
+    threads: Option<usize>,
+}
+
+fn main() {
+    let options = parse_args().unwrap_or_else(|error| {
+        eprintln!("{}\n\n{}", error, USAGE);
+        process::exit(2);
+    });
+    let path = &options.paths[0];
+    let rom = || {
+        fs::read(path).unwrap_or_else(|err| {
+            eprintln!("Couldn't read {}: {}", path.display(), err);
+            process::exit(1);
+        })
+    };
+    let result = match (options.command.as_str(), options.paths.len()) {
+        ("run", 1) => run(&rom(), &options),
+        ("shot", 2) => shot(&rom(), &options, &options.paths[1]),
+        ("disasm", 1) => disasm(&rom(), &options),
+        ("info", 1) => info(&rom()),
+        ("bench", 1) => bench(&rom(), &options),
+        ("regress", 1) => regress(path, &options),
+        _ => {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        }
+    };
+    if let Err(error) = result {
+        eprintln!("{}", error);
+        process::exit(1);
+    }
+}
+
+fn run(rom: &[u8], options: &Options) -> Result<(), String> {
+    let mut headless = load(rom, options)?;
+    let limit = options.limit.unwrap_or(Limit::Frames(DEFAULT_FRAMES));
+    let outcome = match &options.trace {
+        Some(path) => {
+            let file = File::create(path).map_err(|err| format!("{}: {}", path.display(), err))?;
+            let mut out = BufWriter::new(file);
+            let mut written = Ok(());
+            let outcome = headless.run_traced(limit, &mut |entry: &TraceEntry| {
+                if written.is_ok() {
+                    written = writeln!(out, "{}", entry);
+                }
+            });
+            written
+                .and_then(|()| out.flush())
+                .map_err(|err| format!("{}: {}", path.display(), err))?;
+            outcome
+        }
+        None => headless.run(limit),
+    };
+    let chip8 = &headless.chip8;
+    let state = match outcome {
+        Ok(StepOutcome::Halted) => "halted".to_string(),
+        Ok(_) if chip8.is_waiting_for_key() => "waiting for a key".to_string(),
+        Ok(_) => "running".to_string(),
+        Err(error) => format!("failed: {}", error),
+    };
+    println!(
+        "{} cycles, {} frames, PC {:#05X}, {}",
+        chip8.cycles(),
+        headless.frames(),
+        chip8.program_counter(),
+        state
+    );
+    println!("Screen: {}", Screenshot::new(&chip8.display).hash());
+    Ok(())
+}
+
+fn shot(rom: &[u8], options: &Options, output: &Path) -> Result<(), String> {
+    let mut headless = load(rom, options)?;
+    let limit = options.limit.unwrap_or(Limit::Frames(DEFAULT_FRAMES));
+    if let Err(error) = headless.run(limit) {
+        // The screen up to the error is often what's wanted
+        eprintln!("Stopped early: {}", error);
+    }
+    let screenshot = Screenshot::new(&headless.chip8.display);
+    let written = if output == Path::new("-") {
+        let format = options.format.unwrap_or(Format::Ascii);
+        screenshot.write(format, options.scale, io::stdout().lock())
+    } else {
+        let format = options.format.unwrap_or_else(|| Format::from_path(output));
+        File::create(output).and_then(|file| {
+            let mut out = BufWriter::new(file);
+            screenshot.write(format, options.scale, &mut out)?;
+            out.flush()
+        })
+    };
+    written.map_err(|err| format!("{}: {}", output.display(), err))
+}
+
+/// Without a preset the ROM is decoded in the mode `info` finds it needs
+fn disasm(rom: &[u8], options: &Options) -> Result<(), String> {
+    let mode = options
+        .preset
+        .map_or_else(|| needed_mode(rom).0, Preset::mode);
+    let stdout = io::stdout();
+    let mut out = BufWriter::new(stdout.lock());
+    let written = Disassembler::new(rom, mode, options.syntax)
+        .lines()
+        .try_for_each(|line| writeln!(out, "{}", line))
+        .and_then(|()| out.flush());
+    stdout_result(written)
+}
+
+fn info(rom: &[u8]) -> Result<(), String> {
+    let stdout = io::stdout();
+    let mut out = BufWriter::new(stdout.lock());
+    let (mode, counts) = needed_mode(rom);
+    let mode = match mode {
+        Mode::Chip8 => "CHIP-8",
+        Mode::SuperChip => "SUPER-CHIP",
+        Mode::XoChip => "XO-CHIP",
+    };
+    let written = (|| {
+        writeln!(out, "Size: {} bytes", rom.len())?;
+        writeln!(out, "SHA-1: {}", sha1_smol::Sha1::from(rom).digest())?;
+        writeln!(out, "Needs: {}", mode)?;
+        writeln!(out, "Instructions:")?;
+        for (name, count) in counts {
+            writeln!(out, "  {:<24} {}", name, count)?;
+        }
+        out.flush()
+    })();
+    stdout_result(written)
+}
+
+/// The mode the reachable code of a ROM needs, and how often it uses every
+/// instruction. Only reachable code counts, data can look like anything.
+fn needed_mode(rom: &[u8]) -> (Mode, BTreeMap<String, usize>) {
+    let disassembler = Disassembler::new(rom, Mode::XoChip, Syntax::Cowgod);
+    let mut mode = Mode::Chip8;
+    let mut counts = BTreeMap::new();
+    for line in disassembler.lines() {
+        if let LineKind::Instruction(instruction) = line.kind {
+            if disassembler.is_reachable(line.address) {
+                mode = mode.max(instruction.mode());
+                let name = format!("{:?}", instruction);
+                let name = name.split('(').next().unwrap_or_default().to_string();
+                *counts.entry(name).or_insert(0) += 1;
+            }
+        }
+    }
+    (mode, counts)
+}
+
+/// A reader that stopped reading, e.g. `head`, isn't an error
+fn stdout_result(written: io::Result<()>) -> Result<(), String> {
+    match written {
+        Err(err) if err.kind() != io::ErrorKind::BrokenPipe => Err(err.to_string()),
+        _ => Ok(()),
+    }
+}
+
+fn bench(rom: &[u8], options: &Options) -> Result<(), String> {
+    let mut headless = load(rom, options)?;
+    let limit = options.limit.unwrap_or(Limit::Cycles(DEFAULT_BENCH_CYCLES));
+    let start = Instant::now();
+    let outcome = headless.run(limit);
+    let elapsed = start.elapsed().as_secs_f64();
+    if let Err(error) = outcome {
+        eprintln!("Stopped early: {}", error);
+    }
+    // Cycles spent waiting for a key or the next frame don't count
+    let instructions = headless.instructions();
+    println!(
+        "{} instructions in {:.3} s, {:.0} per second",
+        instructions,
+        elapsed,
+        instructions as f64 / elapsed
+    );
+    Ok(())
+}
+
+fn regress(path: &Path, options: &Options) -> Result<(), String> {
+    let tests = regress::load_tests(path)?;
+    let threads = options
+        .threads
+        .unwrap_or_else(|| thread::available_parallelism().map_or(1, |threads| threads.get()));
+    let reports = regress::run_tests(&tests, threads, &options.out);
+    let mut failed = 0;
+    for report in &reports {
+        match &report.verdict {
+            Verdict::Pass => println!("PASS {}", report.name),
+            Verdict::Fail(reason) => {
+                failed += 1;
+                println!("FAIL {}: {}", report.name, reason);
+            }
+        }
+    }
+    println!("{} passed, {} failed", reports.len() - failed, failed);
+    if failed > 0 {
+        return Err(format!(
+            "Images of the failed tests are in {}",
+            options.out.display()
+        ));
+    }
+    Ok(())
+}
+
+fn load(rom: &[u8], options: &Options) -> Result<Headless, String> {
+    let input = match &options.input {
+        Some(path) => InputScript::read(path)?,
+        None => InputScript::default(),
+    };
+    Headless::load(rom, options.preset, options.seed, input)
+        .map_err(|error| format!("Couldn't load the ROM: {}", error))
+}
+
+fn parse_args() -> Result<Options, String> {
+    let mut args = std::env::args().skip(1);
+    let mut options = Options {
+        command: args.next().ok_or("missing command")?,
+        paths: Vec::new(),
+        preset: None,
+        limit: None,
+        input: None,
+        seed: 1,
+        trace: None,
+        format: None,
+        scale: 1,
+        syntax: Syntax::Cowgod,
+        out: DEFAULT_FAILURES_DIR.into(),
+        threads: None,
+    };
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| format!("{} needs a value", arg));
+        match arg.as_str() {
+            "--preset" => options.preset = Some(parse(&value()?)?),
+            "--cycles" => options.limit = Some(Limit::Cycles(parse(&value()?)?)),
+            "--frames" => options.limit = Some(Limit::Frames(parse(&value()?)?)),
+            "--input" => options.input = Some(value()?.into()),
+            "--seed" => options.seed = parse(&value()?)?,
+            "--trace" => options.trace = Some(value()?.into()),
+            "--format" => options.format = Some(parse(&value()?)?),
+            "--scale" => options.scale = parse(&value()?)?,
+            "--syntax" => {
+                options.syntax = match value()?.as_str() {
+                    "cowgod" => Syntax::Cowgod,
+                    "octo" => Syntax::Octo,
+                    other => return Err(format!("unknown syntax {}", other)),
+                }
+            }
+            "--out" => options.out = value()?.into(),
+            "--threads" => options.threads = Some(parse(&value()?)?),
+            "-" => options.paths.push(arg.into()),
+            _ if arg.starts_with('-') => return Err(format!("unexpected argument {}", arg)),
+            _ => options.paths.push(arg.into()),
+        }
+    }
+    if options.paths.is_empty() {
+        return Err("missing ROM".to_string());
+    }
+    Ok(options)
+}
+
+fn parse<T>(value: &str) -> Result<T, String>
+where
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    value
+        .parse()
+        .map_err(|error| format!("{}: {}", value, error))
+}
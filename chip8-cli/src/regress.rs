@@ -0,0 +1,274 @@
+//! Golden image regression tests.
+//!
+//! A manifest is a TOML file with a `[[test]]` table per ROM:
+//!
+//! ```toml
+//! [[test]]
+//! name = "invaders-title"
+//! rom = "Space Invaders.ch8"
+//! preset = "vip"
+//! input = "invaders.keys"
+//! frames = 600
+//! hash = "1b6f0b7e..."
+//! image = "invaders-title.png"
+//! ```
+//!
+//! Paths are relative to the manifest. `preset`, `input` and `seed` are
+//! optional, the run lasts either `cycles` cycles or `frames` frames, and the
+//! screen at the end is checked against `hash`, `image` or both.
+
+use std::any::Any;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+
+use chip8::quirks::Preset;
+use serde::Deserialize;
+
+use crate::{Headless, InputScript, Limit, Screenshot};
+
+/// Enlargement of the diff images written for failures
+const DIFF_SCALE: usize = 4;
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Manifest {
+    #[serde(default)]
+    test: Vec<TestEntry>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TestEntry {
+    name: Option<String>,
+    rom: PathBuf,
+    preset: Option<String>,
+    input: Option<PathBuf>,
+    #[serde(default = "default_seed")]
+    seed: u64,
+    cycles: Option<u64>,
+    frames: Option<u64>,
+    hash: Option<String>,
+    image: Option<PathBuf>,
+}
+
+fn default_seed() -> u64 {
+    1
+}
+
+/// A test from a manifest, with its paths resolved
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TestCase {
+    pub name: String,
+    pub rom: PathBuf,
+    pub preset: Option<Preset>,
+    pub input: Option<PathBuf>,
+    pub seed: u64,
+    pub limit: Limit,
+    pub hash: Option<String>,
+    pub image: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    Pass,
+    Fail(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Report {
+    pub name: String,
+    pub verdict: Verdict,
+}
+
+/// Reads the tests from a manifest, or from every `.toml` manifest in a
+/// directory
+pub fn load_tests(path: &Path) -> Result<Vec<TestCase>, String> {
+    if !path.is_dir() {
+        return load_manifest(path);
+    }
+    let mut manifests: Vec<_> = fs::read_dir(path)
+        .map_err(|err| format!("{}: {}", path.display(), err))?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.extension()
+                .is_some_and(|extension| extension == "toml")
+        })
+        .collect();
+    manifests.sort();
+    let mut tests = Vec::new();
+    for manifest in manifests {
+        tests.extend(load_manifest(&manifest)?);
+    }
+    Ok(tests)
+}
+
+fn load_manifest(path: &Path) -> Result<Vec<TestCase>, String> {
+    let error = |message: String| format!("{}: {}", path.display(), message);
+    let text = fs::read_to_string(path).map_err(|err| error(err.to_string()))?;
+    let manifest: Manifest = toml::from_str(&text).map_err(|err| error(err.to_string()))?;
+    let base = path.parent().unwrap_or_else(|| Path::new(""));
+
+    let mut tests = Vec::new();
+    for (index, entry) in manifest.test.into_iter().enumerate() {
+        let stem = entry.rom.file_stem().unwrap_or_default().to_string_lossy();
+        let name = entry
+            .name
+            .unwrap_or_else(|| format!("{}#{}", stem, index + 1));
+        let preset = entry
+            .preset
+            .map(|preset| preset.parse::<Preset>())
+            .transpose()
+            .map_err(|err| error(format!("{}: {}", name, err)))?;
+        let limit = match (entry.cycles, entry.frames) {
+            (Some(cycles), None) => Limit::Cycles(cycles),
+            (None, Some(frames)) => Limit::Frames(frames),
+            _ => return Err(error(format!("{}: needs either cycles or frames", name))),
+        };
+        if entry.hash.is_none() && entry.image.is_none() {
+            return Err(error(format!("{}: needs a hash or an image", name)));
+        }
+        tests.push(TestCase {
+            name,
+            rom: base.join(entry.rom),
+            preset,
+            input: entry.input.map(|input| base.join(input)),
+            seed: entry.seed,
+            limit,
+            hash: entry.hash.map(|hash| hash.to_ascii_lowercase()),
+            image: entry.image.map(|image| base.join(image)),
+        });
+    }
+    Ok(tests)
+}
+
+/// Runs the tests on `threads` threads, writing the screens of failed tests
+/// and how they differ from the expected image into `failures`. Reports are
+/// in the order of the tests.
+pub fn run_tests(tests: &[TestCase], threads: usize, failures: &Path) -> Vec<Report> {
+    let next = AtomicUsize::new(0);
+    let reports = Mutex::new(vec![None; tests.len()]);
+    thread::scope(|scope| {
+        for _ in 0..threads.clamp(1, tests.len().max(1)) {
+            scope.spawn(|| loop {
+                let index = next.fetch_add(1, Ordering::Relaxed);
+                let test = match tests.get(index) {
+                    Some(test) => test,
+                    None => break,
+                };
+                // A test that panics fails on its own instead of taking
+                // every other test down with it
+                let result = panic::catch_unwind(AssertUnwindSafe(|| run_test(test, failures)));
+                let verdict = match result {
+                    Ok(Ok(())) => Verdict::Pass,
+                    Ok(Err(message)) => Verdict::Fail(message),
+                    Err(payload) => {
+                        Verdict::Fail(format!("panicked: {}", panic_message(&*payload)))
+                    }
+                };
+                let report = Report {
+                    name: test.name.clone(),
+                    verdict,
+                };
+                reports.lock().unwrap()[index] = Some(report);
+            });
+        }
+    });
+    reports
+        .into_inner()
+        .unwrap()
+        .into_iter()
+        .flatten()
+        .collect()
+}
+
+/// The message a panic was raised with
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    match payload.downcast_ref::<&str>() {
+        Some(message) => message,
+        None => payload.downcast_ref::<String>().map_or("", String::as_str),
+    }
+}
+
+fn run_test(test: &TestCase, failures: &Path) -> Result<(), String> {
+    let rom = fs::read(&test.rom).map_err(|err| format!("{}: {}", test.rom.display(), err))?;
+    let input = match &test.input {
+        Some(path) => InputScript::read(path)?,
+        None => InputScript::default(),
+    };
+    let mut headless = Headless::load(&rom, test.preset, test.seed, input)
+        .map_err(|error| format!("couldn't load the ROM: {}", error))?;
+    headless
+        .run(test.limit)
+        .map_err(|error| format!("failed after {} cycles: {}", headless.chip8.cycles(), error))?;
+    let actual = Screenshot::new(&headless.chip8.display);
+
+    let mut problems = Vec::new();
+    if let Some(hash) = &test.hash {
+        if actual.hash() != *hash {
+            problems.push(format!("screen hash is {}", actual.hash()));
+        }
+    }
+    let mut expected_image = None;
+    if let Some(path) = &test.image {
+        let expected = File::open(path)
+            .map_err(|err| err.to_string())
+            .and_then(|file| Screenshot::read_png(file, actual.width, actual.height))
+            .map_err(|err| format!("{}: {}", path.display(), err))?;
+        let differing = (actual.pixels.iter().zip(&expected.pixels))
+            .filter(|(a, e)| a != e)
+            .count();
+        if differing > 0 {
+            problems.push(format!("{} pixels differ from the image", differing));
+            expected_image = Some(expected);
+        }
+    }
+    if problems.is_empty() {
+        return Ok(());
+    }
+
+    let written = write_failure(test, &actual, expected_image.as_ref(), failures);
+    if let Err(err) = written {
+        problems.push(format!("couldn't write the failure images: {}", err));
+    }
+    Err(problems.join(", "))
+}
+
+/// Writes `NAME.actual.png` and, if there's an image to compare with,
+/// `NAME.diff.png`
+fn write_failure(
+    test: &TestCase,
+    actual: &Screenshot,
+    expected: Option<&Screenshot>,
+    failures: &Path,
+) -> io::Result<()> {
+    fs::create_dir_all(failures)?;
+    let file_name: String = test
+        .name
+        .chars()
+        .map(|c| {
+            if c.is_alphanumeric() || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    let mut out = BufWriter::new(File::create(
+        failures.join(format!("{}.actual.png", file_name)),
+    )?);
+    actual.write_png(DIFF_SCALE, &mut out)?;
+    out.flush()?;
+    if let Some(expected) = expected {
+        let mut out = BufWriter::new(File::create(
+            failures.join(format!("{}.diff.png", file_name)),
+        )?);
+        actual.write_diff_png(expected, DIFF_SCALE, &mut out)?;
+        out.flush()?;
+    }
+    Ok(())
+}
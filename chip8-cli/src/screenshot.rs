@@ -0,0 +1,221 @@
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+use chip8::display::{Display, WIDTH};
+
+/// Black, white and two greys for the pixels set in the second XO-CHIP plane,
+/// like the desktop frontend
+const PALETTE: [[u8; 3]; 4] = [[0, 0, 0], [255, 255, 255], [170, 170, 170], [85, 85, 85]];
+/// Matching pixels are drawn darker in diff images, the ones that differ red
+const DIFF_SAME: [[u8; 3]; 4] = [[0, 0, 0], [96, 96, 96], [64, 64, 64], [32, 32, 32]];
+const DIFF_CHANGED: [u8; 3] = [255, 0, 0];
+/// Characters for the four pixel values in ASCII screenshots
+const ASCII_PIXELS: [char; 4] = ['.', '#', '+', '@'];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Png,
+    /// Binary PBM, pixels set in any plane are black
+    Pbm,
+    /// A line of text per row, see `ASCII_PIXELS`
+    Ascii,
+}
+
+impl Format {
+    /// Picks the format from a file extension, anything unknown is ASCII
+    pub fn from_path(path: &Path) -> Format {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("png") => Format::Png,
+            Some("pbm") => Format::Pbm,
+            _ => Format::Ascii,
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Format, String> {
+        match name {
+            "png" => Ok(Format::Png),
+            "pbm" => Ok(Format::Pbm),
+            "ascii" => Ok(Format::Ascii),
+            _ => Err(format!(
+                "unknown format {}, expected png, pbm or ascii",
+                name
+            )),
+        }
+    }
+}
+
+/// The screen at its current resolution, one palette index per pixel
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Screenshot {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<u8>,
+}
+
+impl Screenshot {
+    pub fn new(display: &Display) -> Screenshot {
+        let (width, height) = (display.width(), display.height());
+        // Low resolution pixels take up 2x2 pixels of the buffer
+        let scale = WIDTH / width;
+        let buffer = display.buffer();
+        let pixels = (0..height)
+            .flat_map(|y| (0..width).map(move |x| buffer.pixel(x * scale, y * scale)))
+            .collect();
+        Screenshot {
+            width,
+            height,
+            pixels,
+        }
+    }
+
+    pub fn pixel(&self, x: usize, y: usize) -> u8 {
+        self.pixels[y * self.width + x]
+    }
+
+    /// SHA-1 of the width and height as little endian `u16`s followed by
+    /// the pixels, in hex
+    pub fn hash(&self) -> String {
+        let mut sha1 = sha1_smol::Sha1::new();
+        sha1.update(&(self.width as u16).to_le_bytes());
+        sha1.update(&(self.height as u16).to_le_bytes());
+        sha1.update(&self.pixels);
+        sha1.digest().to_string()
+    }
+
+    /// Reads a screenshot written by `write_png`, or any PNG in the palette's
+    /// colours whose size is a multiple of `width` by `height`, at any bit
+    /// depth
+    pub fn read_png<R: Read>(input: R, width: usize, height: usize) -> Result<Screenshot, String> {
+        let mut decoder = png::Decoder::new(input);
+        // Palettes and bit depths other than 8 become plain 8 bit samples
+        decoder.set_transformations(png::Transformations::normalize_to_color8());
+        let mut reader = decoder.read_info().map_err(|err| err.to_string())?;
+        let mut data = vec![0; reader.output_buffer_size()];
+        let frame = reader
+            .next_frame(&mut data)
+            .map_err(|err| err.to_string())?;
+        let (image_width, image_height) = (frame.width as usize, frame.height as usize);
+        let scale = image_width / width;
+        if scale == 0 || image_width != width * scale || image_height != height * scale {
+            return Err(format!(
+                "image is {}x{}, expected a multiple of {}x{}",
+                image_width, image_height, width, height
+            ));
+        }
+        let samples = frame.color_type.samples();
+        let mut pixels = Vec::with_capacity(width * height);
+        for y in 0..height {
+            for x in 0..width {
+                let offset = y * scale * frame.line_size + x * scale * samples;
+                let sample = &data[offset..offset + samples];
+                let colour = match frame.color_type {
+                    png::ColorType::Rgb | png::ColorType::Rgba => {
+                        Some([sample[0], sample[1], sample[2]])
+                    }
+                    png::ColorType::Grayscale | png::ColorType::GrayscaleAlpha => {
+                        Some([sample[0]; 3])
+                    }
+                    // Expanded into RGB by the transformations
+                    png::ColorType::Indexed => None,
+                };
+                let pixel = colour
+                    .and_then(|colour| PALETTE.iter().position(|&entry| entry == colour))
+                    .ok_or_else(|| format!("pixel ({}, {}) isn't in the palette", x, y))?;
+                pixels.push(pixel as u8);
+            }
+        }
+        Ok(Screenshot {
+            width,
+            height,
+            pixels,
+        })
+    }
+
+    pub fn write<W: Write>(&self, format: Format, scale: usize, out: W) -> io::Result<()> {
+        match format {
+            Format::Png => self.write_png(scale, out),
+            Format::Pbm => self.write_pbm(scale, out),
+            Format::Ascii => self.write_ascii(out),
+        }
+    }
+
+    /// Writes an indexed PNG with every pixel enlarged `scale` times
+    pub fn write_png<W: Write>(&self, scale: usize, out: W) -> io::Result<()> {
+        let scale = scale.max(1);
+        let (width, height) = (self.width * scale, self.height * scale);
+        let mut encoder = png::Encoder::new(out, width as u32, height as u32);
+        encoder.set_color(png::ColorType::Indexed);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder.set_palette(PALETTE.concat());
+        let mut writer = encoder.write_header()?;
+        let data: Vec<u8> = (0..height)
+            .flat_map(|y| (0..width).map(move |x| self.pixel(x / scale, y / scale)))
+            .collect();
+        writer.write_image_data(&data)?;
+        writer.finish()?;
+        Ok(())
+    }
+
+    /// Writes an RGB PNG showing where `self` differs from `expected`, which
+    /// must have the same size
+    pub fn write_diff_png<W: Write>(
+        &self,
+        expected: &Screenshot,
+        scale: usize,
+        out: W,
+    ) -> io::Result<()> {
+        let scale = scale.max(1);
+        let (width, height) = (self.width * scale, self.height * scale);
+        let mut encoder = png::Encoder::new(out, width as u32, height as u32);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header()?;
+        let data: Vec<u8> = (0..height)
+            .flat_map(|y| (0..width).map(move |x| (x / scale, y / scale)))
+            .flat_map(|(x, y)| {
+                let pixel = self.pixel(x, y);
+                if pixel == expected.pixel(x, y) {
+                    DIFF_SAME[pixel as usize]
+                } else {
+                    DIFF_CHANGED
+                }
+            })
+            .collect();
+        writer.write_image_data(&data)?;
+        writer.finish()?;
+        Ok(())
+    }
+
+    pub fn write_pbm<W: Write>(&self, scale: usize, mut out: W) -> io::Result<()> {
+        let scale = scale.max(1);
+        let (width, height) = (self.width * scale, self.height * scale);
+        write!(out, "P4\n{} {}\n", width, height)?;
+        for y in 0..height {
+            // Rows are padded to whole bytes
+            let mut row = vec![0u8; width.div_ceil(8)];
+            for x in 0..width {
+                if self.pixel(x / scale, y / scale) != 0 {
+                    row[x / 8] |= 0x80 >> (x % 8);
+                }
+            }
+            out.write_all(&row)?;
+        }
+        Ok(())
+    }
+
+    pub fn write_ascii<W: Write>(&self, mut out: W) -> io::Result<()> {
+        for row in self.pixels.chunks(self.width) {
+            let line: String = row
+                .iter()
+                .map(|&pixel| ASCII_PIXELS[pixel as usize])
+                .collect();
+            writeln!(out, "{}", line)?;
+        }
+        Ok(())
+    }
+}
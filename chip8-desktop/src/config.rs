@@ -0,0 +1,221 @@
+//! Settings from the command line and the config file.
+//!
+//! The config file is TOML. Settings under `[defaults]` apply to every ROM,
+//! settings under `[roms.HASH]` only to the ROM with that SHA-1 hash, which
+//! is printed at startup:
+//!
+//! ```toml
+//! [defaults]
+//! scale = 8
+//! keymap = "qwerty"
+//!
+//! [roms.5a1e0d8d2a1bd1ee0c7d4e8e9ee8bd1a7c5f3a6e]
+//! preset = "chip48"
+//! per-frame = 15
+//! turbo = ["6"]
+//!
+//! [roms.5a1e0d8d2a1bd1ee0c7d4e8e9ee8bd1a7c5f3a6e.keys]
+//! up = "2"
+//! down = "8"
+//! space = "6"
+//! "pad:south" = "6"
+//! ```
+//!
+//! The command line wins over the ROM's settings, which win over the
+//! defaults. Key bindings are merged one by one, and binding a key to
+//! `none` takes it away.
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use chip8::flicker::Mitigation;
+use chip8::output::{Color, Effect, Filter};
+use chip8::quirks::Preset;
+use chip8::runner::Speed;
+use serde::Deserialize;
+
+use crate::input::{self, Binding};
+use crate::keymap::Keymap;
+
+const DEFAULT_SCALE: u32 = 10;
+/// Turbo keys are pressed 10 times a second by default
+const DEFAULT_TURBO_RATE: u32 = 10;
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Config {
+    defaults: Settings,
+    roms: BTreeMap<String, Settings>,
+}
+
+/// Settings left out are taken from the next layer down
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Settings {
+    pub preset: Option<String>,
+    pub rate: Option<u32>,
+    pub per_frame: Option<u32>,
+    pub scale: Option<u32>,
+    pub foreground: Option<String>,
+    pub background: Option<String>,
+    pub keymap: Option<String>,
+    pub paused: Option<bool>,
+    pub mute: Option<bool>,
+    pub filter: Option<String>,
+    pub effect: Option<String>,
+    pub flicker: Option<String>,
+    /// Keypad keys by the key or gamepad control that presses them
+    pub keys: Option<BTreeMap<String, String>>,
+    /// Keypad keys pressed over and over while they're held
+    pub turbo: Option<Vec<String>>,
+    pub turbo_rate: Option<u32>,
+}
+
+/// The settings a game runs with
+#[derive(Debug, Clone, PartialEq)]
+pub struct Setup {
+    pub preset: Option<Preset>,
+    pub speed: Speed,
+    /// Window pixels per buffer pixel
+    pub scale: u32,
+    pub foreground: Color,
+    pub background: Color,
+    pub bindings: BTreeMap<Binding, u8>,
+    /// A bit for every keypad key with turbo
+    pub turbo: u16,
+    pub turbo_rate: u32,
+    pub paused: bool,
+    pub mute: bool,
+    pub filter: Filter,
+    pub effect: Effect,
+    pub flicker: Option<Mitigation>,
+}
+
+impl Settings {
+    /// These settings, with the ones left out taken from `below`
+    pub fn or(self, below: Settings) -> Settings {
+        // The rate and instructions per frame are one setting
+        let (rate, per_frame) = if self.rate.is_some() || self.per_frame.is_some() {
+            (self.rate, self.per_frame)
+        } else {
+            (below.rate, below.per_frame)
+        };
+        Settings {
+            preset: self.preset.or(below.preset),
+            rate,
+            per_frame,
+            scale: self.scale.or(below.scale),
+            foreground: self.foreground.or(below.foreground),
+            background: self.background.or(below.background),
+            keymap: self.keymap.or(below.keymap),
+            paused: self.paused.or(below.paused),
+            mute: self.mute.or(below.mute),
+            filter: self.filter.or(below.filter),
+            effect: self.effect.or(below.effect),
+            flicker: self.flicker.or(below.flicker),
+            keys: match (self.keys, below.keys) {
+                (Some(keys), Some(mut below)) => {
+                    below.extend(keys);
+                    Some(below)
+                }
+                (keys, below) => keys.or(below),
+            },
+            turbo: self.turbo.or(below.turbo),
+            turbo_rate: self.turbo_rate.or(below.turbo_rate),
+        }
+    }
+
+    pub fn setup(&self) -> Result<Setup, String> {
+        let speed = match (self.rate, self.per_frame) {
+            (Some(_), Some(_)) => return Err("rate and per-frame can't both be set".to_string()),
+            (Some(rate), None) => Speed::InstructionsPerSecond(rate),
+            (None, Some(count)) => Speed::InstructionsPerFrame(count),
+            (None, None) => Speed::default(),
+        };
+        let flicker = match self.flicker.as_deref() {
+            None | Some("none") => None,
+            Some(mitigation) => Some(parse(mitigation)?),
+        };
+        let keymap = self
+            .keymap
+            .as_deref()
+            .map_or(Ok(Keymap::default()), parse)?;
+        let mut bindings = input::default_bindings(keymap);
+        for (binding, value) in self.keys.iter().flatten() {
+            let binding = binding.parse()?;
+            if value.eq_ignore_ascii_case("none") {
+                bindings.remove(&binding);
+            } else {
+                bindings.insert(binding, input::keypad_key(value)?);
+            }
+        }
+        let mut turbo = 0;
+        for value in self.turbo.iter().flatten() {
+            turbo |= 1 << input::keypad_key(value)?;
+        }
+        Ok(Setup {
+            preset: self.preset.as_deref().map(parse).transpose()?,
+            speed,
+            scale: self.scale.unwrap_or(DEFAULT_SCALE).max(1),
+            foreground: self.foreground.as_deref().map_or(Ok(Color::WHITE), color)?,
+            background: self.background.as_deref().map_or(Ok(Color::BLACK), color)?,
+            bindings,
+            turbo,
+            turbo_rate: self.turbo_rate.unwrap_or(DEFAULT_TURBO_RATE),
+            paused: self.paused.unwrap_or(false),
+            mute: self.mute.unwrap_or(false),
+            filter: self.filter.as_deref().map_or(Ok(Filter::None), parse)?,
+            effect: self.effect.as_deref().map_or(Ok(Effect::None), parse)?,
+            flicker,
+        })
+    }
+}
+
+/// Where the config file is looked for when `--config` isn't given
+pub fn default_path() -> Option<PathBuf> {
+    let base = env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("APPDATA").map(PathBuf::from))
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")))?;
+    Some(base.join("rust-8").join("config.toml"))
+}
+
+/// The settings a config file has for a ROM, the defaults with the ROM's
+/// own settings on top
+pub fn load(path: &Path, rom_hash: &str) -> Result<Settings, String> {
+    let error = |message: String| format!("{}: {}", path.display(), message);
+    let text = fs::read_to_string(path).map_err(|err| error(err.to_string()))?;
+    let config: Config = toml::from_str(&text).map_err(|err| error(err.to_string()))?;
+    let rom = (config.roms.into_iter())
+        .find(|(hash, _)| hash.eq_ignore_ascii_case(rom_hash))
+        .map(|(_, settings)| settings)
+        .unwrap_or_default();
+    Ok(rom.or(config.defaults))
+}
+
+/// A colour written as `RRGGBB` in hex, with or without a leading `#`
+fn color(text: &str) -> Result<Color, String> {
+    let hex = text.strip_prefix('#').unwrap_or(text);
+    let value = match hex.len() {
+        6 => u32::from_str_radix(hex, 16).ok(),
+        _ => None,
+    };
+    let value = value.ok_or_else(|| format!("{}: expected a colour like #FFFFFF", text))?;
+    Ok(Color::rgb(
+        (value >> 16) as u8,
+        (value >> 8) as u8,
+        value as u8,
+    ))
+}
+
+fn parse<T>(value: &str) -> Result<T, String>
+where
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    value
+        .parse()
+        .map_err(|error| format!("{}: {}", value, error))
+}
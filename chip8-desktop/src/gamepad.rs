@@ -0,0 +1,79 @@
+//! Gamepads, read with gilrs.
+
+use gilrs::{Axis, Button, EventType, Gilrs};
+
+use crate::input::{Binding, Control, Input};
+
+pub struct Gamepads {
+    gilrs: Gilrs,
+}
+
+impl Gamepads {
+    pub fn new() -> Result<Gamepads, String> {
+        let gilrs = Gilrs::new().map_err(|err| err.to_string())?;
+        Ok(Gamepads { gilrs })
+    }
+
+    /// Passes on what happened to the gamepads since the last call
+    pub fn poll(&mut self, input: &mut Input) {
+        while let Some(event) = self.gilrs.next_event() {
+            match event.event {
+                EventType::ButtonPressed(button, _) => {
+                    if let Some(control) = control(button) {
+                        input.press(Binding::Pad(control));
+                    }
+                }
+                EventType::ButtonReleased(button, _) => {
+                    if let Some(control) = control(button) {
+                        input.release(Binding::Pad(control));
+                    }
+                }
+                EventType::AxisChanged(axis, value, _) => {
+                    if let Some((negative, positive)) = directions(axis) {
+                        input.stick(negative, positive, value);
+                    }
+                }
+                EventType::Disconnected => input.release_gamepad(),
+                _ => {}
+            }
+        }
+    }
+}
+
+fn control(button: Button) -> Option<Control> {
+    let control = match button {
+        Button::South => Control::South,
+        Button::East => Control::East,
+        Button::North => Control::North,
+        Button::West => Control::West,
+        Button::LeftTrigger => Control::L1,
+        Button::LeftTrigger2 => Control::L2,
+        Button::RightTrigger => Control::R1,
+        Button::RightTrigger2 => Control::R2,
+        Button::Select => Control::Select,
+        Button::Start => Control::Start,
+        Button::LeftThumb => Control::L3,
+        Button::RightThumb => Control::R3,
+        Button::DPadUp => Control::DPadUp,
+        Button::DPadDown => Control::DPadDown,
+        Button::DPadLeft => Control::DPadLeft,
+        Button::DPadRight => Control::DPadRight,
+        _ => return None,
+    };
+    Some(control)
+}
+
+/// The directions an axis points in when it's negative and positive, up is
+/// positive on the Y axes. Some gamepads report their d-pad as axes.
+fn directions(axis: Axis) -> Option<(Control, Control)> {
+    let directions = match axis {
+        Axis::LeftStickX => (Control::LeftStickLeft, Control::LeftStickRight),
+        Axis::LeftStickY => (Control::LeftStickDown, Control::LeftStickUp),
+        Axis::RightStickX => (Control::RightStickLeft, Control::RightStickRight),
+        Axis::RightStickY => (Control::RightStickDown, Control::RightStickUp),
+        Axis::DPadX => (Control::DPadLeft, Control::DPadRight),
+        Axis::DPadY => (Control::DPadDown, Control::DPadUp),
+        _ => return None,
+    };
+    Some(directions)
+}
@@ -0,0 +1,303 @@
+//! Bindings from keyboard keys and gamepad controls to the hex keypad.
+//!
+//! Bindings are written as the name of a key, like `w`, `up` or `numpad5`,
+//! or as `pad:` and the name of a gamepad control, like `pad:south` or
+//! `pad:left-stick-up`.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::str::FromStr;
+
+use chip8::random::XorShift;
+use chip8::Chip8;
+use piston_window::Key;
+
+use crate::keymap::Keymap;
+
+/// How far a stick has to be pushed to press its direction
+#[cfg(feature = "gamepad")]
+const STICK_THRESHOLD: f32 = 0.5;
+
+/// A gamepad button, or a direction of one of its sticks
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Control {
+    South,
+    East,
+    North,
+    West,
+    L1,
+    L2,
+    R1,
+    R2,
+    Select,
+    Start,
+    L3,
+    R3,
+    DPadUp,
+    DPadDown,
+    DPadLeft,
+    DPadRight,
+    LeftStickUp,
+    LeftStickDown,
+    LeftStickLeft,
+    LeftStickRight,
+    RightStickUp,
+    RightStickDown,
+    RightStickLeft,
+    RightStickRight,
+}
+
+impl Control {
+    pub const ALL: [Control; 24] = [
+        Control::South,
+        Control::East,
+        Control::North,
+        Control::West,
+        Control::L1,
+        Control::L2,
+        Control::R1,
+        Control::R2,
+        Control::Select,
+        Control::Start,
+        Control::L3,
+        Control::R3,
+        Control::DPadUp,
+        Control::DPadDown,
+        Control::DPadLeft,
+        Control::DPadRight,
+        Control::LeftStickUp,
+        Control::LeftStickDown,
+        Control::LeftStickLeft,
+        Control::LeftStickRight,
+        Control::RightStickUp,
+        Control::RightStickDown,
+        Control::RightStickLeft,
+        Control::RightStickRight,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Control::South => "south",
+            Control::East => "east",
+            Control::North => "north",
+            Control::West => "west",
+            Control::L1 => "l1",
+            Control::L2 => "l2",
+            Control::R1 => "r1",
+            Control::R2 => "r2",
+            Control::Select => "select",
+            Control::Start => "start",
+            Control::L3 => "l3",
+            Control::R3 => "r3",
+            Control::DPadUp => "dpad-up",
+            Control::DPadDown => "dpad-down",
+            Control::DPadLeft => "dpad-left",
+            Control::DPadRight => "dpad-right",
+            Control::LeftStickUp => "left-stick-up",
+            Control::LeftStickDown => "left-stick-down",
+            Control::LeftStickLeft => "left-stick-left",
+            Control::LeftStickRight => "left-stick-right",
+            Control::RightStickUp => "right-stick-up",
+            Control::RightStickDown => "right-stick-down",
+            Control::RightStickLeft => "right-stick-left",
+            Control::RightStickRight => "right-stick-right",
+        }
+    }
+}
+
+/// Something that can be held down to press a keypad key
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Binding {
+    Key(Key),
+    Pad(Control),
+}
+
+impl FromStr for Binding {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Binding, String> {
+        let lower = name.to_ascii_lowercase();
+        let binding = match lower.strip_prefix("pad:") {
+            Some(control) => (Control::ALL.iter())
+                .find(|known| known.name() == control)
+                .map(|&control| Binding::Pad(control)),
+            None => key(&lower).map(Binding::Key),
+        };
+        binding.ok_or_else(|| format!("unknown key or gamepad control {}", name))
+    }
+}
+
+/// The bindings to start from, the keymap's keys and the d-pad, left stick
+/// and face buttons on the keys QWERTY games tend to use, WASD, Q and E
+pub fn default_bindings(keymap: Keymap) -> BTreeMap<Binding, u8> {
+    let pad = [
+        (Control::DPadUp, 0x5),
+        (Control::DPadDown, 0x8),
+        (Control::DPadLeft, 0x7),
+        (Control::DPadRight, 0x9),
+        (Control::LeftStickUp, 0x5),
+        (Control::LeftStickDown, 0x8),
+        (Control::LeftStickLeft, 0x7),
+        (Control::LeftStickRight, 0x9),
+        (Control::South, 0x6),
+        (Control::East, 0x4),
+    ];
+    let keys = (keymap.keys().iter()).map(|&(key, value)| (Binding::Key(key), value));
+    keys.chain(
+        pad.iter()
+            .map(|&(control, value)| (Binding::Pad(control), value)),
+    )
+    .collect()
+}
+
+/// A keypad key written as a hex digit
+pub fn keypad_key(text: &str) -> Result<u8, String> {
+    match u8::from_str_radix(text, 16) {
+        Ok(value) if text.len() == 1 => Ok(value),
+        _ => Err(format!("{}: expected a keypad key from 0 to F", text)),
+    }
+}
+
+/// Which keypad keys are held, from the bindings held down.
+///
+/// Turbo keys are pressed and released over and over while they're held,
+/// paced by `frame`.
+pub struct Input {
+    bindings: BTreeMap<Binding, u8>,
+    /// A bit for every keypad key with turbo
+    turbo: u16,
+    /// Frames a turbo key stays pressed, and then released
+    turbo_frames: u32,
+    held: BTreeSet<Binding>,
+    /// Frames every turbo key has been held for
+    held_frames: [u32; 16],
+    /// The keys the machine was last told are pressed
+    pressed: u16,
+}
+
+impl Input {
+    /// `turbo_rate` is how many times a second turbo keys are pressed
+    pub fn new(bindings: BTreeMap<Binding, u8>, turbo: u16, turbo_rate: u32) -> Input {
+        let half_period = chip8::TIMER_RATE / 2 / turbo_rate.max(1);
+        Input {
+            bindings,
+            turbo,
+            turbo_frames: half_period.max(1),
+            held: BTreeSet::new(),
+            held_frames: [0; 16],
+            pressed: 0,
+        }
+    }
+
+    pub fn press(&mut self, binding: Binding) {
+        if self.bindings.contains_key(&binding) {
+            self.held.insert(binding);
+        }
+    }
+
+    pub fn release(&mut self, binding: Binding) {
+        self.held.remove(&binding);
+    }
+
+    /// Holds the direction a stick axis is pushed in, if it's pushed far
+    /// enough
+    #[cfg(feature = "gamepad")]
+    pub fn stick(&mut self, negative: Control, positive: Control, value: f32) {
+        self.release(Binding::Pad(negative));
+        self.release(Binding::Pad(positive));
+        if value <= -STICK_THRESHOLD {
+            self.press(Binding::Pad(negative));
+        } else if value >= STICK_THRESHOLD {
+            self.press(Binding::Pad(positive));
+        }
+    }
+
+    /// Lets go of every gamepad control, e.g. when one is unplugged
+    #[cfg(feature = "gamepad")]
+    pub fn release_gamepad(&mut self) {
+        self.held
+            .retain(|binding| matches!(binding, Binding::Key(_)));
+    }
+
+    /// Moves turbo keys on by a frame
+    pub fn frame(&mut self) {
+        let held = self.held_keys();
+        for (value, frames) in self.held_frames.iter_mut().enumerate() {
+            *frames = if held & (1 << value) != 0 {
+                frames.wrapping_add(1)
+            } else {
+                0
+            };
+        }
+    }
+
+    /// Tells the machine about the keys pressed and released since the
+    /// last call
+    pub fn apply(&mut self, computer: &mut Chip8<XorShift>) {
+        let pressed = self.pressed_keys();
+        let changed = pressed ^ self.pressed;
+        for value in (0..16).filter(|value| changed & (1 << value) != 0) {
+            if pressed & (1 << value) != 0 {
+                computer.handle_key_press(value);
+            } else {
+                computer.handle_key_release(value);
+            }
+        }
+        self.pressed = pressed;
+    }
+
+    /// A bit for every keypad key with a binding held down
+    fn held_keys(&self) -> u16 {
+        (self.held.iter())
+            .filter_map(|binding| self.bindings.get(binding))
+            .fold(0, |keys, &value| keys | 1 << value)
+    }
+
+    fn pressed_keys(&self) -> u16 {
+        let held = self.held_keys();
+        let released = (0..16)
+            .filter(|&value| self.turbo & (1 << value) != 0)
+            .filter(|&value| (self.held_frames[value] / self.turbo_frames) % 2 == 1)
+            .fold(0, |keys, value| keys | 1 << value);
+        held & !released
+    }
+}
+
+/// The key with a name, a letter or digit or one of the named keys
+fn key(name: &str) -> Option<Key> {
+    let mut chars = name.chars();
+    if let (Some(c), None) = (chars.next(), chars.next()) {
+        // Letters and digits have their ASCII codes
+        return Some(c)
+            .filter(|c| c.is_ascii_lowercase() || c.is_ascii_digit())
+            .map(|c| Key::from(c as u32));
+    }
+    if let Some(digit) = name.strip_prefix("numpad") {
+        return digit
+            .parse::<u32>()
+            .ok()
+            .filter(|&digit| digit < 10)
+            // The keypad's codes run from 1 to 9 and then 0
+            .map(|digit| match digit {
+                0 => Key::NumPad0,
+                _ => Key::from(Key::NumPad1.code() as u32 + digit - 1),
+            });
+    }
+    let key = match name {
+        "up" => Key::Up,
+        "down" => Key::Down,
+        "left" => Key::Left,
+        "right" => Key::Right,
+        "space" => Key::Space,
+        "enter" | "return" => Key::Return,
+        "lctrl" => Key::LCtrl,
+        "rctrl" => Key::RCtrl,
+        "lalt" => Key::LAlt,
+        "ralt" => Key::RAlt,
+        "comma" => Key::Comma,
+        "period" => Key::Period,
+        "slash" => Key::Slash,
+        "semicolon" => Key::Semicolon,
+        _ => return None,
+    };
+    Some(key)
+}
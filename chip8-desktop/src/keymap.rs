@@ -0,0 +1,98 @@
+//! Which keyboard keys press which keys of the hex keypad.
+
+use std::fmt;
+use std::str::FromStr;
+
+use piston_window::Key;
+
+/// The keyboard layout bindings start out with
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Keymap {
+    /// The keys labelled with the hex digits, 0-9 and A-F
+    Hex,
+    /// The 4x4 block from 1 to V, laid out like the COSMAC VIP keypad:
+    ///
+    /// ```text
+    /// 1 2 3 4      1 2 3 C
+    /// Q W E R      4 5 6 D
+    /// A S D F  ->  7 8 9 E
+    /// Z X C V      A 0 B F
+    /// ```
+    #[default]
+    Qwerty,
+}
+
+impl Keymap {
+    pub const ALL: [Keymap; 2] = [Keymap::Hex, Keymap::Qwerty];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Keymap::Hex => "hex",
+            Keymap::Qwerty => "qwerty",
+        }
+    }
+
+    /// The keyboard keys and the keypad keys they press
+    pub fn keys(self) -> &'static [(Key, u8)] {
+        match self {
+            Keymap::Hex => &HEX_KEYS,
+            Keymap::Qwerty => &QWERTY_KEYS,
+        }
+    }
+}
+
+impl fmt::Display for Keymap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Keymap {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Keymap, String> {
+        Keymap::ALL
+            .iter()
+            .copied()
+            .find(|keymap| keymap.name().eq_ignore_ascii_case(name))
+            .ok_or_else(|| "unknown keymap, expected hex or qwerty".to_string())
+    }
+}
+
+const HEX_KEYS: [(Key, u8); 16] = [
+    (Key::D0, 0x0),
+    (Key::D1, 0x1),
+    (Key::D2, 0x2),
+    (Key::D3, 0x3),
+    (Key::D4, 0x4),
+    (Key::D5, 0x5),
+    (Key::D6, 0x6),
+    (Key::D7, 0x7),
+    (Key::D8, 0x8),
+    (Key::D9, 0x9),
+    (Key::A, 0xA),
+    (Key::B, 0xB),
+    (Key::C, 0xC),
+    (Key::D, 0xD),
+    (Key::E, 0xE),
+    (Key::F, 0xF),
+];
+
+const QWERTY_KEYS: [(Key, u8); 16] = [
+    (Key::D1, 0x1),
+    (Key::D2, 0x2),
+    (Key::D3, 0x3),
+    (Key::D4, 0xC),
+    (Key::Q, 0x4),
+    (Key::W, 0x5),
+    (Key::E, 0x6),
+    (Key::R, 0xD),
+    (Key::A, 0x7),
+    (Key::S, 0x8),
+    (Key::D, 0x9),
+    (Key::F, 0xE),
+    (Key::Z, 0xA),
+    (Key::X, 0x0),
+    (Key::C, 0xB),
+    (Key::V, 0xF),
+];
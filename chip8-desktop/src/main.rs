@@ -1,113 +1,533 @@
 extern crate piston_window;
 
+mod config;
+#[cfg(feature = "gamepad")]
+mod gamepad;
+mod input;
+mod keymap;
+#[cfg(feature = "sound")]
+mod sound;
+
 use chip8::display;
+use chip8::error::MovieError;
+use chip8::flicker::Flicker;
+use chip8::movie::{Movie, Player};
+use chip8::output::{OutputData, Panel, PixelFormat, Scaling, Screen};
+use chip8::quirks::Quirks;
+use chip8::random::XorShift;
+use chip8::runner::{Runner, Step};
+use chip8::state::STATE_SIZE;
+use chip8::{Chip8, Mode};
 use std::env;
-use std::fs::File;
-use std::io::Read;
-
-use random_fast_rng::FastRng;
+use std::fs;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::process;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
+use piston_window::texture::{CreateTexture, Format, UpdateTexture};
 use piston_window::*;
+use sha1_smol::Sha1;
+
+use crate::config::Settings;
+use crate::input::{Binding, Input};
+
+const USAGE: &str = "usage: chip8-desktop ROM [PRESET] [OPTIONS]
 
-const ENLARGEMENT_FACTOR: usize = 20;
-const WINDOW_DIMENSIONS: [u32; 2] = [
-    (display::WIDTH * ENLARGEMENT_FACTOR) as u32,
-    (display::HEIGHT * ENLARGEMENT_FACTOR) as u32,
-];
+options:
+  --preset NAME        vip, chip48, schip or octo, also taken as the second argument
+  --rate N             run N instructions per second, 800 by default
+  --per-frame N        run N instructions every 60 Hz frame instead
+  --scale N            show each pixel of the 128x64 buffer N times as large, 10 by default
+  --foreground COLOR   the colour of lit pixels, like #FFFFFF
+  --background COLOR   the colour of unlit pixels, like #000000
+  --keymap NAME        qwerty for the 1234 to ZXCV block, the default, or hex for 0-9 and A-F
+  --bind INPUT=KEY     press keypad KEY with a key like w or up, or a gamepad control like pad:south
+  --turbo KEYS         keypad keys to press over and over while held, like 6,A
+  --turbo-rate N       press turbo keys N times a second, 10 by default
+  --paused             start paused, P resumes
+  --mute               don't play sound
+  --filter NAME        none, scale2x or scale3x
+  --effect NAME        none, scanlines or grid
+  --flicker MODE       none, blend:N, phosphor:N or clears
+  --seed N             seed for the random number generator
+  --record FILE        record a movie of the session
+  --play FILE          play a movie back
+  --config FILE        read settings from FILE instead of the default config";
+
+/// Rewinding goes back up to a minute, with a checkpoint every quarter second
+const REWIND_INTERVAL: u64 = chip8::INSTRUCTION_RATE as u64 / 4;
+const REWIND_CHECKPOINTS: usize = 4 * 60;
+/// How much faster holding tab runs the game
+const FAST_FORWARD: u32 = 4;
+
+struct Options {
+    rom: String,
+    /// Settings given as flags, which win over the config file
+    settings: Settings,
+    seed: Option<u64>,
+    record: Option<String>,
+    play: Option<String>,
+    config: Option<PathBuf>,
+}
 
 fn main() {
-    let file_name = env::args()
-        .nth(1)
-        .expect("Must give game name as first file");
-    let mut file = File::open(file_name).expect("There was an issue opening the file");
-    let mut game_data = Vec::new();
-    file.read_to_end(&mut game_data)
-        .expect("Failure to read file");
-
-    let mut window: PistonWindow = WindowSettings::new("Rust-8 Emulator", WINDOW_DIMENSIONS)
+    let options = parse_args().unwrap_or_else(|error| {
+        eprintln!("{}\n\n{}", error, USAGE);
+        process::exit(2);
+    });
+    let file_name = options.rom;
+    let game_data = fs::read(&file_name).unwrap_or_else(|err| {
+        eprintln!("Couldn't read {}: {}", file_name, err);
+        process::exit(1);
+    });
+    // Printed so the ROM's settings can be put in the config file
+    let rom_hash = Sha1::from(&game_data).digest().to_string();
+    println!("ROM SHA-1 {}", rom_hash);
+
+    // Without --config the default config file is optional
+    let config_path =
+        (options.config).or_else(|| config::default_path().filter(|path| path.exists()));
+    let config_settings = match &config_path {
+        Some(path) => config::load(path, &rom_hash).unwrap_or_else(|error| {
+            eprintln!("Couldn't read the config {}", error);
+            process::exit(1);
+        }),
+        None => Settings::default(),
+    };
+    let setup = (options.settings.or(config_settings))
+        .setup()
+        .unwrap_or_else(|error| {
+            eprintln!("{}", error);
+            process::exit(1);
+        });
+    let (mode, quirks) = match setup.preset {
+        Some(preset) => (preset.mode(), preset.quirks()),
+        None => (Mode::Chip8, Quirks::default()),
+    };
+    let (seed, record, play) = (options.seed, options.record, options.play);
+
+    // Printed so the run can be repeated, e.g. with chip8-cli
+    let seed = seed.unwrap_or_else(|| {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |time| time.as_nanos() as u64)
+    });
+    if play.is_none() {
+        println!("Random seed {}", seed);
+    }
+
+    // A movie brings its own mode, quirks and seed
+    let movie = play.map(|path| {
+        fs::read(&path)
+            .map_err(|err| err.to_string())
+            .and_then(|bytes| Movie::from_bytes(&bytes).map_err(|err| err.to_string()))
+            .unwrap_or_else(|err| {
+                eprintln!("Couldn't read the movie {}: {}", path, err);
+                process::exit(1);
+            })
+    });
+    let (mut computer, mut player) = match &movie {
+        Some(movie) => match start_playback(movie, &game_data) {
+            Ok((computer, player)) => (computer, Some(player)),
+            Err(error) => {
+                eprintln!("Couldn't play the movie: {}", error);
+                process::exit(1);
+            }
+        },
+        None => match Chip8::with_mode(&game_data, XorShift::new(seed), mode, quirks) {
+            Ok(computer) => (computer, None),
+            Err(error) => {
+                eprintln!("Couldn't load the game: {}", error);
+                process::exit(1);
+            }
+        },
+    };
+    // Rewinding and loading states would leave the movie behind
+    let movie_active = record.is_some() || movie.is_some();
+    if let Some(path) = &record {
+        if let Err(error) = computer.start_recording(&game_data) {
+            eprintln!("Couldn't record to {}: {}", path, error);
+            process::exit(1);
+        }
+    } else if movie.is_none() {
+        computer.enable_rewind(REWIND_INTERVAL, REWIND_CHECKPOINTS);
+    }
+
+    // The screen is drawn into an image with half the scale when it's even,
+    // which the window shows twice as large
+    let zoom = if setup.scale.is_multiple_of(2) { 2 } else { 1 };
+    let image_scale = setup.scale / zoom;
+    let window_size = [
+        display::WIDTH as u32 * setup.scale,
+        display::HEIGHT as u32 * setup.scale,
+    ];
+    let mut window: PistonWindow = WindowSettings::new("Rust-8 Emulator", window_size)
         .exit_on_esc(true)
         .build()
         .unwrap();
-    let mut computer = chip8::Chip8::new(&game_data, FastRng::new());
-    let mut instruction_time_left = 0.0;
-    let mut clock_time_left = 0.0;
+    // Buffers are only swapped after drawing, so an unchanged screen is left alone
+    window.set_swap_buffers(false);
+    let mut panel = Panel {
+        width: display::WIDTH as u32 * image_scale,
+        height: display::HEIGHT as u32 * image_scale,
+        scaling: Scaling::Stretch,
+        format: PixelFormat::Rgb888,
+        filter: setup.filter,
+        effect: setup.effect,
+        ..Panel::default()
+    };
+    panel.colors[0] = setup.background;
+    panel.colors[1] = setup.foreground;
+    let mut texture_context = window.create_texture_context();
+    let mut texture: G2dTexture = CreateTexture::create(
+        &mut texture_context,
+        Format::Rgba8,
+        &vec![0; (panel.width * panel.height * 4) as usize],
+        [panel.width, panel.height],
+        &TextureSettings::new().filter(Filter::Nearest),
+    )
+    .expect("Couldn't create the screen texture");
+    let mut redraw = true;
+    let mut flicker = setup.flicker.map(Flicker::new);
+    // Set once the program faults, the last frame stays on screen
+    let mut error = None;
+    let mut runner = Runner::new(setup.speed);
+    runner.set_paused(setup.paused);
+    let mut sound_active = false;
+    let mut shift_held = false;
+    let mut rewinding = false;
+    let mut input = Input::new(setup.bindings, setup.turbo, setup.turbo_rate);
+    #[cfg(feature = "gamepad")]
+    let mut gamepads = gamepad::Gamepads::new()
+        .map_err(|err| eprintln!("Gamepads aren't available: {}", err))
+        .ok();
+    #[cfg(feature = "sound")]
+    let sound = sound::Sound::new()
+        .map_err(|err| eprintln!("Sound isn't available, ringing the terminal bell: {}", err))
+        .ok();
     while let Some(e) = window.next() {
-        if e.render_args().is_some() {
-            draw_screen(&computer.display.get_buffer(), &mut window, &e);
+        #[cfg(feature = "gamepad")]
+        if let Some(gamepads) = gamepads.as_mut() {
+            gamepads.poll(&mut input);
+        }
+        if e.resize_args().is_some() || e.focus_args().is_some() {
+            redraw = true;
+        }
+        let dirty = match &flicker {
+            Some(flicker) => flicker.is_dirty(),
+            None => computer.display.is_dirty(),
+        };
+        if e.render_args().is_some() && (redraw || dirty) {
+            computer.display.take_dirty();
+            let screen: &dyn Screen = match &mut flicker {
+                Some(flicker) => {
+                    flicker.take_dirty();
+                    flicker
+                }
+                None => &computer.display,
+            };
+            draw_screen(
+                screen,
+                &panel,
+                &mut texture,
+                &mut texture_context,
+                &mut window,
+                &e,
+                zoom as f64,
+            );
+            Window::swap_buffers(&mut window);
+            redraw = false;
         }
 
-        if let Some(u) = e.update_args() {
-            instruction_time_left += u.dt;
-            while instruction_time_left > 1.0 / chip8::INSTRUCTION_RATE as f64 {
-                instruction_time_left -= 1.0 / chip8::INSTRUCTION_RATE as f64;
-                clock_time_left += 1.0 / chip8::INSTRUCTION_RATE as f64;
-                computer.run_cycle();
-                if clock_time_left > 1.0 / chip8::TIMER_RATE as f64 {
-                    computer.timer_tick();
-                    clock_time_left -= 1.0 / chip8::TIMER_RATE as f64;
+        // Backspace runs the game backwards while it's held
+        if let (Some(u), true) = (e.update_args(), rewinding) {
+            let cycles = (u.dt * chip8::INSTRUCTION_RATE as f64) as u64;
+            let rewound = computer.rewind(cycles);
+            if let Some(flicker) = flicker.as_mut() {
+                flicker.frame(&computer.display);
+            }
+            if rewound && error.is_some() {
+                error = None;
+                window.set_title("Rust-8 Emulator".to_string());
+            }
+        }
+
+        if let (Some(u), None, false) = (e.update_args(), error, rewinding) {
+            let elapsed = Duration::from_secs_f64(u.dt);
+            let result = runner.advance_with(elapsed, |step| {
+                if let (Step::Cycle, Some(flicker)) = (step, flicker.as_mut()) {
+                    flicker.before_cycle(&computer);
+                }
+                let result = match (step, player.as_mut()) {
+                    (Step::Cycle, Some(player)) => {
+                        if player.is_finished(&computer) {
+                            println!("Movie finished, playing it again");
+                            // It started fine the first time around
+                            let (restarted, restarted_player) =
+                                start_playback(player.movie(), &game_data).unwrap();
+                            computer = restarted;
+                            *player = restarted_player;
+                        }
+                        player.run_cycle(&mut computer).map(|_| ())
+                    }
+                    (Step::Cycle, None) => computer
+                        .run_cycle()
+                        .map(|_| ())
+                        .map_err(MovieError::Machine),
+                    // Movies replay their own timer ticks
+                    (Step::TimerTick, Some(_)) => Ok(()),
+                    (Step::TimerTick, None) => {
+                        computer.timer_tick();
+                        // Turbo keys keep pace with the game's frames
+                        input.frame();
+                        input.apply(&mut computer);
+                        Ok(())
+                    }
+                };
+                // Frames still pass at the same pace during movies
+                if let (Step::TimerTick, Some(flicker)) = (step, flicker.as_mut()) {
+                    flicker.frame(&computer.display);
                 }
+                result
+            });
+            if let Err(err) = result {
+                eprintln!("Emulation stopped: {}", err);
+                eprintln!("{:?}", computer);
+                window.set_title(format!("Rust-8 Emulator - {}", err));
+                error = Some(err);
             }
+            let sounding = computer.is_sound_active() && !runner.is_paused() && !setup.mute;
+            #[cfg(feature = "sound")]
+            let bell = match &sound {
+                Some(sound) => {
+                    sound.play(&computer, sounding);
+                    false
+                }
+                None => true,
+            };
+            // Without an audio backend, ring the terminal bell whenever a tone starts
+            #[cfg(not(feature = "sound"))]
+            let bell = true;
+            if bell && sounding && !sound_active {
+                print!("\x07");
+                io::stdout().flush().ok();
+            }
+            sound_active = sounding;
         }
 
         if let Some(Button::Keyboard(key)) = e.release_args() {
-            if let Some(key_value) = key_value(&key) {
-                computer.handle_key_release(key_value);
+            if key == Key::LShift || key == Key::RShift {
+                shift_held = false;
+            }
+            if key == Key::Backspace {
+                rewinding = false;
             }
+            if key == Key::Tab {
+                runner.set_fast_forward(1);
+            }
+            input.release(Binding::Key(key));
         }
 
         if let Some(Button::Keyboard(key)) = e.press_args() {
-            if let Some(key_value) = key_value(&key) {
-                computer.handle_key_press(key_value);
+            if key == Key::LShift || key == Key::RShift {
+                shift_held = true;
+            }
+            if key == Key::Backspace {
+                rewinding = true;
+            }
+            if key == Key::Tab {
+                runner.set_fast_forward(FAST_FORWARD);
+            }
+            if key == Key::P {
+                runner.set_paused(!runner.is_paused());
             }
+            if let Some(slot) = save_slot(&key) {
+                let path = format!("{}.state{}", file_name, slot);
+                if shift_held {
+                    match save_state(&computer, &path) {
+                        Ok(()) => println!("Saved slot {}", slot),
+                        Err(err) => eprintln!("Couldn't save slot {}: {}", slot, err),
+                    }
+                } else if movie_active {
+                    eprintln!("States can't be loaded while recording or playing a movie");
+                } else {
+                    match load_state(&mut computer, &path) {
+                        Ok(()) => {
+                            println!("Loaded slot {}", slot);
+                            if let Some(flicker) = flicker.as_mut() {
+                                flicker.frame(&computer.display);
+                            }
+                            // The loaded state may be from before the program faulted
+                            error = None;
+                            window.set_title("Rust-8 Emulator".to_string());
+                        }
+                        Err(err) => eprintln!("Couldn't load slot {}: {}", slot, err),
+                    }
+                }
+            }
+            input.press(Binding::Key(key));
+        }
+
+        // Movies press their own keys
+        if player.is_none() {
+            input.apply(&mut computer);
+        }
+    }
+
+    if let (Some(path), Some(movie)) = (record, computer.stop_recording()) {
+        match fs::write(&path, movie.to_bytes()) {
+            Ok(()) => println!("Recorded {} cycles to {}", movie.length, path),
+            Err(err) => eprintln!("Couldn't write the movie {}: {}", path, err),
         }
     }
 }
 
-fn key_value(key: &Key) -> Option<u8> {
-    if key.code() >= 48 && key.code() <= 57 {
-        Some((key.code() - 48) as u8)
-    } else if key.code() >= 97 && key.code() <= 102 {
-        Some((key.code() - 97 + 10) as u8)
-    } else {
-        None
+fn parse_args() -> Result<Options, String> {
+    let mut args = env::args().skip(1);
+    let mut positional = Vec::new();
+    let mut options = Options {
+        rom: String::new(),
+        settings: Settings::default(),
+        seed: None,
+        record: None,
+        play: None,
+        config: None,
+    };
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| format!("{} needs a value", arg));
+        let settings = &mut options.settings;
+        match arg.as_str() {
+            "--preset" => settings.preset = Some(value()?),
+            "--rate" => settings.rate = Some(parse(&value()?)?),
+            "--per-frame" => settings.per_frame = Some(parse(&value()?)?),
+            "--scale" => settings.scale = Some(parse(&value()?)?),
+            "--foreground" => settings.foreground = Some(value()?),
+            "--background" => settings.background = Some(value()?),
+            "--keymap" => settings.keymap = Some(value()?),
+            "--bind" => {
+                let value = value()?;
+                let (binding, key) = (value.split_once('='))
+                    .ok_or_else(|| format!("{}: expected INPUT=KEY", value))?;
+                (settings.keys.get_or_insert_with(Default::default))
+                    .insert(binding.to_string(), key.to_string());
+            }
+            "--turbo" => settings.turbo = Some(value()?.split(',').map(String::from).collect()),
+            "--turbo-rate" => settings.turbo_rate = Some(parse(&value()?)?),
+            "--paused" => settings.paused = Some(true),
+            "--mute" => settings.mute = Some(true),
+            "--filter" => settings.filter = Some(value()?),
+            "--effect" => settings.effect = Some(value()?),
+            "--flicker" => settings.flicker = Some(value()?),
+            "--seed" => options.seed = Some(parse(&value()?)?),
+            "--record" => options.record = Some(value()?),
+            "--play" => options.play = Some(value()?),
+            "--config" => options.config = Some(value()?.into()),
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                process::exit(0);
+            }
+            _ if arg.starts_with('-') => return Err(format!("unexpected argument {}", arg)),
+            _ => positional.push(arg),
+        }
+    }
+    let mut positional = positional.into_iter();
+    options.rom = positional.next().ok_or("missing ROM")?;
+    if let Some(preset) = positional.next() {
+        options.settings.preset = options.settings.preset.or(Some(preset));
+    }
+    if let Some(arg) = positional.next() {
+        return Err(format!("unexpected argument {}", arg));
     }
+    Ok(options)
 }
 
+fn parse<T>(value: &str) -> Result<T, String>
+where
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    value
+        .parse()
+        .map_err(|error| format!("{}: {}", value, error))
+}
+
+/// Creates the machine a movie was recorded on and a player to drive it
+fn start_playback(movie: &Movie, game: &[u8]) -> Result<(Chip8<XorShift>, Player), MovieError> {
+    let computer = movie.machine(game, XorShift::new(0))?;
+    Ok((computer, Player::new(movie.clone())))
+}
+
+/// Save state slots live on F1 to F9, loaded on a press and saved with shift held
+fn save_slot(key: &Key) -> Option<u8> {
+    let slot = match *key {
+        Key::F1 => 1,
+        Key::F2 => 2,
+        Key::F3 => 3,
+        Key::F4 => 4,
+        Key::F5 => 5,
+        Key::F6 => 6,
+        Key::F7 => 7,
+        Key::F8 => 8,
+        Key::F9 => 9,
+        _ => return None,
+    };
+    Some(slot)
+}
+
+fn save_state(computer: &Chip8<XorShift>, path: &str) -> Result<(), String> {
+    let mut state = vec![0; STATE_SIZE];
+    computer
+        .save_state(&mut state)
+        .map_err(|err| err.to_string())?;
+    fs::write(path, state).map_err(|err| err.to_string())
+}
+
+fn load_state(computer: &mut Chip8<XorShift>, path: &str) -> Result<(), String> {
+    let state = fs::read(path).map_err(|err| err.to_string())?;
+    computer.load_state(&state).map_err(|err| err.to_string())
+}
+
+/// Draws the display into the texture, then the texture into the window
 fn draw_screen(
-    display_buffer: &display::Buffer,
+    screen: &dyn Screen,
+    panel: &Panel,
+    texture: &mut G2dTexture,
+    texture_context: &mut G2dTextureContext,
     window: &mut PistonWindow,
     e: &piston_window::Event,
+    zoom: f64,
 ) {
-    window.draw_2d(e, |context, graphics, _| {
-        piston_window::clear(color::BLACK, graphics);
+    let image: Vec<u8> = OutputData::with_panel(screen, panel)
+        .flat_map(|pixel| {
+            let [_, r, g, b] = pixel.to_be_bytes();
+            [r, g, b, 0xFF]
+        })
+        .collect();
+    let size = [panel.width, panel.height];
+    UpdateTexture::update(
+        texture,
+        texture_context,
+        Format::Rgba8,
+        &image,
+        [0, 0],
+        size,
+    )
+    .expect("Couldn't update the screen texture");
 
-        for (i, row) in display_buffer.iter().enumerate() {
-            for (j, val) in row.iter().enumerate() {
-                if *val {
-                    let dimensions = [
-                        (j * ENLARGEMENT_FACTOR) as f64,
-                        (i * ENLARGEMENT_FACTOR) as f64,
-                        ENLARGEMENT_FACTOR as f64,
-                        ENLARGEMENT_FACTOR as f64,
-                    ];
-                    Rectangle::new(color::WHITE).draw(
-                        dimensions,
-                        &context.draw_state,
-                        context.transform,
-                        graphics,
-                    );
-                }
-            }
-        }
+    window.draw_2d(e, |context, graphics, device| {
+        texture_context.encoder.flush(device);
+        piston_window::clear(color::BLACK, graphics);
+        piston_window::image(&*texture, context.transform.scale(zoom, zoom), graphics);
     });
 }
 
 #[allow(dead_code)]
 fn debug(display_buffer: &display::Buffer) {
-    for row in display_buffer.iter() {
+    for y in 0..display::HEIGHT {
         print!("|");
-        for val in row.iter() {
-            if *val {
+        for val in display_buffer.row_pixels(y) {
+            if val != 0 {
                 print!("*")
             } else {
                 print!(".")
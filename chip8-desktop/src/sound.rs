@@ -0,0 +1,120 @@
+//! The buzzer, played with rodio.
+//!
+//! XO-CHIP games play their 1-bit audio pattern at their pitch, other games
+//! get a square wave from the default pattern.
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use chip8::random::XorShift;
+use chip8::Chip8;
+use rodio::{OutputStream, Source};
+
+const SAMPLE_RATE: u32 = 44_100;
+/// The XO-CHIP pitch games start with
+const DEFAULT_PITCH: u8 = 64;
+/// Quiet enough not to clip, square waves are loud
+const VOLUME: f32 = 0.2;
+/// A 250 Hz square wave at the default pitch, for games that never load a
+/// pattern
+const SQUARE_PATTERN: [u8; 16] = [
+    0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00,
+];
+
+/// What the buzzer is playing, shared with the audio thread
+#[derive(Clone, Copy)]
+struct Tone {
+    active: bool,
+    pattern: [u8; 16],
+    /// Pattern bits played every second
+    bit_rate: f32,
+}
+
+pub struct Sound {
+    // Playback stops when the stream is dropped
+    _stream: OutputStream,
+    tone: Arc<Mutex<Tone>>,
+}
+
+impl Sound {
+    pub fn new() -> Result<Sound, String> {
+        let (stream, handle) = OutputStream::try_default().map_err(|err| err.to_string())?;
+        let tone = Arc::new(Mutex::new(Tone {
+            active: false,
+            pattern: SQUARE_PATTERN,
+            bit_rate: bit_rate(DEFAULT_PITCH),
+        }));
+        let buzzer = Buzzer {
+            tone: Arc::clone(&tone),
+            position: 0.0,
+        };
+        handle.play_raw(buzzer).map_err(|err| err.to_string())?;
+        Ok(Sound {
+            _stream: stream,
+            tone,
+        })
+    }
+
+    /// Plays the machine's tone while `active`, and silence otherwise
+    pub fn play(&self, computer: &Chip8<XorShift>, active: bool) {
+        let pattern = computer.audio_pattern();
+        let pattern = if pattern.iter().all(|&bits| bits == 0) {
+            SQUARE_PATTERN
+        } else {
+            *pattern
+        };
+        let mut tone = self.tone.lock().unwrap();
+        *tone = Tone {
+            active,
+            pattern,
+            bit_rate: bit_rate(computer.pitch()),
+        };
+    }
+}
+
+/// `4000 * 2^((pitch - 64) / 48)` bits per second
+fn bit_rate(pitch: u8) -> f32 {
+    4000.0 * 2f32.powf((pitch as f32 - 64.0) / 48.0)
+}
+
+/// Samples of the current tone, forever
+struct Buzzer {
+    tone: Arc<Mutex<Tone>>,
+    /// The bit of the pattern being played, and how far into it
+    position: f32,
+}
+
+impl Iterator for Buzzer {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        let tone = *self.tone.lock().unwrap();
+        if !tone.active {
+            self.position = 0.0;
+            return Some(0.0);
+        }
+        let bit = self.position as usize;
+        let high = tone.pattern[bit / 8] & (0x80 >> (bit % 8)) != 0;
+        let bits = (tone.pattern.len() * 8) as f32;
+        self.position = (self.position + tone.bit_rate / SAMPLE_RATE as f32) % bits;
+        Some(if high { VOLUME } else { -VOLUME })
+    }
+}
+
+impl Source for Buzzer {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        1
+    }
+
+    fn sample_rate(&self) -> u32 {
+        SAMPLE_RATE
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
@@ -0,0 +1,231 @@
+use std::fmt;
+use std::ops::Range;
+
+use chip8::display::{Buffer, HEIGHT, PLANES};
+use chip8::error::{Chip8Error, StepOutcome};
+use chip8::instruction::Instruction;
+use chip8::trace::TraceEntry;
+use chip8::Chip8;
+use random_trait::Random;
+
+/// At most this many runs of differing memory are reported
+const MAX_MEMORY_RUNS: usize = 8;
+
+/// A single way in which the two sides differ, left first
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Difference {
+    /// One side ran an instruction, the other didn't or failed
+    Outcome(
+        Result<StepOutcome, Chip8Error>,
+        Result<StepOutcome, Chip8Error>,
+    ),
+    ProgramCounter(u16, u16),
+    Opcode(u16, u16),
+    Register(u8, u8, u8),
+    I(u16, u16),
+    Stack(Vec<u16>, Vec<u16>),
+    DelayTimer(u8, u8),
+    SoundTimer(u8, u8),
+    /// Whether the display is in the 128x64 resolution
+    Hires(bool, bool),
+    /// The bitmask of the planes that are drawn to
+    Planes(u8, u8),
+    /// A run of bytes that differ at every address
+    Memory {
+        address: u16,
+        left: Vec<u8>,
+        right: Vec<u8>,
+    },
+    Display {
+        pixels: usize,
+        first: (usize, usize),
+    },
+}
+
+/// Compares everything the instructions can change, except that memory is
+/// only compared in `written`, the bytes the last instructions could have
+/// written
+pub fn compare<R: Random>(
+    left: &Chip8<R>,
+    right: &Chip8<R>,
+    written: &[Range<usize>],
+) -> Vec<Difference> {
+    let mut differences = Vec::new();
+    if left.program_counter() != right.program_counter() {
+        differences.push(Difference::ProgramCounter(
+            left.program_counter(),
+            right.program_counter(),
+        ));
+    }
+    for (reg, (&l, &r)) in left.regs().iter().zip(right.regs()).enumerate() {
+        if l != r {
+            differences.push(Difference::Register(reg as u8, l, r));
+        }
+    }
+    if left.i_reg() != right.i_reg() {
+        differences.push(Difference::I(left.i_reg(), right.i_reg()));
+    }
+    if left.stack() != right.stack() {
+        differences.push(Difference::Stack(
+            left.stack().to_vec(),
+            right.stack().to_vec(),
+        ));
+    }
+    if left.delay_timer() != right.delay_timer() {
+        differences.push(Difference::DelayTimer(
+            left.delay_timer(),
+            right.delay_timer(),
+        ));
+    }
+    if left.sound_timer() != right.sound_timer() {
+        differences.push(Difference::SoundTimer(
+            left.sound_timer(),
+            right.sound_timer(),
+        ));
+    }
+    for range in merge(written) {
+        differences.extend(compare_memory(left.memory(), right.memory(), range));
+    }
+    if left.display.is_hires() != right.display.is_hires() {
+        differences.push(Difference::Hires(
+            left.display.is_hires(),
+            right.display.is_hires(),
+        ));
+    }
+    if left.display.selected_planes() != right.display.selected_planes() {
+        differences.push(Difference::Planes(
+            left.display.selected_planes(),
+            right.display.selected_planes(),
+        ));
+    }
+    if left.display.buffer() != right.display.buffer() {
+        differences.extend(compare_display(
+            left.display.buffer(),
+            right.display.buffer(),
+        ));
+    }
+    differences
+}
+
+fn compare_display(left: &Buffer, right: &Buffer) -> Option<Difference> {
+    let mut pixels = 0;
+    let mut first = None;
+    for y in 0..HEIGHT {
+        let differing = (0..PLANES as usize).fold(0, |differing, plane| {
+            differing | (left.row(plane, y) ^ right.row(plane, y))
+        });
+        if differing != 0 {
+            pixels += differing.count_ones() as usize;
+            first.get_or_insert((differing.leading_zeros() as usize, y));
+        }
+    }
+    first.map(|first| Difference::Display { pixels, first })
+}
+
+/// The memory an instruction could have written, the instruction stores
+/// registers or BCD digits at I
+pub fn written_memory(entry: &TraceEntry) -> Option<Range<usize>> {
+    let length = match entry.instruction {
+        Instruction::StoreRegisters(highest_reg) => highest_reg as usize + 1,
+        Instruction::SaveRange(first, last) => (first as usize).abs_diff(last as usize) + 1,
+        Instruction::BCDRepresentation(_) => 3,
+        _ => return None,
+    };
+    let start = entry.before.i as usize;
+    Some(start..start + length)
+}
+
+/// Joins ranges that overlap, so no byte is compared twice
+fn merge(ranges: &[Range<usize>]) -> Vec<Range<usize>> {
+    let mut ranges = ranges.to_vec();
+    ranges.sort_by_key(|range| range.start);
+    let mut merged: Vec<Range<usize>> = Vec::new();
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+            _ => merged.push(range),
+        }
+    }
+    merged
+}
+
+fn compare_memory(left: &[u8], right: &[u8], range: Range<usize>) -> Vec<Difference> {
+    let end = range.end.min(left.len()).min(right.len());
+    let mut runs = Vec::new();
+    let mut address = range.start;
+    while address < end && runs.len() < MAX_MEMORY_RUNS {
+        if left[address] == right[address] {
+            address += 1;
+            continue;
+        }
+        let start = address;
+        while address < end && left[address] != right[address] {
+            address += 1;
+        }
+        runs.push(Difference::Memory {
+            address: start as u16,
+            left: left[start..address].to_vec(),
+            right: right[start..address].to_vec(),
+        });
+    }
+    runs
+}
+
+impl fmt::Display for Difference {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Difference::Outcome(left, right) => {
+                write!(f, "outcome: {} != {}", Outcome(left), Outcome(right))
+            }
+            Difference::ProgramCounter(left, right) => {
+                write!(f, "PC: {:#05X} != {:#05X}", left, right)
+            }
+            Difference::Opcode(left, right) => write!(f, "opcode: {:04X} != {:04X}", left, right),
+            Difference::Register(reg, left, right) => {
+                write!(f, "V{:X}: {:#04X} != {:#04X}", reg, left, right)
+            }
+            Difference::I(left, right) => write!(f, "I: {:#05X} != {:#05X}", left, right),
+            Difference::Stack(left, right) => write!(f, "stack: {:X?} != {:X?}", left, right),
+            Difference::DelayTimer(left, right) => write!(f, "DT: {} != {}", left, right),
+            Difference::SoundTimer(left, right) => write!(f, "ST: {} != {}", left, right),
+            Difference::Hires(left, right) => write!(f, "hires: {} != {}", left, right),
+            Difference::Planes(left, right) => {
+                write!(f, "planes: {:02b} != {:02b}", left, right)
+            }
+            Difference::Memory {
+                address,
+                left,
+                right,
+            } => {
+                write!(f, "memory {:#05X}:", address)?;
+                for byte in left {
+                    write!(f, " {:02X}", byte)?;
+                }
+                f.write_str(" !=")?;
+                for byte in right {
+                    write!(f, " {:02X}", byte)?;
+                }
+                Ok(())
+            }
+            Difference::Display { pixels, first } => write!(
+                f,
+                "display: {} pixels differ, the first at ({}, {})",
+                pixels, first.0, first.1
+            ),
+        }
+    }
+}
+
+struct Outcome<'a>(&'a Result<StepOutcome, Chip8Error>);
+
+impl<'a> fmt::Display for Outcome<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            Ok(StepOutcome::Ran) => f.write_str("ran"),
+            Ok(StepOutcome::WaitingForKey) => f.write_str("waiting for a key"),
+            Ok(StepOutcome::WaitingForVBlank) => f.write_str("waiting for the display"),
+            Ok(StepOutcome::Halted) => f.write_str("halted"),
+            Err(error) => write!(f, "{}", error),
+        }
+    }
+}
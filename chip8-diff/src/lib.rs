@@ -0,0 +1,185 @@
+//! Finds the first instruction where two runs of a ROM go apart.
+//!
+//! Either two `Chip8`s run in lockstep, e.g. with different quirks or a
+//! patched ROM, or one runs against a trace recorded in the format of
+//! `chip8::trace`. Both sides get their timer ticks at the same cycles, so
+//! with the same seeded random number generator the only differences come
+//! from the ROMs or the interpreters themselves.
+
+mod difference;
+mod recorded;
+
+use std::collections::VecDeque;
+
+use chip8::error::{Chip8Error, StepOutcome};
+use chip8::trace::TraceEntry;
+use chip8::{Chip8, INSTRUCTION_RATE, TIMER_RATE};
+use random_trait::Random;
+
+pub use crate::difference::{compare, written_memory, Difference};
+pub use crate::recorded::{ParseError, RecordedEntry};
+
+/// Where the runs went apart
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+    /// The number of cycles run when the difference showed up
+    pub cycle: u64,
+    pub differences: Vec<Difference>,
+    /// The last instructions on each side, oldest first, formatted as trace lines
+    pub left_history: Vec<String>,
+    pub right_history: Vec<String>,
+}
+
+/// Keeps the last few trace lines of a run
+struct History {
+    lines: VecDeque<String>,
+    len: usize,
+}
+
+impl History {
+    fn new(len: usize) -> History {
+        History {
+            lines: VecDeque::with_capacity(len),
+            len,
+        }
+    }
+
+    fn push(&mut self, line: String) {
+        if self.len == 0 {
+            return;
+        }
+        if self.lines.len() == self.len {
+            self.lines.pop_front();
+        }
+        self.lines.push_back(line);
+    }
+
+    fn to_vec(&self) -> Vec<String> {
+        self.lines.iter().cloned().collect()
+    }
+}
+
+/// Ticks the timers at `TIMER_RATE` relative to `INSTRUCTION_RATE`
+#[derive(Default)]
+struct Timers {
+    progress: u32,
+}
+
+impl Timers {
+    fn tick<R: Random>(&mut self, machines: &mut [&mut Chip8<R>]) {
+        self.progress += TIMER_RATE;
+        if self.progress >= INSTRUCTION_RATE {
+            self.progress -= INSTRUCTION_RATE;
+            for chip8 in machines {
+                chip8.timer_tick();
+            }
+        }
+    }
+}
+
+/// Runs two machines side by side, comparing them after every cycle. Memory
+/// is only compared where an instruction on either side stored to it.
+pub struct Lockstep<R: Random> {
+    pub left: Chip8<R>,
+    pub right: Chip8<R>,
+    left_history: History,
+    right_history: History,
+    timers: Timers,
+}
+
+impl<R: Random> Lockstep<R> {
+    /// Keeps the last `history` instructions of each machine for the report
+    pub fn new(left: Chip8<R>, right: Chip8<R>, history: usize) -> Lockstep<R> {
+        Lockstep {
+            left,
+            right,
+            left_history: History::new(history),
+            right_history: History::new(history),
+            timers: Timers::default(),
+        }
+    }
+
+    /// Runs up to `cycles` cycles, stopping at the first difference or once
+    /// both machines halted. Fails if both fail in the same way.
+    pub fn run(&mut self, cycles: u64) -> Result<Option<Divergence>, Chip8Error> {
+        for _ in 0..cycles {
+            let left_history = &mut self.left_history;
+            let right_history = &mut self.right_history;
+            let mut written = Vec::new();
+            let left = self.left.run_cycle_traced(&mut |entry: &TraceEntry| {
+                left_history.push(entry.to_string());
+                written.extend(written_memory(entry));
+            });
+            let right = self.right.run_cycle_traced(&mut |entry: &TraceEntry| {
+                right_history.push(entry.to_string());
+                written.extend(written_memory(entry));
+            });
+            self.timers.tick(&mut [&mut self.left, &mut self.right]);
+
+            let mut differences = Vec::new();
+            match (left, right) {
+                (Err(left), Err(right)) if left == right => return Err(left),
+                (left, right) if left != right => {
+                    differences.push(Difference::Outcome(left, right))
+                }
+                _ => {}
+            }
+            differences.extend(compare(&self.left, &self.right, &written));
+            if !differences.is_empty() {
+                return Ok(Some(Divergence {
+                    cycle: self.left.cycles(),
+                    differences,
+                    left_history: self.left_history.to_vec(),
+                    right_history: self.right_history.to_vec(),
+                }));
+            }
+            if left == Ok(StepOutcome::Halted) {
+                break;
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Runs `chip8` against a recorded trace, stopping at the first entry that
+/// doesn't match the instruction it ran. Cycle numbers in the trace are
+/// ignored, since other emulators count them differently. Cycles where no
+/// instruction runs because the machine waits for the display don't use up
+/// an entry.
+pub fn check_trace<R, I>(chip8: &mut Chip8<R>, recorded: I, history: usize) -> Option<Divergence>
+where
+    R: Random,
+    I: IntoIterator<Item = RecordedEntry>,
+{
+    let mut left_history = History::new(history);
+    let mut right_history = History::new(history);
+    let mut timers = Timers::default();
+
+    for expected in recorded {
+        right_history.push(expected.to_string());
+        let (outcome, entry) = loop {
+            let mut ran = None;
+            let outcome = chip8.run_cycle_traced(&mut |entry: &TraceEntry| ran = Some(*entry));
+            timers.tick(&mut [&mut *chip8]);
+            if outcome != Ok(StepOutcome::WaitingForVBlank) {
+                break (outcome, ran);
+            }
+        };
+        let differences = match entry {
+            Some(entry) => {
+                left_history.push(entry.to_string());
+                expected.compare(&entry)
+            }
+            None => vec![Difference::Outcome(outcome, Ok(StepOutcome::Ran))],
+        };
+        if !differences.is_empty() {
+            return Some(Divergence {
+                cycle: chip8.cycles(),
+                differences,
+                left_history: left_history.to_vec(),
+                right_history: right_history.to_vec(),
+            });
+        }
+    }
+    None
+}
@@ -0,0 +1,143 @@
+use std::fs;
+use std::process;
+
+use chip8::quirks::{Preset, Quirks};
+use chip8::random::XorShift;
+use chip8::{Chip8, Mode};
+use chip8_diff::{check_trace, Divergence, Lockstep, RecordedEntry};
+
+const USAGE: &str = "usage: chip8-diff ROM [--preset NAME] \
+[--against ROM] [--against-preset NAME] [--trace FILE] \
+[--cycles N] [--seed N] [--history N]";
+
+struct Options {
+    rom: String,
+    preset: Option<Preset>,
+    against: Option<String>,
+    against_preset: Option<Preset>,
+    trace: Option<String>,
+    cycles: u64,
+    seed: u64,
+    history: usize,
+}
+
+fn main() {
+    let options = parse_args().unwrap_or_else(|error| {
+        eprintln!("{}\n{}", error, USAGE);
+        process::exit(2);
+    });
+    let left = load(&options.rom, options.preset, options.seed);
+
+    let divergence = match &options.trace {
+        Some(path) => {
+            let text = fs::read_to_string(path).unwrap_or_else(|err| {
+                eprintln!("Couldn't read {}: {}", path, err);
+                process::exit(1);
+            });
+            let recorded = text
+                .lines()
+                .filter(|line| !line.trim().is_empty())
+                .map(|line| line.parse::<RecordedEntry>())
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap_or_else(|error| {
+                    eprintln!("{}: {}", path, error);
+                    process::exit(1);
+                });
+            let mut chip8 = left;
+            check_trace(&mut chip8, recorded, options.history)
+        }
+        None => {
+            let right = load(
+                options.against.as_ref().unwrap_or(&options.rom),
+                options.against_preset.or(options.preset),
+                options.seed,
+            );
+            let mut lockstep = Lockstep::new(left, right, options.history);
+            lockstep.run(options.cycles).unwrap_or_else(|error| {
+                eprintln!("Both sides failed: {}", error);
+                process::exit(1);
+            })
+        }
+    };
+
+    match divergence {
+        Some(divergence) => {
+            report(&divergence);
+            process::exit(1);
+        }
+        None => println!("No differences"),
+    }
+}
+
+fn report(divergence: &Divergence) {
+    println!("Diverged after {} cycles", divergence.cycle);
+    for difference in &divergence.differences {
+        println!("  {}", difference);
+    }
+    println!("Left:");
+    for line in &divergence.left_history {
+        println!("  {}", line);
+    }
+    println!("Right:");
+    for line in &divergence.right_history {
+        println!("  {}", line);
+    }
+}
+
+fn load(path: &str, preset: Option<Preset>, seed: u64) -> Chip8<XorShift> {
+    let rom = fs::read(path).unwrap_or_else(|err| {
+        eprintln!("Couldn't read {}: {}", path, err);
+        process::exit(1);
+    });
+    let (mode, quirks) = match preset {
+        Some(preset) => (preset.mode(), preset.quirks()),
+        None => (Mode::Chip8, Quirks::default()),
+    };
+    Chip8::with_mode(&rom, XorShift::new(seed), mode, quirks).unwrap_or_else(|error| {
+        eprintln!("Couldn't load {}: {}", path, error);
+        process::exit(1);
+    })
+}
+
+fn parse_args() -> Result<Options, String> {
+    let mut args = std::env::args().skip(1);
+    let mut options = Options {
+        rom: String::new(),
+        preset: None,
+        against: None,
+        against_preset: None,
+        trace: None,
+        cycles: 100_000,
+        seed: 1,
+        history: 8,
+    };
+    let mut rom = None;
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| format!("{} needs a value", arg));
+        match arg.as_str() {
+            "--preset" => options.preset = Some(parse(&value()?)?),
+            "--against" => options.against = Some(value()?),
+            "--against-preset" => options.against_preset = Some(parse(&value()?)?),
+            "--trace" => options.trace = Some(value()?),
+            "--cycles" => options.cycles = parse(&value()?)?,
+            "--seed" => options.seed = parse(&value()?)?,
+            "--history" => options.history = parse(&value()?)?,
+            _ if arg.starts_with("--") || rom.is_some() => {
+                return Err(format!("unexpected argument {}", arg))
+            }
+            _ => rom = Some(arg),
+        }
+    }
+    options.rom = rom.ok_or("missing ROM")?;
+    Ok(options)
+}
+
+fn parse<T>(value: &str) -> Result<T, String>
+where
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    value
+        .parse()
+        .map_err(|error| format!("{}: {}", value, error))
+}
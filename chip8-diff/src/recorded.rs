@@ -0,0 +1,119 @@
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+
+use chip8::trace::{Registers, TraceEntry};
+
+use crate::Difference;
+
+/// A line of a recorded trace. Only what other emulators can be expected to
+/// log is kept, the cycle and the mnemonic are skipped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecordedEntry {
+    pub pc: u16,
+    pub opcode: u16,
+    pub before: Registers,
+    pub after: Registers,
+}
+
+impl RecordedEntry {
+    /// Compares the entry, as the expected side, with what actually ran
+    pub fn compare(&self, entry: &TraceEntry) -> Vec<Difference> {
+        let mut differences = Vec::new();
+        if entry.pc != self.pc {
+            differences.push(Difference::ProgramCounter(entry.pc, self.pc));
+        }
+        if entry.opcode != self.opcode {
+            differences.push(Difference::Opcode(entry.opcode, self.opcode));
+        }
+        // A difference before the instruction shows up after the previous one,
+        // unless it's the first or something outside the registers caused it
+        for (ours, expected) in [(&entry.before, &self.before), (&entry.after, &self.after)] {
+            for (reg, (&l, &r)) in ours.v.iter().zip(&expected.v).enumerate() {
+                if l != r {
+                    differences.push(Difference::Register(reg as u8, l, r));
+                }
+            }
+            if ours.i != expected.i {
+                differences.push(Difference::I(ours.i, expected.i));
+            }
+            if !differences.is_empty() {
+                break;
+            }
+        }
+        differences
+    }
+}
+
+impl fmt::Display for RecordedEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04X}: {:04X} {} -> {}",
+            self.pc, self.opcode, self.before, self.after
+        )
+    }
+}
+
+/// A trace line that isn't in the `chip8::trace` format
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError(pub String);
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "not a trace line: {}", self.0)
+    }
+}
+
+impl Error for ParseError {}
+
+impl FromStr for RecordedEntry {
+    type Err = ParseError;
+
+    /// Parses a line, the leading cycle and the trailing mnemonic are optional
+    fn from_str(line: &str) -> Result<RecordedEntry, ParseError> {
+        let error = || ParseError(line.to_string());
+        let fields = line.split(" ; ").next().unwrap_or_default();
+        let mut tokens = fields.split_whitespace().peekable();
+        if tokens.peek().is_some_and(|token| !token.ends_with(':')) {
+            tokens.next();
+        }
+        let pc = tokens
+            .next()
+            .and_then(|token| token.strip_suffix(':'))
+            .and_then(|pc| u16::from_str_radix(pc, 16).ok())
+            .ok_or_else(error)?;
+        let opcode = tokens
+            .next()
+            .and_then(|opcode| u16::from_str_radix(opcode, 16).ok())
+            .ok_or_else(error)?;
+        let before = parse_registers(&mut tokens).ok_or_else(error)?;
+        if tokens.next() != Some("->") {
+            return Err(error());
+        }
+        let after = parse_registers(&mut tokens).ok_or_else(error)?;
+        if tokens.next().is_some() {
+            return Err(error());
+        }
+        Ok(RecordedEntry {
+            pc,
+            opcode,
+            before,
+            after,
+        })
+    }
+}
+
+fn parse_registers<'a>(tokens: &mut impl Iterator<Item = &'a str>) -> Option<Registers> {
+    let v_hex = tokens.next()?.strip_prefix("V=")?;
+    let i_hex = tokens.next()?.strip_prefix("I=")?;
+    if v_hex.len() != 32 || !v_hex.is_ascii() {
+        return None;
+    }
+    let mut v = [0; 16];
+    for (reg, value) in v.iter_mut().enumerate() {
+        *value = u8::from_str_radix(&v_hex[reg * 2..reg * 2 + 2], 16).ok()?;
+    }
+    let i = u16::from_str_radix(i_hex, 16).ok()?;
+    Some(Registers { v, i })
+}
@@ -11,6 +11,7 @@ bitflags! {
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn read_keypad<R1, R2, R3, R4, C1, C2, C3, C4, DELAY, E>(
     delay: &mut DELAY,
     r1: &mut R1,
@@ -1,8 +1,7 @@
 #![no_main]
 #![no_std]
 
-#[allow(unused)]
-use panic_semihosting;
+use panic_semihosting as _;
 
 use stm32f1xx_hal as hal;
 
@@ -11,30 +10,34 @@ use crate::hal::{
     prelude::*,
     spi::Spi,
     stm32,
-    stm32::{interrupt, Interrupt, Peripherals, EXTI, TIM3, TIM4},
+    stm32::{interrupt, Interrupt, TIM4},
     time::Hertz,
     timer::Event,
     timer::Timer,
 };
-use embedded_hal::digital::v1_compat::OldOutputPin;
+use embedded_hal::digital::{v1_compat::OldOutputPin, v2::OutputPin};
 
+use chip8::output;
+use chip8::quirks::Quirks;
+use chip8::random::XorShift;
+use chip8::runner::Runner;
 use core::cell::RefCell;
+use core::iter;
 use cortex_m::interrupt::{free, Mutex};
 use cortex_m_rt::entry;
-use cortex_m_semihosting::hprintln;
 
 mod keypad;
-mod random;
 
-static INSTRUCTION_COUNTER: Mutex<RefCell<(u32, Option<Timer<TIM3>>)>> =
-    Mutex::new(RefCell::new((0, None)));
+const ERROR_COLOR: u16 = 0xF800;
+
+/// Frames that passed since the main loop last ran, counted at `TIMER_RATE`
 static DELAY_COUNTER: Mutex<RefCell<(u32, Option<Timer<TIM4>>)>> =
     Mutex::new(RefCell::new((0, None)));
 
 #[entry]
 fn main() -> ! {
     if let (Some(p), Some(cp)) = (stm32::Peripherals::take(), cortex_m::Peripherals::take()) {
-        let (mut ili, (mut r1, mut r2, mut r3, mut r4), (c1, c2, c3, c4), mut delay) =
+        let (mut ili, (mut r1, mut r2, mut r3, mut r4), (c1, c2, c3, c4), mut buzzer, mut delay) =
             cortex_m::interrupt::free(move |cs| {
                 let mut flash = p.FLASH.constrain();
                 let mut rcc = p.RCC.constrain();
@@ -68,6 +71,9 @@ fn main() -> ! {
                     gpioa.pa12.into_pull_up_input(&mut gpioa.crh),
                 );
 
+                // Active buzzer, driven high while the sound timer is running
+                let buzzer = gpiob.pb0.into_push_pull_output(&mut gpiob.crl);
+
                 // Display spi pins
                 let spi_pins = (
                     gpioa.pa5.into_alternate_push_pull(&mut gpioa.crl),
@@ -92,79 +98,73 @@ fn main() -> ! {
                 // Check display resolution
                 ili.set_orientation(ili9341::Orientation::Landscape)
                     .unwrap();
-                let mut instruction_timer = Timer::tim3(
-                    p.TIM3,
-                    Hertz(chip8::INSTRUCTION_RATE),
-                    clocks,
-                    &mut rcc.apb1,
-                );
-                instruction_timer.listen(Event::Update);
                 let mut delay_timer =
                     Timer::tim4(p.TIM4, Hertz(chip8::TIMER_RATE), clocks, &mut rcc.apb1);
                 delay_timer.listen(Event::Update);
-                nvic.enable(Interrupt::TIM3);
                 nvic.enable(Interrupt::TIM4);
-                INSTRUCTION_COUNTER.borrow(cs).borrow_mut().1 = Some(instruction_timer);
                 DELAY_COUNTER.borrow(cs).borrow_mut().1 = Some(delay_timer);
 
-                (ili, row_pins, collum_pins, delay)
+                (ili, row_pins, collum_pins, buzzer, delay)
             });
+        let panel = output::Panel::default();
+        let (right, bottom) = (panel.width as u16 - 1, panel.height as u16 - 1);
         let game_data = include_bytes!("../../Space Invaders.ch8");
-        let mut computer = chip8::Chip8::new(game_data, random::RandomGen { state: 43 });
-        let mut pressed_key = None;
-        loop {
-            let (instructions, delays) = free(|cs| {
-                let mut instruction_cell = INSTRUCTION_COUNTER.borrow(cs).borrow_mut();
-                let mut delay_cell = DELAY_COUNTER.borrow(cs).borrow_mut();
-                let counts = (instruction_cell.0, delay_cell.0);
-                instruction_cell.0 = 0;
-                delay_cell.0 = 0;
-                counts
-            });
+        // A bad program stops the emulation instead of panicking,
+        // the screen turns red to show what happened
+        // Chords press several keys at once, `FX0A` waits until the first one is let go
+        let quirks = Quirks {
+            wait_for_release: true,
+            ..Quirks::default()
+        };
+        if let Ok(mut computer) = chip8::Chip8::with_quirks(game_data, XorShift::new(43), quirks) {
+            // Redrawing the screen can take longer than a frame, the runner catches up
+            let mut runner = Runner::default();
+            // The border around the buffer is only drawn once
+            let output_iter = output::OutputData::with_panel(&computer.display, &panel);
+            ili.draw_iter(0, 0, right, bottom, output_iter.map(|pixel| pixel as u16))
+                .unwrap();
+            computer.display.take_dirty();
+            loop {
+                let frames = free(|cs| {
+                    let mut delay_cell = DELAY_COUNTER.borrow(cs).borrow_mut();
+                    let frames = delay_cell.0;
+                    delay_cell.0 = 0;
+                    frames
+                });
 
-            for _ in 0..instructions {
-                computer.run_cycle();
-            }
-            for _ in 0..delays {
-                computer.timer_tick();
-            }
-            let buffer = computer.display.get_buffer();
-            let output_iter = chip8::output::OutputData::new(&buffer);
-            ili.draw_iter(0, 0, 319, 239, output_iter).unwrap();
-            let pressed_keys = keypad::read_keypad(
-                &mut delay, &mut r1, &mut r2, &mut r3, &mut r4, &c1, &c2, &c3, &c4,
-            )
-            .unwrap();
-            let curr_pressed_key = pressed_keys.trailing_zeros() as u8;
-            // TODO Expand for multiple pressed keys
-            pressed_key = if curr_pressed_key == 16 {
-                if let Some(prev_pressed_key) = pressed_key {
-                    computer.handle_key_release(prev_pressed_key);
+                if runner.run_frames(&mut computer, frames).is_err() {
+                    break;
+                }
+                if computer.is_sound_active() {
+                    buzzer.set_high().unwrap();
+                } else {
+                    buzzer.set_low().unwrap();
                 }
-                None
-            } else {
-                if None == pressed_key {
-                    computer.handle_key_press(curr_pressed_key);
+                // Only what changed is sent, a full screen takes a while over SPI
+                if let Some(region) = computer.display.take_dirty() {
+                    let output =
+                        output::OutputRegion::with_panel(&computer.display, region, &panel);
+                    if let Some((x0, y0, x1, y1)) = output.window() {
+                        ili.draw_iter(x0, y0, x1, y1, output.map(|pixel| pixel as u16))
+                            .unwrap();
+                    }
                 }
-                Some(curr_pressed_key)
-            };
+                let pressed_keys = keypad::read_keypad(
+                    &mut delay, &mut r1, &mut r2, &mut r3, &mut r4, &c1, &c2, &c3, &c4,
+                )
+                .unwrap();
+                computer.set_keypad(pressed_keys);
+            }
         }
+        buzzer.set_low().unwrap();
+        let error_screen = iter::repeat_n(ERROR_COLOR, (panel.width * panel.height) as usize);
+        ili.draw_iter(0, 0, right, bottom, error_screen).unwrap();
     }
 
     loop {
         continue;
     }
 }
-// Instruction
-#[interrupt]
-fn TIM3() {
-    free(|cs| {
-        let mut cell = INSTRUCTION_COUNTER.borrow(cs).borrow_mut();
-        cell.0 += 1;
-        cell.1.as_mut().unwrap().clear_update_interrupt_flag();
-    });
-}
-
 // Delay
 #[interrupt]
 fn TIM4() {
@@ -0,0 +1,39 @@
+//! Prints a listing of a ROM image
+//!
+//! `cargo run --example disassemble -- $GAME [cowgod|octo] [chip8|schip|xochip]`
+use std::env;
+use std::fs;
+use std::process;
+
+use chip8::disasm::{Disassembler, Syntax};
+use chip8::Mode;
+
+fn main() {
+    let mut args = env::args().skip(1);
+    let file_name = args.next().unwrap_or_else(|| usage());
+    let syntax = match args.next().as_deref() {
+        None | Some("cowgod") => Syntax::Cowgod,
+        Some("octo") => Syntax::Octo,
+        Some(_) => usage(),
+    };
+    let mode = match args.next().as_deref() {
+        None | Some("chip8") => Mode::Chip8,
+        Some("schip") => Mode::SuperChip,
+        Some("xochip") => Mode::XoChip,
+        Some(_) => usage(),
+    };
+    let rom = fs::read(&file_name).unwrap_or_else(|error| {
+        eprintln!("{}: {}", file_name, error);
+        process::exit(1);
+    });
+
+    let disassembler = Disassembler::new(&rom, mode, syntax);
+    for line in disassembler.lines() {
+        println!("{}", line);
+    }
+}
+
+fn usage() -> ! {
+    eprintln!("usage: disassemble ROM [cowgod|octo] [chip8|schip|xochip]");
+    process::exit(2);
+}
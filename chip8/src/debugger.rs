@@ -0,0 +1,431 @@
+//! Breakpoints, watchpoints and stepping for debugging tools.
+//!
+//! The `Debugger` drives a `Chip8` itself, including the timers, so it
+//! knows where frames end. Everything has a fixed capacity to keep it usable
+//! without a heap.
+
+use core::fmt;
+
+use random_trait::Random;
+
+use crate::error::{Chip8Error, StepOutcome};
+use crate::instruction::{Instruction, Register};
+use crate::runner::Speed;
+use crate::{register_count, Chip8, Mode, AUDIO_PATTERN_SIZE, INSTRUCTION_RATE, TIMER_RATE};
+
+pub const MAX_BREAKPOINTS: usize = 16;
+pub const MAX_WATCHPOINTS: usize = 8;
+pub const MAX_CONDITIONS: usize = 8;
+/// Ten minutes at `INSTRUCTION_RATE`
+pub const DEFAULT_MAX_CYCLES: u64 = INSTRUCTION_RATE as u64 * 60 * 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+/// A range of memory an instruction reads or writes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryAccess {
+    pub access: Access,
+    pub start: u16,
+    pub len: u16,
+}
+
+/// The accesses a watchpoint stops on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Watch {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub start: u16,
+    pub len: u16,
+    pub watch: Watch,
+}
+
+impl Watchpoint {
+    fn matches(&self, access: &MemoryAccess) -> bool {
+        let kind = matches!(
+            (self.watch, access.access),
+            (Watch::ReadWrite, _) | (Watch::Read, Access::Read) | (Watch::Write, Access::Write)
+        );
+        let (start, end) = (self.start as u32, self.start as u32 + self.len as u32);
+        let (access_start, access_end) =
+            (access.start as u32, access.start as u32 + access.len as u32);
+        kind && access_start < end && start < access_end
+    }
+}
+
+/// A condition on the registers, checked after every cycle
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Condition {
+    /// The register was changed by the last instruction
+    RegisterChanged(Register),
+    /// The register was set to the value by the last instruction
+    RegisterEquals(Register, u8),
+    /// I was set to the value by the last instruction
+    IEquals(u16),
+}
+
+/// Why the debugger handed back control
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    /// The step or the `run_until` target was reached
+    Finished,
+    /// The program counter reached a breakpoint, the instruction there hasn't run yet
+    Breakpoint(u16),
+    /// The last instruction made an access a watchpoint covers
+    Watchpoint(MemoryAccess),
+    Condition(Condition),
+    /// The machine can't go on until a key is pressed
+    WaitingForKey,
+    Halted,
+    /// The run went on for the most cycles a run may take, see
+    /// `Debugger::set_max_cycles`
+    CycleLimit,
+    Error(Chip8Error),
+}
+
+impl fmt::Display for StopReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StopReason::Finished => write!(f, "finished"),
+            StopReason::Breakpoint(address) => write!(f, "breakpoint at {:#05X}", address),
+            StopReason::Watchpoint(access) => write!(
+                f,
+                "{} of {} bytes at {:#05X}",
+                match access.access {
+                    Access::Read => "read",
+                    Access::Write => "write",
+                },
+                access.len,
+                access.start
+            ),
+            StopReason::Condition(Condition::RegisterChanged(reg)) => {
+                write!(f, "V{:X} changed", reg)
+            }
+            StopReason::Condition(Condition::RegisterEquals(reg, value)) => {
+                write!(f, "V{:X} == {:#04X}", reg, value)
+            }
+            StopReason::Condition(Condition::IEquals(value)) => write!(f, "I == {:#05X}", value),
+            StopReason::WaitingForKey => write!(f, "waiting for a key"),
+            StopReason::Halted => write!(f, "halted"),
+            StopReason::CycleLimit => write!(f, "cycle limit reached"),
+            StopReason::Error(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+/// Where `run_until` stops, unless something else stops it first
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Until {
+    /// After this many more cycles
+    Cycles(u64),
+    /// At the next timer tick
+    Frame,
+    /// Once the program waits for a key
+    KeyWait,
+    /// Only on breakpoints, watchpoints, conditions, errors or when halted
+    Stopped,
+}
+
+/// All slots for breakpoints, watchpoints or conditions are taken
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NoFreeSlot;
+
+impl fmt::Display for NoFreeSlot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("no free slot left")
+    }
+}
+
+pub struct Debugger {
+    breakpoints: [Option<u16>; MAX_BREAKPOINTS],
+    watchpoints: [Option<Watchpoint>; MAX_WATCHPOINTS],
+    conditions: [Option<Condition>; MAX_CONDITIONS],
+    speed: Speed,
+    max_cycles: u64,
+    /// Progress towards the next timer tick, in units of `TIMER_RATE` for
+    /// `InstructionsPerSecond` and of cycles for `InstructionsPerFrame`
+    frame_progress: u32,
+}
+
+impl Debugger {
+    pub fn new() -> Debugger {
+        Debugger::with_speed(Speed::default())
+    }
+
+    /// Ticks the timers as often as a `Runner` with the same speed would
+    pub fn with_speed(speed: Speed) -> Debugger {
+        Debugger {
+            breakpoints: [None; MAX_BREAKPOINTS],
+            watchpoints: [None; MAX_WATCHPOINTS],
+            conditions: [None; MAX_CONDITIONS],
+            speed,
+            max_cycles: DEFAULT_MAX_CYCLES,
+            frame_progress: 0,
+        }
+    }
+
+    pub fn speed(&self) -> Speed {
+        self.speed
+    }
+
+    pub fn set_speed(&mut self, speed: Speed) {
+        self.speed = speed;
+        self.frame_progress = 0;
+    }
+
+    pub fn max_cycles(&self) -> u64 {
+        self.max_cycles
+    }
+
+    /// The most cycles a run may take before it stops with
+    /// `StopReason::CycleLimit`, so running a program that never stops
+    /// doesn't hang. `Until::Cycles` isn't limited.
+    pub fn set_max_cycles(&mut self, max_cycles: u64) {
+        self.max_cycles = max_cycles;
+    }
+
+    pub fn add_breakpoint(&mut self, address: u16) -> Result<(), NoFreeSlot> {
+        insert(&mut self.breakpoints, address)
+    }
+
+    pub fn remove_breakpoint(&mut self, address: u16) -> bool {
+        remove(&mut self.breakpoints, address)
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = u16> + '_ {
+        self.breakpoints.iter().flatten().copied()
+    }
+
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) -> Result<(), NoFreeSlot> {
+        insert(&mut self.watchpoints, watchpoint)
+    }
+
+    pub fn remove_watchpoint(&mut self, watchpoint: Watchpoint) -> bool {
+        remove(&mut self.watchpoints, watchpoint)
+    }
+
+    pub fn watchpoints(&self) -> impl Iterator<Item = Watchpoint> + '_ {
+        self.watchpoints.iter().flatten().copied()
+    }
+
+    pub fn add_condition(&mut self, condition: Condition) -> Result<(), NoFreeSlot> {
+        insert(&mut self.conditions, condition)
+    }
+
+    pub fn remove_condition(&mut self, condition: Condition) -> bool {
+        remove(&mut self.conditions, condition)
+    }
+
+    pub fn conditions(&self) -> impl Iterator<Item = Condition> + '_ {
+        self.conditions.iter().flatten().copied()
+    }
+
+    /// Removes all breakpoints, watchpoints and conditions
+    pub fn clear(&mut self) {
+        let max_cycles = self.max_cycles;
+        *self = Debugger {
+            frame_progress: self.frame_progress,
+            ..Debugger::with_speed(self.speed)
+        };
+        self.max_cycles = max_cycles;
+    }
+
+    /// Runs a single instruction, stepping into calls
+    pub fn step<R: Random>(&mut self, chip8: &mut Chip8<R>) -> StopReason {
+        self.run(chip8, Some(1), |_, _| true)
+    }
+
+    /// Runs a single instruction, or a whole subroutine if it's a `Call`
+    pub fn step_over<R: Random>(&mut self, chip8: &mut Chip8<R>) -> StopReason {
+        match chip8.current_instruction() {
+            Ok(Instruction::Call(_)) => {
+                let depth = chip8.stack().len();
+                self.run(chip8, None, |chip8, _| chip8.stack().len() <= depth)
+            }
+            _ => self.step(chip8),
+        }
+    }
+
+    /// Runs until the current subroutine returns. Outside of a subroutine
+    /// only breakpoints and the like, or the cycle limit, stop it.
+    pub fn step_out<R: Random>(&mut self, chip8: &mut Chip8<R>) -> StopReason {
+        let depth = chip8.stack().len();
+        self.run(chip8, None, |chip8, _| chip8.stack().len() < depth)
+    }
+
+    pub fn run_until<R: Random>(&mut self, chip8: &mut Chip8<R>, until: Until) -> StopReason {
+        match until {
+            Until::Cycles(cycles) => {
+                if cycles == 0 {
+                    return StopReason::Finished;
+                }
+                self.run(chip8, Some(cycles), |_, _| false)
+            }
+            Until::Frame => self.run(chip8, None, |_, frame_ended| frame_ended),
+            Until::KeyWait => self.run(chip8, None, |chip8, _| chip8.is_waiting_for_key()),
+            Until::Stopped => self.run(chip8, None, |_, _| false),
+        }
+    }
+
+    /// Runs until `predicate` returns true after a cycle
+    pub fn run_until_fn<R, F>(&mut self, chip8: &mut Chip8<R>, mut predicate: F) -> StopReason
+    where
+        R: Random,
+        F: FnMut(&Chip8<R>) -> bool,
+    {
+        self.run(chip8, None, |chip8, _| predicate(chip8))
+    }
+
+    /// Runs cycles until `done` returns true, which gets told whether the
+    /// cycle ended a frame. Running `cycles` cycles finishes the run too,
+    /// without them it stops with `CycleLimit` after `max_cycles`.
+    fn run<R, F>(&mut self, chip8: &mut Chip8<R>, cycles: Option<u64>, mut done: F) -> StopReason
+    where
+        R: Random,
+        F: FnMut(&Chip8<R>, bool) -> bool,
+    {
+        let mut first = true;
+        for _ in 0..cycles.unwrap_or(self.max_cycles) {
+            // Don't stop on the breakpoint the last run stopped on
+            let pc = chip8.program_counter();
+            if !first && self.breakpoints().any(|address| address == pc) {
+                return StopReason::Breakpoint(pc);
+            }
+            first = false;
+
+            let access = chip8
+                .current_instruction()
+                .ok()
+                .and_then(|instruction| chip8.memory_access(&instruction));
+            let regs = *chip8.regs();
+            let i_reg = chip8.i_reg();
+            let ran = match chip8.run_cycle() {
+                Ok(StepOutcome::Ran) => true,
+                Ok(StepOutcome::WaitingForVBlank) => false,
+                Ok(StepOutcome::WaitingForKey) => return StopReason::WaitingForKey,
+                Ok(StepOutcome::Halted) => return StopReason::Halted,
+                Err(error) => return StopReason::Error(error),
+            };
+            let frame_ended = self.advance_timers(chip8);
+
+            // A draw waiting for the next frame didn't read anything yet
+            if let (true, Some(access)) = (ran, access) {
+                if self
+                    .watchpoints()
+                    .any(|watchpoint| watchpoint.matches(&access))
+                {
+                    return StopReason::Watchpoint(access);
+                }
+            }
+            let triggered = self.conditions().find(|condition| match *condition {
+                Condition::RegisterChanged(reg) => regs[reg as usize] != chip8.regs()[reg as usize],
+                Condition::RegisterEquals(reg, value) => {
+                    regs[reg as usize] != value && chip8.regs()[reg as usize] == value
+                }
+                Condition::IEquals(value) => i_reg != value && chip8.i_reg() == value,
+            });
+            if let Some(condition) = triggered {
+                return StopReason::Condition(condition);
+            }
+            if done(chip8, frame_ended) {
+                return StopReason::Finished;
+            }
+        }
+        match cycles {
+            Some(_) => StopReason::Finished,
+            None => StopReason::CycleLimit,
+        }
+    }
+
+    /// Ticks the timers as the speed says, returning whether they ticked
+    fn advance_timers<R: Random>(&mut self, chip8: &mut Chip8<R>) -> bool {
+        let (progress, period) = match self.speed {
+            Speed::InstructionsPerSecond(rate) => (TIMER_RATE, rate.max(1)),
+            Speed::InstructionsPerFrame(count) => (1, count.max(1)),
+        };
+        self.frame_progress += progress;
+        let mut ticked = false;
+        while self.frame_progress >= period {
+            self.frame_progress -= period;
+            chip8.timer_tick();
+            ticked = true;
+        }
+        ticked
+    }
+}
+
+impl Default for Debugger {
+    fn default() -> Debugger {
+        Debugger::new()
+    }
+}
+
+impl<RANDOM> Chip8<RANDOM>
+where
+    RANDOM: Random,
+{
+    /// The memory an instruction would read or write if it ran now, apart
+    /// from fetching it
+    pub fn memory_access(&self, instruction: &Instruction) -> Option<MemoryAccess> {
+        let (access, len) = match *instruction {
+            Instruction::Draw(_, _, n) => {
+                let planes = self.display.selected_planes().count_ones() as u16;
+                let rows = if n == 0 && self.mode >= Mode::SuperChip {
+                    32
+                } else {
+                    n as u16
+                };
+                (Access::Read, rows * planes)
+            }
+            Instruction::LoadAudio => (Access::Read, AUDIO_PATTERN_SIZE as u16),
+            Instruction::BCDRepresentation(_) => (Access::Write, 3),
+            Instruction::StoreRegisters(highest_reg) => (Access::Write, highest_reg as u16 + 1),
+            Instruction::LoadRegisters(highest_reg) => (Access::Read, highest_reg as u16 + 1),
+            Instruction::SaveRange(first, last) => {
+                (Access::Write, register_count(first, last) as u16)
+            }
+            Instruction::LoadRange(first, last) => {
+                (Access::Read, register_count(first, last) as u16)
+            }
+            _ => return None,
+        };
+        if len == 0 {
+            return None;
+        }
+        Some(MemoryAccess {
+            access,
+            start: self.i_reg,
+            len,
+        })
+    }
+}
+
+fn insert<T: Copy + PartialEq>(slots: &mut [Option<T>], value: T) -> Result<(), NoFreeSlot> {
+    if slots.contains(&Some(value)) {
+        return Ok(());
+    }
+    let slot = slots
+        .iter_mut()
+        .find(|slot| slot.is_none())
+        .ok_or(NoFreeSlot)?;
+    *slot = Some(value);
+    Ok(())
+}
+
+fn remove<T: Copy + PartialEq>(slots: &mut [Option<T>], value: T) -> bool {
+    match slots.iter_mut().find(|slot| **slot == Some(value)) {
+        Some(slot) => {
+            *slot = None;
+            true
+        }
+        None => false,
+    }
+}
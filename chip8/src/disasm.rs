@@ -0,0 +1,433 @@
+use core::convert::TryFrom;
+use core::fmt;
+
+use crate::instruction::{Address, Instruction, RawInstruction};
+use crate::{Mode, MEMORY_SIZE, PROGRAM_CODE_OFFSET};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Syntax {
+    /// The mnemonics from Cowgod's Chip-8 Technical Reference
+    Cowgod,
+    /// Octo assembly language
+    Octo,
+}
+
+/// Formats an instruction in the given syntax, see `Instruction::mnemonic`
+pub struct Mnemonic<'a> {
+    instruction: &'a Instruction,
+    syntax: Syntax,
+    labels: Option<&'a Labels>,
+}
+
+impl Instruction {
+    pub fn mnemonic(&self, syntax: Syntax) -> Mnemonic<'_> {
+        Mnemonic {
+            instruction: self,
+            syntax,
+            labels: None,
+        }
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.mnemonic(Syntax::Cowgod).fmt(f)
+    }
+}
+
+/// An address operand, written as a label if there is one for it
+struct Target<'a>(Address, Option<&'a Labels>);
+
+impl<'a> fmt::Display for Target<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.1 {
+            Some(labels) if labels.contains(self.0) => write!(f, "L{:03X}", self.0),
+            _ => write!(f, "0x{:03X}", self.0),
+        }
+    }
+}
+
+impl<'a> fmt::Display for Mnemonic<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let target = |addr| Target(addr, self.labels);
+        match self.syntax {
+            Syntax::Cowgod => match *self.instruction {
+                Instruction::ClearDisplay => write!(f, "CLS"),
+                Instruction::Return => write!(f, "RET"),
+                Instruction::ScrollDown(n) => write!(f, "SCD {}", n),
+                Instruction::ScrollUp(n) => write!(f, "SCU {}", n),
+                Instruction::ScrollRight => write!(f, "SCR"),
+                Instruction::ScrollLeft => write!(f, "SCL"),
+                Instruction::Exit => write!(f, "EXIT"),
+                Instruction::LowRes => write!(f, "LOW"),
+                Instruction::HighRes => write!(f, "HIGH"),
+                Instruction::Jump(addr) => write!(f, "JP {}", target(addr)),
+                Instruction::Call(addr) => write!(f, "CALL {}", target(addr)),
+                Instruction::SkipIfEqualsByte(x, kk) => write!(f, "SE V{:X}, 0x{:02X}", x, kk),
+                Instruction::SkipIfNotEqualsByte(x, kk) => {
+                    write!(f, "SNE V{:X}, 0x{:02X}", x, kk)
+                }
+                Instruction::SkipIfEqual(x, y) => write!(f, "SE V{:X}, V{:X}", x, y),
+                Instruction::SaveRange(x, y) => write!(f, "SAVE V{:X} - V{:X}", x, y),
+                Instruction::LoadRange(x, y) => write!(f, "LOAD V{:X} - V{:X}", x, y),
+                Instruction::LoadByte(x, kk) => write!(f, "LD V{:X}, 0x{:02X}", x, kk),
+                Instruction::AddByte(x, kk) => write!(f, "ADD V{:X}, 0x{:02X}", x, kk),
+                Instruction::Move(x, y) => write!(f, "LD V{:X}, V{:X}", x, y),
+                Instruction::Or(x, y) => write!(f, "OR V{:X}, V{:X}", x, y),
+                Instruction::And(x, y) => write!(f, "AND V{:X}, V{:X}", x, y),
+                Instruction::Xor(x, y) => write!(f, "XOR V{:X}, V{:X}", x, y),
+                Instruction::Add(x, y) => write!(f, "ADD V{:X}, V{:X}", x, y),
+                Instruction::Sub(x, y) => write!(f, "SUB V{:X}, V{:X}", x, y),
+                Instruction::ShiftRight(x, y) => write!(f, "SHR V{:X}, V{:X}", x, y),
+                Instruction::ReverseSub(x, y) => write!(f, "SUBN V{:X}, V{:X}", x, y),
+                Instruction::ShiftLeft(x, y) => write!(f, "SHL V{:X}, V{:X}", x, y),
+                Instruction::SkipIfNotEqual(x, y) => write!(f, "SNE V{:X}, V{:X}", x, y),
+                Instruction::LoadI(addr) | Instruction::LoadLongI(addr) => {
+                    write!(f, "LD I, {}", target(addr))
+                }
+                Instruction::JumpPlusZero(addr) => write!(f, "JP V0, {}", target(addr)),
+                Instruction::Random(x, kk) => write!(f, "RND V{:X}, 0x{:02X}", x, kk),
+                Instruction::Draw(x, y, n) => write!(f, "DRW V{:X}, V{:X}, {}", x, y, n),
+                Instruction::SkipIfPressed(x) => write!(f, "SKP V{:X}", x),
+                Instruction::SkipIfNotPressed(x) => write!(f, "SKNP V{:X}", x),
+                Instruction::LoadDelayTimer(x) => write!(f, "LD V{:X}, DT", x),
+                Instruction::WaitForKeyPress(x) => write!(f, "LD V{:X}, K", x),
+                Instruction::SelectPlanes(n) => write!(f, "PLANE {}", n),
+                Instruction::LoadAudio => write!(f, "AUDIO"),
+                Instruction::SetPitch(x) => write!(f, "PITCH V{:X}", x),
+                Instruction::SetDelayTimer(x) => write!(f, "LD DT, V{:X}", x),
+                Instruction::SetSoundTimer(x) => write!(f, "LD ST, V{:X}", x),
+                Instruction::AddToI(x) => write!(f, "ADD I, V{:X}", x),
+                Instruction::LoadSprite(x) => write!(f, "LD F, V{:X}", x),
+                Instruction::LoadBigSprite(x) => write!(f, "LD HF, V{:X}", x),
+                Instruction::BCDRepresentation(x) => write!(f, "LD B, V{:X}", x),
+                Instruction::StoreRegisters(x) => write!(f, "LD [I], V{:X}", x),
+                Instruction::LoadRegisters(x) => write!(f, "LD V{:X}, [I]", x),
+                Instruction::StoreFlags(x) => write!(f, "LD R, V{:X}", x),
+                Instruction::LoadFlags(x) => write!(f, "LD V{:X}, R", x),
+            },
+            // Octo has no skips, so they're written as the conditional that
+            // executes the next instruction
+            Syntax::Octo => match *self.instruction {
+                Instruction::ClearDisplay => write!(f, "clear"),
+                Instruction::Return => write!(f, "return"),
+                Instruction::ScrollDown(n) => write!(f, "scroll-down {}", n),
+                Instruction::ScrollUp(n) => write!(f, "scroll-up {}", n),
+                Instruction::ScrollRight => write!(f, "scroll-right"),
+                Instruction::ScrollLeft => write!(f, "scroll-left"),
+                Instruction::Exit => write!(f, "exit"),
+                Instruction::LowRes => write!(f, "lores"),
+                Instruction::HighRes => write!(f, "hires"),
+                Instruction::Jump(addr) => write!(f, "jump {}", target(addr)),
+                Instruction::Call(addr) => write!(f, ":call {}", target(addr)),
+                Instruction::SkipIfEqualsByte(x, kk) => {
+                    write!(f, "if v{:x} != 0x{:02X} then", x, kk)
+                }
+                Instruction::SkipIfNotEqualsByte(x, kk) => {
+                    write!(f, "if v{:x} == 0x{:02X} then", x, kk)
+                }
+                Instruction::SkipIfEqual(x, y) => write!(f, "if v{:x} != v{:x} then", x, y),
+                Instruction::SaveRange(x, y) => write!(f, "save v{:x} - v{:x}", x, y),
+                Instruction::LoadRange(x, y) => write!(f, "load v{:x} - v{:x}", x, y),
+                Instruction::LoadByte(x, kk) => write!(f, "v{:x} := 0x{:02X}", x, kk),
+                Instruction::AddByte(x, kk) => write!(f, "v{:x} += 0x{:02X}", x, kk),
+                Instruction::Move(x, y) => write!(f, "v{:x} := v{:x}", x, y),
+                Instruction::Or(x, y) => write!(f, "v{:x} |= v{:x}", x, y),
+                Instruction::And(x, y) => write!(f, "v{:x} &= v{:x}", x, y),
+                Instruction::Xor(x, y) => write!(f, "v{:x} ^= v{:x}", x, y),
+                Instruction::Add(x, y) => write!(f, "v{:x} += v{:x}", x, y),
+                Instruction::Sub(x, y) => write!(f, "v{:x} -= v{:x}", x, y),
+                Instruction::ShiftRight(x, y) => write!(f, "v{:x} >>= v{:x}", x, y),
+                Instruction::ReverseSub(x, y) => write!(f, "v{:x} =- v{:x}", x, y),
+                Instruction::ShiftLeft(x, y) => write!(f, "v{:x} <<= v{:x}", x, y),
+                Instruction::SkipIfNotEqual(x, y) => write!(f, "if v{:x} == v{:x} then", x, y),
+                Instruction::LoadI(addr) => write!(f, "i := {}", target(addr)),
+                Instruction::LoadLongI(addr) => write!(f, "i := long {}", target(addr)),
+                Instruction::JumpPlusZero(addr) => write!(f, "jump0 {}", target(addr)),
+                Instruction::Random(x, kk) => write!(f, "v{:x} := random 0x{:02X}", x, kk),
+                Instruction::Draw(x, y, n) => write!(f, "sprite v{:x} v{:x} {}", x, y, n),
+                Instruction::SkipIfPressed(x) => write!(f, "if v{:x} -key then", x),
+                Instruction::SkipIfNotPressed(x) => write!(f, "if v{:x} key then", x),
+                Instruction::LoadDelayTimer(x) => write!(f, "v{:x} := delay", x),
+                Instruction::WaitForKeyPress(x) => write!(f, "v{:x} := key", x),
+                Instruction::SelectPlanes(n) => write!(f, "plane {}", n),
+                Instruction::LoadAudio => write!(f, "audio"),
+                Instruction::SetPitch(x) => write!(f, "pitch := v{:x}", x),
+                Instruction::SetDelayTimer(x) => write!(f, "delay := v{:x}", x),
+                Instruction::SetSoundTimer(x) => write!(f, "buzzer := v{:x}", x),
+                Instruction::AddToI(x) => write!(f, "i += v{:x}", x),
+                Instruction::LoadSprite(x) => write!(f, "i := hex v{:x}", x),
+                Instruction::LoadBigSprite(x) => write!(f, "i := bighex v{:x}", x),
+                Instruction::BCDRepresentation(x) => write!(f, "bcd v{:x}", x),
+                Instruction::StoreRegisters(x) => write!(f, "save v{:x}", x),
+                Instruction::LoadRegisters(x) => write!(f, "load v{:x}", x),
+                Instruction::StoreFlags(x) => write!(f, "saveflags v{:x}", x),
+                Instruction::LoadFlags(x) => write!(f, "loadflags v{:x}", x),
+            },
+        }
+    }
+}
+
+const SET_WORDS: usize = MEMORY_SIZE / 32;
+
+/// Bitset of addresses
+struct AddressSet([u32; SET_WORDS]);
+
+impl AddressSet {
+    fn new() -> AddressSet {
+        AddressSet([0; SET_WORDS])
+    }
+
+    fn contains(&self, address: Address) -> bool {
+        let address = address as usize;
+        address < MEMORY_SIZE && self.0[address / 32] & (1 << (address % 32)) != 0
+    }
+
+    /// Adds an address, returning whether it wasn't in the set yet
+    fn insert(&mut self, address: Address) -> bool {
+        let address = address as usize;
+        if address >= MEMORY_SIZE || self.0[address / 32] & (1 << (address % 32)) != 0 {
+            return false;
+        }
+        self.0[address / 32] |= 1 << (address % 32);
+        true
+    }
+}
+
+type Labels = AddressSet;
+
+/// Walks a ROM image loaded at 0x200, producing an annotated listing.
+///
+/// Code reachable from 0x200 is found by following jumps, calls and skips
+/// first, so text or sprites in front of it can't shift the alignment. The
+/// rest is decoded word by word, with words that can't be decoded in the
+/// given mode listed as data bytes. `Jump`, `Call` and `LoadI` targets at
+/// the start of a line get labels.
+pub struct Disassembler<'a> {
+    rom: &'a [u8],
+    mode: Mode,
+    syntax: Syntax,
+    code: AddressSet,
+    labels: Labels,
+}
+
+impl<'a> Disassembler<'a> {
+    pub fn new(rom: &'a [u8], mode: Mode, syntax: Syntax) -> Disassembler<'a> {
+        let mut disassembler = Disassembler {
+            rom,
+            mode,
+            syntax,
+            code: AddressSet::new(),
+            labels: AddressSet::new(),
+        };
+        disassembler.trace_code();
+
+        // Targets pointing into the middle of a line are left as plain addresses
+        let mut line_starts = AddressSet::new();
+        for line in disassembler.lines() {
+            line_starts.insert(line.address);
+        }
+        let mut labels = AddressSet::new();
+        for line in disassembler.lines() {
+            if let LineKind::Instruction(
+                Instruction::Jump(addr)
+                | Instruction::Call(addr)
+                | Instruction::LoadI(addr)
+                | Instruction::LoadLongI(addr),
+            ) = line.kind
+            {
+                if line_starts.contains(addr) {
+                    labels.insert(addr);
+                }
+            }
+        }
+        disassembler.labels = labels;
+        disassembler
+    }
+
+    /// Whether the instruction at `address` is reachable from the entry point
+    pub fn is_reachable(&self, address: Address) -> bool {
+        self.code.contains(address)
+    }
+
+    pub fn lines(&self) -> Lines<'_> {
+        Lines {
+            disassembler: self,
+            offset: 0,
+        }
+    }
+
+    /// Marks the instructions reachable from the entry point, sweeping over
+    /// the ROM until no more are found
+    fn trace_code(&mut self) {
+        self.code.insert(PROGRAM_CODE_OFFSET as Address);
+        let mut found_code = true;
+        while found_code {
+            found_code = false;
+            for offset in 0..self.rom.len() {
+                let address = (PROGRAM_CODE_OFFSET + offset) as Address;
+                if !self.code.contains(address) {
+                    continue;
+                }
+                let (instruction, size) = match self.decode(offset) {
+                    Some(decoded) => decoded,
+                    None => continue,
+                };
+                // Worked out past the end of memory, where they're outside the ROM
+                let next = address as usize + size;
+                let skip = next + self.decode(offset + size).map_or(2, |(_, size)| size);
+                let successors = match instruction {
+                    Instruction::Jump(addr) => [Some(addr as usize), None],
+                    Instruction::Call(addr) => [Some(addr as usize), Some(next)],
+                    Instruction::SkipIfEqualsByte(..)
+                    | Instruction::SkipIfNotEqualsByte(..)
+                    | Instruction::SkipIfEqual(..)
+                    | Instruction::SkipIfNotEqual(..)
+                    | Instruction::SkipIfPressed(_)
+                    | Instruction::SkipIfNotPressed(_) => [Some(next), Some(skip)],
+                    // The target of jump0 isn't known without running the program
+                    Instruction::Return | Instruction::Exit | Instruction::JumpPlusZero(_) => {
+                        [None, None]
+                    }
+                    _ => [Some(next), None],
+                };
+                for &successor in successors.iter().flatten() {
+                    let in_rom = (successor.checked_sub(PROGRAM_CODE_OFFSET))
+                        .is_some_and(|offset| offset < self.rom.len());
+                    if in_rom && self.code.insert(successor as Address) {
+                        found_code = true;
+                    }
+                }
+            }
+        }
+    }
+
+    fn decode(&self, offset: usize) -> Option<(Instruction, usize)> {
+        let word = |offset: usize| {
+            let bytes = self.rom.get(offset..offset + 2)?;
+            Some((bytes[0] as u16) << 8 | bytes[1] as u16)
+        };
+        let opcode = word(offset)?;
+        let raw_instruction = match RawInstruction::new(opcode) {
+            raw if raw.is_long() && self.mode >= Mode::XoChip => {
+                RawInstruction::with_operand(opcode, word(offset + 2)?)
+            }
+            raw => raw,
+        };
+        let instruction = raw_instruction
+            .to_instruction()
+            .filter(|instruction| instruction.mode() <= self.mode)?;
+        let size = instruction.size() as usize;
+        Some((instruction, size))
+    }
+}
+
+pub struct Lines<'a> {
+    disassembler: &'a Disassembler<'a>,
+    offset: usize,
+}
+
+impl<'a> Iterator for Lines<'a> {
+    type Item = Line<'a>;
+
+    fn next(&mut self) -> Option<Line<'a>> {
+        let disassembler = self.disassembler;
+        let rom = disassembler.rom;
+        if self.offset >= rom.len() {
+            return None;
+        }
+        let address = PROGRAM_CODE_OFFSET + self.offset;
+        // Nothing past the end of memory is code
+        let is_code = |address: usize| {
+            Address::try_from(address).is_ok_and(|address| disassembler.code.contains(address))
+        };
+        let decoded = if is_code(address) {
+            disassembler.decode(self.offset)
+        } else if is_code(address + 1) {
+            None
+        } else {
+            // Outside of the traced code, as long as it doesn't overlap it
+            disassembler
+                .decode(self.offset)
+                .filter(|&(_, size)| size == 2 || !is_code(address + 2) && !is_code(address + 3))
+        };
+        let (kind, size) = match decoded {
+            Some((instruction, size)) => (LineKind::Instruction(instruction), size),
+            None if is_code(address + 1) => (LineKind::Data, 1),
+            None => (LineKind::Data, 2.min(rom.len() - self.offset)),
+        };
+        let line = Line {
+            address: address as Address,
+            bytes: &rom[self.offset..self.offset + size],
+            kind,
+            disassembler,
+        };
+        self.offset += size;
+        Some(line)
+    }
+}
+
+pub enum LineKind {
+    Instruction(Instruction),
+    /// Bytes that don't decode to an instruction
+    Data,
+}
+
+/// A line of the listing, formatted with its label, address, raw bytes and mnemonic
+pub struct Line<'a> {
+    pub address: Address,
+    pub bytes: &'a [u8],
+    pub kind: LineKind,
+    disassembler: &'a Disassembler<'a>,
+}
+
+impl<'a> fmt::Display for Line<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let syntax = self.disassembler.syntax;
+        if self.disassembler.labels.contains(self.address) {
+            match syntax {
+                Syntax::Cowgod => writeln!(f, "L{:03X}:", self.address)?,
+                Syntax::Octo => writeln!(f, ": L{:03X}", self.address)?,
+            }
+        }
+        write!(f, "{:03X}  ", self.address)?;
+        // Room for the four bytes of a long instruction
+        for (index, byte) in self.bytes.iter().enumerate() {
+            if index > 0 && index % 2 == 0 {
+                write!(f, " ")?;
+            }
+            write!(f, "{:02X}", byte)?;
+        }
+        for index in self.bytes.len()..4 {
+            if index % 2 == 0 {
+                write!(f, " ")?;
+            }
+            write!(f, "  ")?;
+        }
+        write!(f, "  ")?;
+        match self.kind {
+            LineKind::Instruction(ref instruction) => Mnemonic {
+                instruction,
+                syntax,
+                labels: Some(&self.disassembler.labels),
+            }
+            .fmt(f),
+            LineKind::Data => {
+                let prefix = match syntax {
+                    Syntax::Cowgod => "DB ",
+                    Syntax::Octo => "",
+                };
+                write!(f, "{}", prefix)?;
+                for (index, byte) in self.bytes.iter().enumerate() {
+                    let separator = match (syntax, index) {
+                        (_, 0) => "",
+                        (Syntax::Cowgod, _) => ", ",
+                        (Syntax::Octo, _) => " ",
+                    };
+                    write!(f, "{}0x{:02X}", separator, byte)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
@@ -1,5 +1,30 @@
-pub const WIDTH: usize = 64;
-pub const HEIGHT: usize = 32;
+use crate::state::{Reader, Writer};
+
+/// Size of the buffer, which has the SUPER-CHIP high resolution with the
+/// `hires` feature
+#[cfg(feature = "hires")]
+pub const WIDTH: usize = 128;
+#[cfg(feature = "hires")]
+pub const HEIGHT: usize = 64;
+#[cfg(not(feature = "hires"))]
+pub const WIDTH: usize = LORES_WIDTH;
+#[cfg(not(feature = "hires"))]
+pub const HEIGHT: usize = LORES_HEIGHT;
+/// Size of the original CHIP-8 display, each pixel covers 2x2 buffer pixels
+/// with the `hires` feature
+pub const LORES_WIDTH: usize = 64;
+pub const LORES_HEIGHT: usize = 32;
+/// XO-CHIP bitplanes, every buffer pixel holds one bit per plane. There's
+/// only one without the `xo-chip` feature.
+#[cfg(feature = "xo-chip")]
+pub const PLANES: u8 = 2;
+#[cfg(not(feature = "xo-chip"))]
+pub const PLANES: u8 = 1;
+/// A row of the buffer, wide enough for `WIDTH` pixels
+#[cfg(feature = "hires")]
+pub type Row = u128;
+#[cfg(not(feature = "hires"))]
+pub type Row = u64;
 pub const SPRITES: [u8; 80] = [
     0xF0, 0x90, 0x90, 0x90, 0xF0, /* 0 */
     0x20, 0x60, 0x20, 0x20, 0x70, /* 1 */
@@ -18,48 +43,438 @@ pub const SPRITES: [u8; 80] = [
     0xF0, 0x80, 0xF0, 0x80, 0xF0, /* e */
     0xF0, 0x80, 0xF0, 0x80, 0x80,
 ]; // f
+/// The SUPER-CHIP 8x10 font, loaded right behind `SPRITES`
+pub const BIG_SPRITES: [u8; 160] = [
+    0xFF, 0xFF, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, /* 0 */
+    0x18, 0x78, 0x78, 0x18, 0x18, 0x18, 0x18, 0x18, 0xFF, 0xFF, /* 1 */
+    0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, /* 2 */
+    0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, /* 3 */
+    0xC3, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0x03, 0x03, 0x03, 0x03, /* 4 */
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, /* 5 */
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, /* 6 */
+    0xFF, 0xFF, 0x03, 0x03, 0x06, 0x0C, 0x18, 0x18, 0x18, 0x18, /* 7 */
+    0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, /* 8 */
+    0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, /* 9 */
+    0x7E, 0xFF, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xC3, /* a */
+    0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, /* b */
+    0x3C, 0xFF, 0xC3, 0xC0, 0xC0, 0xC0, 0xC0, 0xC3, 0xFF, 0x3C, /* c */
+    0xFC, 0xFE, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFE, 0xFC, /* d */
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, /* e */
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xC0, 0xC0, /* f */
+];
+
+/// The screen at the buffer resolution, packed into one bit per pixel and
+/// plane. Each row of a plane is a `Row` with the leftmost pixel in the
+/// highest bit, so sprites are drawn a word at a time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Buffer {
+    planes: [[Row; HEIGHT]; PLANES as usize],
+}
+
+impl Buffer {
+    pub const fn new() -> Buffer {
+        Buffer {
+            planes: [[0; HEIGHT]; PLANES as usize],
+        }
+    }
+
+    /// Bitmask of the planes a pixel is set in, which makes it an index
+    /// into a four colour palette
+    pub fn pixel(&self, x: usize, y: usize) -> u8 {
+        let bit = WIDTH - 1 - x;
+        (0..PLANES as usize).fold(0, |pixel, plane| {
+            pixel | (((self.planes[plane][y] >> bit) & 1) as u8) << plane
+        })
+    }
+
+    /// A row of one plane, the leftmost pixel in the highest bit
+    pub fn row(&self, plane: usize, y: usize) -> Row {
+        self.planes[plane][y]
+    }
+
+    /// The pixels of a row from left to right, as `pixel` returns them
+    pub fn row_pixels(&self, y: usize) -> impl Iterator<Item = u8> + '_ {
+        (0..WIDTH).map(move |x| self.pixel(x, y))
+    }
+}
+
+impl Default for Buffer {
+    fn default() -> Buffer {
+        Buffer::new()
+    }
+}
+
+/// A rectangle of buffer pixels
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Region {
+    /// The whole buffer
+    pub const FULL: Region = Region {
+        x: 0,
+        y: 0,
+        width: WIDTH,
+        height: HEIGHT,
+    };
+
+    /// The smallest region covering both
+    pub fn union(self, other: Region) -> Region {
+        let x = self.x.min(other.x);
+        let y = self.y.min(other.y);
+        Region {
+            x,
+            y,
+            width: (self.x + self.width).max(other.x + other.width) - x,
+            height: (self.y + self.height).max(other.y + other.height) - y,
+        }
+    }
+
+    /// The part inside the buffer, `None` if there's none
+    pub fn clamp(self) -> Option<Region> {
+        let right = self.x.saturating_add(self.width).min(WIDTH);
+        let bottom = self.y.saturating_add(self.height).min(HEIGHT);
+        if self.x >= right || self.y >= bottom {
+            return None;
+        }
+        Some(Region {
+            x: self.x,
+            y: self.y,
+            width: right - self.x,
+            height: bottom - self.y,
+        })
+    }
 
-pub type Buffer = [[bool; WIDTH]; HEIGHT];
+    /// Grown by `by` pixels on every side, without leaving the buffer
+    pub fn grow(self, by: usize) -> Region {
+        let x = self.x.saturating_sub(by);
+        let y = self.y.saturating_sub(by);
+        Region {
+            x,
+            y,
+            width: (self.x + self.width + by).min(WIDTH) - x,
+            height: (self.y + self.height + by).min(HEIGHT) - y,
+        }
+    }
+}
 
 pub struct Display {
     buffer: Buffer,
+    hires: bool,
+    /// Bitmask of the planes drawing, clearing and scrolling operate on
+    selected_planes: u8,
+    /// What changed since the frontend last took the changes
+    dirty: Option<Region>,
+    /// One bit per buffer row that changed
+    dirty_rows: u64,
 }
 
 impl Display {
+    /// Starts out dirty, as nothing has been drawn yet
     pub fn new() -> Display {
         Display {
-            buffer: [[false; WIDTH]; HEIGHT],
+            buffer: Buffer::new(),
+            hires: false,
+            selected_planes: 1,
+            dirty: Some(Region::FULL),
+            dirty_rows: !0,
         }
     }
 
-    pub fn draw(&mut self, starting_x: u8, starting_y: u8, memory: &[u8]) -> bool {
-        let mut pixel_turned_off = false;
+    pub fn is_hires(&self) -> bool {
+        self.hires
+    }
 
-        for (byte_number, block) in memory.iter().enumerate() {
-            let y = (starting_y as usize + byte_number) % HEIGHT;
+    /// Switches between the 64x32 and 128x64 resolution, clearing all planes.
+    /// Without the `hires` feature both are 64x32.
+    pub fn set_hires(&mut self, hires: bool) {
+        self.hires = hires;
+        self.buffer = Buffer::new();
+        self.mark_dirty(Region::FULL);
+    }
 
-            for bit_number in 0..8 {
-                let x = (starting_x as usize + bit_number) % WIDTH;
-                let current_pixel = self.buffer[y][x] as u8;
+    pub fn selected_planes(&self) -> u8 {
+        self.selected_planes
+    }
 
-                let current_bit = (block >> (7 - bit_number)) & 1;
-                let new_pixel = current_bit ^ current_pixel;
+    pub fn select_planes(&mut self, planes: u8) {
+        self.selected_planes = planes & ((1 << PLANES) - 1);
+    }
 
-                self.buffer[y][x] = new_pixel != 0;
+    /// Width of the screen in the current resolution
+    pub fn width(&self) -> usize {
+        if self.hires {
+            WIDTH
+        } else {
+            LORES_WIDTH
+        }
+    }
+
+    /// Height of the screen in the current resolution
+    pub fn height(&self) -> usize {
+        if self.hires {
+            HEIGHT
+        } else {
+            LORES_HEIGHT
+        }
+    }
 
-                if current_pixel == 1 && new_pixel == 0 {
-                    pixel_turned_off = true;
-                }
+    /// XORs an 8 pixel wide sprite onto the selected planes, returning the
+    /// number of rows in which a pixel was turned off.
+    ///
+    /// `memory` holds one sprite of equal height for every selected plane.
+    /// The starting position always wraps around, the parts of the sprite that
+    /// go past an edge are either clipped or wrapped to the other side.
+    pub fn draw(&mut self, starting_x: u8, starting_y: u8, memory: &[u8], clip: bool) -> u8 {
+        let mut colliding_rows = 0;
+        for (plane, sprite) in self.plane_sprites(memory) {
+            let rows = sprite.iter().map(|&block| (block as u16) << 8);
+            colliding_rows += self.draw_rows(starting_x, starting_y, rows, clip, plane);
+        }
+        colliding_rows
+    }
+
+    /// Like `draw`, but for the 16x16 SUPER-CHIP sprites stored as 32 bytes
+    pub fn draw_wide(&mut self, starting_x: u8, starting_y: u8, memory: &[u8], clip: bool) -> u8 {
+        let mut colliding_rows = 0;
+        for (plane, sprite) in self.plane_sprites(memory) {
+            let rows = sprite
+                .chunks(2)
+                .map(|row| (row[0] as u16) << 8 | row.get(1).copied().unwrap_or(0) as u16);
+            colliding_rows += self.draw_rows(starting_x, starting_y, rows, clip, plane);
+        }
+        colliding_rows
+    }
+
+    /// Splits sprite data into the parts for each selected plane
+    fn plane_sprites<'a>(&self, memory: &'a [u8]) -> impl Iterator<Item = (u8, &'a [u8])> {
+        let selected_planes = self.selected_planes;
+        let plane_count = selected_planes.count_ones() as usize;
+        let sprite_len = memory.len() / plane_count.max(1);
+        (0..PLANES)
+            .map(|plane| 1 << plane)
+            .filter(move |plane| selected_planes & plane != 0)
+            .zip(memory.chunks(sprite_len.max(1)))
+    }
+
+    fn draw_rows<I>(&mut self, starting_x: u8, starting_y: u8, rows: I, clip: bool, plane: u8) -> u8
+    where
+        I: Iterator<Item = u16>,
+    {
+        let (screen_width, screen_height) = (self.width(), self.height());
+        let starting_x = starting_x as usize % screen_width;
+        let starting_y = starting_y as usize % screen_height;
+        let scale = self.scale();
+        let plane_rows = &mut self.buffer.planes[plane.trailing_zeros() as usize];
+        let mut colliding_rows = 0;
+        let mut dirty: Option<Region> = None;
+
+        for (row_number, row) in rows.enumerate() {
+            let y = starting_y + row_number;
+            if clip && y >= screen_height {
+                break;
+            }
+            let y = (y % screen_height) * scale;
+
+            // Lines the sprite up with the left edge of a row of the current
+            // resolution, in its lowest `screen_width` bits
+            let sprite = (row as Row) << (screen_width - 16);
+            let mut mask = sprite >> starting_x;
+            if !clip && starting_x > 0 {
+                mask |= sprite << (screen_width - starting_x);
+            }
+            #[cfg(feature = "hires")]
+            let mask = if scale == 2 {
+                double(mask as u64)
+            } else {
+                mask
+            };
+            if mask == 0 {
+                continue;
+            }
+
+            colliding_rows += (plane_rows[y] & mask != 0) as u8;
+            for buffer_row in &mut plane_rows[y..y + scale] {
+                *buffer_row ^= mask;
+            }
+            let left = mask.leading_zeros() as usize;
+            let region = Region {
+                x: left,
+                y,
+                width: WIDTH - left - mask.trailing_zeros() as usize,
+                height: scale,
+            };
+            dirty = Some(dirty.map_or(region, |dirty| dirty.union(region)));
+        }
+
+        if let Some(region) = dirty {
+            self.mark_dirty(region);
+        }
+        colliding_rows
+    }
+
+    /// Scrolls the selected planes down by `rows` pixels of the current resolution
+    pub fn scroll_down(&mut self, rows: usize) {
+        let rows = (rows * self.scale()).min(HEIGHT);
+        for plane in self.selected_plane_rows() {
+            plane.copy_within(..HEIGHT - rows, rows);
+            plane[..rows].fill(0);
+        }
+        self.mark_dirty(Region::FULL);
+    }
+
+    /// Scrolls the selected planes up by `rows` pixels of the current resolution
+    pub fn scroll_up(&mut self, rows: usize) {
+        let rows = (rows * self.scale()).min(HEIGHT);
+        for plane in self.selected_plane_rows() {
+            plane.copy_within(rows.., 0);
+            plane[HEIGHT - rows..].fill(0);
+        }
+        self.mark_dirty(Region::FULL);
+    }
+
+    /// Scrolls the selected planes right by 4 pixels of the current resolution
+    pub fn scroll_right(&mut self) {
+        let columns = 4 * self.scale();
+        for plane in self.selected_plane_rows() {
+            for row in plane.iter_mut() {
+                *row >>= columns;
             }
         }
-        pixel_turned_off
+        self.mark_dirty(Region::FULL);
     }
 
+    /// Scrolls the selected planes left by 4 pixels of the current resolution
+    pub fn scroll_left(&mut self) {
+        let columns = 4 * self.scale();
+        for plane in self.selected_plane_rows() {
+            for row in plane.iter_mut() {
+                *row <<= columns;
+            }
+        }
+        self.mark_dirty(Region::FULL);
+    }
+
+    /// The rows of every selected plane
+    fn selected_plane_rows(&mut self) -> impl Iterator<Item = &mut [Row; HEIGHT]> {
+        let selected_planes = self.selected_planes;
+        self.buffer
+            .planes
+            .iter_mut()
+            .enumerate()
+            .filter(move |(plane, _)| selected_planes & (1 << plane) != 0)
+            .map(|(_, rows)| rows)
+    }
+
+    #[deprecated(note = "copies the whole buffer, borrow it with `buffer` instead")]
     pub fn get_buffer(&self) -> Buffer {
         self.buffer
     }
 
+    /// Borrows the buffer, for when a copy is too expensive
+    pub fn buffer(&self) -> &Buffer {
+        &self.buffer
+    }
+
+    /// Clears the selected planes
     pub fn clear(&mut self) {
-        self.buffer = [[false; WIDTH]; HEIGHT];
+        for plane in self.selected_plane_rows() {
+            plane.fill(0);
+        }
+        self.mark_dirty(Region::FULL);
+    }
+
+    /// Whether anything changed since the changes were last taken
+    pub fn is_dirty(&self) -> bool {
+        self.dirty.is_some()
+    }
+
+    /// The region covering every pixel that changed since the changes were
+    /// last taken. Pixels drawn twice may have changed back.
+    pub fn dirty_region(&self) -> Option<Region> {
+        self.dirty
+    }
+
+    /// The buffer rows that changed, one bit per row with row 0 in the
+    /// lowest bit, for displays that update line by line
+    pub fn dirty_rows(&self) -> u64 {
+        self.dirty_rows
+    }
+
+    /// Returns the region that changed and starts tracking changes anew,
+    /// call it when redrawing
+    pub fn take_dirty(&mut self) -> Option<Region> {
+        self.dirty_rows = 0;
+        self.dirty.take()
+    }
+
+    /// Marks a region to be redrawn, e.g. after the frontend lost its copy.
+    /// The part outside the buffer is left out.
+    pub fn mark_dirty(&mut self, region: Region) {
+        let region = match region.clamp() {
+            Some(region) => region,
+            None => return,
+        };
+        self.dirty = Some(self.dirty.map_or(region, |dirty| dirty.union(region)));
+        let rows = if region.height >= HEIGHT {
+            !0
+        } else {
+            ((1u64 << region.height) - 1) << region.y
+        };
+        self.dirty_rows |= rows;
+    }
+
+    /// Buffer pixels per pixel of the current resolution
+    fn scale(&self) -> usize {
+        WIDTH / self.width()
+    }
+
+    /// The resolution, selected planes and one bit per pixel for every plane
+    pub(crate) const STATE_SIZE: usize = 2 + PLANES as usize * WIDTH * HEIGHT / 8;
+
+    pub(crate) fn save_state(&self, writer: &mut Writer) {
+        writer.u8(self.hires as u8);
+        writer.u8(self.selected_planes);
+        for plane in self.buffer.planes.iter() {
+            for row in plane.iter() {
+                writer.bytes(&row.to_be_bytes());
+            }
+        }
+    }
+
+    pub(crate) fn load_state(&mut self, reader: &mut Reader) {
+        self.hires = reader.u8() != 0;
+        self.select_planes(reader.u8());
+        for plane in self.buffer.planes.iter_mut() {
+            for row in plane.iter_mut() {
+                let mut bytes = [0; WIDTH / 8];
+                bytes.copy_from_slice(reader.bytes(WIDTH / 8));
+                *row = Row::from_be_bytes(bytes);
+            }
+        }
+        self.mark_dirty(Region::FULL);
+    }
+}
+
+/// Widens a row of the low resolution to the buffer by doubling every bit
+#[cfg(feature = "hires")]
+fn double(row: u64) -> u128 {
+    let mut bits = row as u128;
+    bits = (bits | bits << 32) & 0x0000_0000_FFFF_FFFF_0000_0000_FFFF_FFFF;
+    bits = (bits | bits << 16) & 0x0000_FFFF_0000_FFFF_0000_FFFF_0000_FFFF;
+    bits = (bits | bits << 8) & 0x00FF_00FF_00FF_00FF_00FF_00FF_00FF_00FF;
+    bits = (bits | bits << 4) & 0x0F0F_0F0F_0F0F_0F0F_0F0F_0F0F_0F0F_0F0F;
+    bits = (bits | bits << 2) & 0x3333_3333_3333_3333_3333_3333_3333_3333;
+    bits = (bits | bits << 1) & 0x5555_5555_5555_5555_5555_5555_5555_5555;
+    bits | bits << 1
+}
+
+impl Default for Display {
+    fn default() -> Display {
+        Display::new()
     }
 }
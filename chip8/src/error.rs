@@ -0,0 +1,148 @@
+use core::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// The program doesn't fit into the memory of its mode above the
+    /// interpreter area
+    RomTooLarge(usize),
+    UnknownInstruction,
+    /// The program switched to the 128x64 resolution in a build without the
+    /// `hires` feature
+    HiresUnsupported,
+    StackOverflow,
+    StackUnderflow,
+    /// An instruction tried to access memory past the end of the address
+    /// space. The access began at `start`, usually `I`, and `address` is its
+    /// first byte out of bounds.
+    MemoryOutOfBounds {
+        start: usize,
+        address: usize,
+    },
+}
+
+/// An error raised while loading or executing a program.
+///
+/// `pc` and `opcode` describe the instruction that failed. For errors raised
+/// while loading a program, `pc` is the load address and `opcode` is zero.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Chip8Error {
+    pub kind: ErrorKind,
+    pub pc: u16,
+    pub opcode: u16,
+}
+
+impl Chip8Error {
+    pub fn new(kind: ErrorKind, pc: u16, opcode: u16) -> Chip8Error {
+        Chip8Error { kind, pc, opcode }
+    }
+}
+
+impl fmt::Display for Chip8Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            ErrorKind::RomTooLarge(size) => {
+                return write!(f, "program of {} bytes doesn't fit into memory", size)
+            }
+            ErrorKind::UnknownInstruction => write!(f, "unknown instruction")?,
+            ErrorKind::HiresUnsupported => write!(f, "the 128x64 resolution isn't supported")?,
+            ErrorKind::StackOverflow => write!(f, "stack overflow")?,
+            ErrorKind::StackUnderflow => write!(f, "return with empty stack")?,
+            ErrorKind::MemoryOutOfBounds { start, address } => write!(
+                f,
+                "memory access from {:#05X} out of bounds at {:#05X}",
+                start, address
+            )?,
+        }
+        write!(f, " (pc: {:#05X}, opcode: {:04X})", self.pc, self.opcode)
+    }
+}
+
+/// What happened during a call to `Chip8::run_cycle`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StepOutcome {
+    /// An instruction was executed
+    Ran,
+    /// Nothing was executed, the machine is blocked until a key is pressed
+    WaitingForKey,
+    /// Nothing was executed, a `Draw` is waiting for the next timer tick
+    WaitingForVBlank,
+    /// The program is stuck in a jump to itself and will never make progress
+    Halted,
+}
+
+/// Why a save state couldn't be written or restored
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StateError {
+    /// The buffer is smaller than the state, which needs this many bytes
+    BufferTooSmall(usize),
+    NotASaveState,
+    UnsupportedVersion(u8),
+    ChecksumMismatch,
+    /// The state was saved with a different memory size, i.e. with the
+    /// `xo-chip` feature toggled
+    MemorySizeMismatch(usize),
+    /// The checksum matches, but a field holds an impossible value
+    Corrupt,
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StateError::BufferTooSmall(size) => {
+                write!(f, "buffer too small, a save state needs {} bytes", size)
+            }
+            StateError::NotASaveState => write!(f, "not a save state"),
+            StateError::UnsupportedVersion(version) => {
+                write!(f, "unsupported save state version {}", version)
+            }
+            StateError::ChecksumMismatch => write!(f, "save state checksum mismatch"),
+            StateError::MemorySizeMismatch(size) => write!(
+                f,
+                "save state has {} bytes of memory instead of {}",
+                size,
+                crate::MEMORY_SIZE
+            ),
+            StateError::Corrupt => write!(f, "corrupt save state"),
+        }
+    }
+}
+
+/// Why a movie couldn't be read, recorded or played back
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MovieError {
+    NotAMovie,
+    UnsupportedVersion(u8),
+    ChecksumMismatch,
+    /// The checksum matches, but a field holds an impossible value
+    Corrupt,
+    /// Recording has to start before the first cycle runs
+    NotAtStart,
+    /// The ROM isn't the one the movie was recorded with
+    WrongRom,
+    /// The machine state stopped matching the recording at this cycle
+    Desync(u64),
+    Machine(Chip8Error),
+}
+
+impl fmt::Display for MovieError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MovieError::NotAMovie => write!(f, "not a movie"),
+            MovieError::UnsupportedVersion(version) => {
+                write!(f, "unsupported movie version {}", version)
+            }
+            MovieError::ChecksumMismatch => write!(f, "movie checksum mismatch"),
+            MovieError::Corrupt => write!(f, "corrupt movie"),
+            MovieError::NotAtStart => write!(f, "recording has to start before the first cycle"),
+            MovieError::WrongRom => write!(f, "the movie was recorded with a different ROM"),
+            MovieError::Desync(cycle) => {
+                write!(
+                    f,
+                    "playback no longer matches the recording at cycle {}",
+                    cycle
+                )
+            }
+            MovieError::Machine(error) => write!(f, "{}", error),
+        }
+    }
+}
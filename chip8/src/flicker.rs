@@ -0,0 +1,321 @@
+//! Flicker reduction.
+//!
+//! Games move a sprite by drawing it again with XOR to erase it and then
+//! drawing it in its new place, so a screen shown at the wrong moment misses
+//! it. A `Flicker` keeps its own picture of the display, updated once per
+//! frame, which the output shows in place of the display.
+
+#[cfg(feature = "alloc")]
+use alloc::boxed::Box;
+#[cfg(feature = "alloc")]
+use alloc::vec;
+use core::fmt;
+use core::str::FromStr;
+
+use random_trait::Random;
+
+use crate::display::{Display, Region, HEIGHT, LORES_HEIGHT, LORES_WIDTH, WIDTH};
+use crate::instruction::Instruction;
+use crate::output::{Screen, Shade};
+use crate::Chip8;
+
+/// The highest level a pixel can have, levels share a byte with the palette
+/// index
+const MAX_LEVEL: u8 = 0x3F;
+const LEVEL_BITS: u8 = 6;
+const LORES_PIXELS: usize = LORES_WIDTH * LORES_HEIGHT;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mitigation {
+    /// Shows every pixel lit in any of the last this many frames
+    Blend(u8),
+    /// Pixels fade out once they're turned off like the phosphor of a CRT,
+    /// losing half their brightness every this many frames
+    Phosphor(u8),
+    /// Shows the display as it was right before it was cleared, so games
+    /// that clear it to draw every frame are never seen half drawn. Frames
+    /// without a clear show the display as it is at their end.
+    Clears,
+}
+
+impl fmt::Display for Mitigation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Mitigation::Blend(frames) => write!(f, "blend:{}", frames),
+            Mitigation::Phosphor(half_life) => write!(f, "phosphor:{}", half_life),
+            Mitigation::Clears => f.write_str("clears"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnknownMitigation;
+
+impl fmt::Display for UnknownMitigation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("unknown flicker reduction, expected blend:FRAMES, phosphor:FRAMES or clears")
+    }
+}
+
+/// Parses `blend` and `phosphor` with an optional number of frames after a
+/// colon, two if it's left out
+impl FromStr for Mitigation {
+    type Err = UnknownMitigation;
+
+    fn from_str(text: &str) -> Result<Mitigation, UnknownMitigation> {
+        let mut parts = text.splitn(2, ':');
+        let name = parts.next().unwrap_or_default();
+        let frames = parts.next();
+        if name.eq_ignore_ascii_case("clears") && frames.is_none() {
+            return Ok(Mitigation::Clears);
+        }
+        let frames = match frames {
+            Some(frames) => frames.parse().map_err(|_| UnknownMitigation)?,
+            None => 2,
+        };
+        if name.eq_ignore_ascii_case("blend") {
+            Ok(Mitigation::Blend(frames))
+        } else if name.eq_ignore_ascii_case("phosphor") {
+            Ok(Mitigation::Phosphor(frames))
+        } else {
+            Err(UnknownMitigation)
+        }
+    }
+}
+
+/// A picture of the display with less flicker.
+///
+/// `before_cycle` has to be called before every cycle and `frame` after
+/// every timer tick. It takes a byte for every lores pixel, 2 KiB of RAM,
+/// and with `alloc` another 8 KiB on the heap once the display first
+/// switches to hires. Without `alloc`, hires frames fit into the same 2 KiB
+/// but aren't blended or faded, they're only taken at the end of the frame
+/// or before a clear.
+pub struct Flicker {
+    mitigation: Mitigation,
+    /// Every lores pixel, the palette index in the top two bits and the
+    /// level in the others. The level counts down frames for `Blend` and is
+    /// the brightness for `Phosphor`. Without `alloc`, it holds a two bit
+    /// palette index for every hires pixel instead.
+    lores: [u8; LORES_PIXELS],
+    /// Every buffer pixel like `lores`, allocated the first time it's hires
+    #[cfg(feature = "alloc")]
+    hires_pixels: Option<Box<[u8]>>,
+    hires: bool,
+    /// How much brightness is left after a frame, in 256ths
+    decay: u16,
+    /// The display was taken right before a clear during this frame
+    cleared: bool,
+    dirty: Option<Region>,
+}
+
+impl Flicker {
+    /// Starts out blank, until the first frame
+    pub fn new(mitigation: Mitigation) -> Flicker {
+        let decay = match mitigation {
+            Mitigation::Phosphor(half_life) => decay(half_life),
+            Mitigation::Blend(_) | Mitigation::Clears => 0,
+        };
+        Flicker {
+            mitigation,
+            lores: [0; LORES_PIXELS],
+            #[cfg(feature = "alloc")]
+            hires_pixels: None,
+            hires: false,
+            decay,
+            cleared: false,
+            dirty: Some(Region::FULL),
+        }
+    }
+
+    pub fn mitigation(&self) -> Mitigation {
+        self.mitigation
+    }
+
+    /// Takes the display before it's cleared, for `Mitigation::Clears`
+    pub fn before_cycle<RANDOM: Random>(&mut self, chip8: &Chip8<RANDOM>) {
+        if self.mitigation == Mitigation::Clears
+            && chip8.current_instruction().ok() == Some(Instruction::ClearDisplay)
+        {
+            self.take(&chip8.display);
+            self.cleared = true;
+        }
+    }
+
+    /// Moves on by a frame, also useful to catch up with a display that
+    /// changed without running, e.g. after loading a state
+    pub fn frame(&mut self, display: &Display) {
+        match self.mitigation {
+            Mitigation::Blend(frames) => self.update(display, |lit, level| {
+                if lit {
+                    frames.clamp(1, MAX_LEVEL)
+                } else {
+                    level.saturating_sub(1)
+                }
+            }),
+            Mitigation::Phosphor(_) => {
+                let decay = self.decay;
+                self.update(display, |lit, level| {
+                    if lit {
+                        MAX_LEVEL
+                    } else {
+                        ((level as u16 * decay) >> 8) as u8
+                    }
+                })
+            }
+            Mitigation::Clears => {
+                if !self.cleared {
+                    self.take(display);
+                }
+                self.cleared = false;
+            }
+        }
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        self.dirty.is_some()
+    }
+
+    /// The part of the picture that changed since the last call, like
+    /// `Display::take_dirty`
+    pub fn take_dirty(&mut self) -> Option<Region> {
+        self.dirty.take()
+    }
+
+    fn take(&mut self, display: &Display) {
+        self.update(display, |lit, _| if lit { MAX_LEVEL } else { 0 });
+    }
+
+    /// Sets the level of every pixel from whether it's lit on the display
+    /// and its level so far. Unlit pixels keep the palette index they were
+    /// last lit with while they fade out.
+    fn update<F: Fn(bool, u8) -> u8>(&mut self, display: &Display, level: F) {
+        if display.is_hires() != self.hires {
+            self.set_hires(display.is_hires());
+        }
+        let scale = self.scale();
+        let width = WIDTH / scale;
+        let keeps_levels = !self.hires || cfg!(feature = "alloc");
+        let buffer = display.buffer();
+        let mut dirty: Option<Region> = None;
+        for y in 0..HEIGHT / scale {
+            for x in 0..width {
+                let index = buffer.pixel(x * scale, y * scale);
+                let old = self.pixel(y * width + x);
+                let lit = index != 0;
+                let index = if lit { index } else { old >> LEVEL_BITS };
+                let level = match (keeps_levels, lit) {
+                    (true, _) => level(lit, old & MAX_LEVEL),
+                    (false, true) => MAX_LEVEL,
+                    (false, false) => 0,
+                };
+                let new = index << LEVEL_BITS | level;
+                if new != old {
+                    self.set_pixel(y * width + x, new);
+                    let region = Region {
+                        x: x * scale,
+                        y: y * scale,
+                        width: scale,
+                        height: scale,
+                    };
+                    dirty = Some(dirty.map_or(region, |dirty| dirty.union(region)));
+                }
+            }
+        }
+        if let Some(region) = dirty {
+            self.dirty = Some(self.dirty.map_or(region, |dirty| dirty.union(region)));
+        }
+    }
+
+    /// Starts over blank at the other resolution, like the display does
+    fn set_hires(&mut self, hires: bool) {
+        self.hires = hires;
+        self.lores = [0; LORES_PIXELS];
+        #[cfg(feature = "alloc")]
+        if hires {
+            (self.hires_pixels)
+                .get_or_insert_with(|| vec![0; WIDTH * HEIGHT].into_boxed_slice())
+                .fill(0);
+        }
+        self.dirty = Some(Region::FULL);
+    }
+
+    /// Buffer pixels per pixel of the current resolution, along each axis
+    fn scale(&self) -> usize {
+        if self.hires {
+            1
+        } else {
+            WIDTH / LORES_WIDTH
+        }
+    }
+
+    /// A pixel of the current resolution, counted row by row, as a palette
+    /// index and level
+    fn pixel(&self, index: usize) -> u8 {
+        if !self.hires {
+            return self.lores[index];
+        }
+        #[cfg(feature = "alloc")]
+        if let Some(pixels) = &self.hires_pixels {
+            return pixels[index];
+        }
+        let color = (self.lores[index / 4] >> (index % 4 * 2)) & 0b11;
+        if color == 0 {
+            0
+        } else {
+            color << LEVEL_BITS | MAX_LEVEL
+        }
+    }
+
+    fn set_pixel(&mut self, index: usize, pixel: u8) {
+        if !self.hires {
+            self.lores[index] = pixel;
+            return;
+        }
+        #[cfg(feature = "alloc")]
+        if let Some(pixels) = &mut self.hires_pixels {
+            pixels[index] = pixel;
+            return;
+        }
+        let color = if pixel & MAX_LEVEL == 0 {
+            0
+        } else {
+            pixel >> LEVEL_BITS
+        };
+        let shift = index % 4 * 2;
+        self.lores[index / 4] = (self.lores[index / 4] & !(0b11 << shift)) | color << shift;
+    }
+}
+
+impl Screen for Flicker {
+    fn shade(&self, x: usize, y: usize) -> Shade {
+        let scale = self.scale();
+        let pixel = self.pixel(y / scale * (WIDTH / scale) + x / scale);
+        let (color, level) = (pixel >> LEVEL_BITS, pixel & MAX_LEVEL);
+        if level == 0 {
+            return Shade::BACKGROUND;
+        }
+        let level = match self.mitigation {
+            Mitigation::Phosphor(_) => (level as u16 * 0xFF / MAX_LEVEL as u16) as u8,
+            Mitigation::Blend(_) | Mitigation::Clears => 0xFF,
+        };
+        Shade { color, level }
+    }
+
+    fn is_hires(&self) -> bool {
+        self.hires
+    }
+}
+
+/// The factor, in 256ths, that halves a brightness in `half_life` frames
+fn decay(half_life: u8) -> u16 {
+    let half_life = half_life.max(1);
+    // The largest factor that still loses at least half
+    (0..=0xFF)
+        .rev()
+        .find(|&factor: &u16| {
+            let left = (0..half_life).fold(1u64 << 16, |left, _| (left * factor as u64) >> 8);
+            left <= 1 << 15
+        })
+        .unwrap_or(0)
+}
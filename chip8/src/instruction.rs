@@ -1,15 +1,26 @@
+use crate::Mode;
+
 pub type Address = u16;
 pub type Register = u8;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Instruction {
     ClearDisplay,
     Return,
+    ScrollDown(u8),
+    ScrollUp(u8),
+    ScrollRight,
+    ScrollLeft,
+    Exit,
+    LowRes,
+    HighRes,
     Jump(Address),
     Call(Address),
     SkipIfEqualsByte(Register, u8),
     SkipIfNotEqualsByte(Register, u8),
     SkipIfEqual(Register, Register),
+    SaveRange(Register, Register),
+    LoadRange(Register, Register),
     LoadByte(Register, u8),
     AddByte(Register, u8),
     Move(Register, Register),
@@ -18,11 +29,12 @@ pub enum Instruction {
     Xor(Register, Register),
     Add(Register, Register),
     Sub(Register, Register),
-    ShiftRight(Register),
+    ShiftRight(Register, Register),
     ReverseSub(Register, Register),
-    ShiftLeft(Register),
+    ShiftLeft(Register, Register),
     SkipIfNotEqual(Register, Register),
     LoadI(u16),
+    LoadLongI(Address),
     JumpPlusZero(Address),
     Random(Register, u8),
     Draw(Register, Register, u8),
@@ -30,22 +42,148 @@ pub enum Instruction {
     SkipIfNotPressed(Register),
     LoadDelayTimer(Register),
     WaitForKeyPress(Register),
+    SelectPlanes(u8),
+    LoadAudio,
+    SetPitch(Register),
     SetDelayTimer(Register),
     SetSoundTimer(Register),
     AddToI(Register),
     LoadSprite(Register),
+    LoadBigSprite(Register),
     BCDRepresentation(Register),
     StoreRegisters(Register),
     LoadRegisters(Register),
+    StoreFlags(Register),
+    LoadFlags(Register),
+}
+
+impl Instruction {
+    /// The first mode in which this instruction is available
+    pub fn mode(&self) -> Mode {
+        match *self {
+            Instruction::ScrollDown(_)
+            | Instruction::ScrollRight
+            | Instruction::ScrollLeft
+            | Instruction::Exit
+            | Instruction::LowRes
+            | Instruction::HighRes
+            | Instruction::LoadBigSprite(_)
+            | Instruction::StoreFlags(_)
+            | Instruction::LoadFlags(_) => Mode::SuperChip,
+            Instruction::ScrollUp(_)
+            | Instruction::SaveRange(_, _)
+            | Instruction::LoadRange(_, _)
+            | Instruction::LoadLongI(_)
+            | Instruction::SelectPlanes(_)
+            | Instruction::LoadAudio
+            | Instruction::SetPitch(_) => Mode::XoChip,
+            _ => Mode::Chip8,
+        }
+    }
+
+    /// Size of the instruction in bytes
+    pub fn size(&self) -> u16 {
+        match *self {
+            Instruction::LoadLongI(_) => 4,
+            _ => 2,
+        }
+    }
+
+    /// Encodes the instruction, the inverse of `RawInstruction::to_instruction`
+    pub fn to_raw(&self) -> RawInstruction {
+        let x = |x: Register| (x as u16 & 0xF) << 8;
+        let xy = |x: Register, y: Register| (x as u16 & 0xF) << 8 | (y as u16 & 0xF) << 4;
+        let xkk = |x: Register, kk: u8| (x as u16 & 0xF) << 8 | kk as u16;
+        let nnn = |addr: Address| addr & 0xFFF;
+        let value = match *self {
+            Instruction::ClearDisplay => 0x00E0,
+            Instruction::Return => 0x00EE,
+            Instruction::ScrollDown(n) => 0x00C0 | (n as u16 & 0xF),
+            Instruction::ScrollUp(n) => 0x00D0 | (n as u16 & 0xF),
+            Instruction::ScrollRight => 0x00FB,
+            Instruction::ScrollLeft => 0x00FC,
+            Instruction::Exit => 0x00FD,
+            Instruction::LowRes => 0x00FE,
+            Instruction::HighRes => 0x00FF,
+            Instruction::Jump(addr) => 0x1000 | nnn(addr),
+            Instruction::Call(addr) => 0x2000 | nnn(addr),
+            Instruction::SkipIfEqualsByte(vx, kk) => 0x3000 | xkk(vx, kk),
+            Instruction::SkipIfNotEqualsByte(vx, kk) => 0x4000 | xkk(vx, kk),
+            Instruction::SkipIfEqual(vx, vy) => 0x5000 | xy(vx, vy),
+            Instruction::SaveRange(vx, vy) => 0x5002 | xy(vx, vy),
+            Instruction::LoadRange(vx, vy) => 0x5003 | xy(vx, vy),
+            Instruction::LoadByte(vx, kk) => 0x6000 | xkk(vx, kk),
+            Instruction::AddByte(vx, kk) => 0x7000 | xkk(vx, kk),
+            Instruction::Move(vx, vy) => 0x8000 | xy(vx, vy),
+            Instruction::Or(vx, vy) => 0x8001 | xy(vx, vy),
+            Instruction::And(vx, vy) => 0x8002 | xy(vx, vy),
+            Instruction::Xor(vx, vy) => 0x8003 | xy(vx, vy),
+            Instruction::Add(vx, vy) => 0x8004 | xy(vx, vy),
+            Instruction::Sub(vx, vy) => 0x8005 | xy(vx, vy),
+            Instruction::ShiftRight(vx, vy) => 0x8006 | xy(vx, vy),
+            Instruction::ReverseSub(vx, vy) => 0x8007 | xy(vx, vy),
+            Instruction::ShiftLeft(vx, vy) => 0x800E | xy(vx, vy),
+            Instruction::SkipIfNotEqual(vx, vy) => 0x9000 | xy(vx, vy),
+            Instruction::LoadI(addr) => 0xA000 | nnn(addr),
+            Instruction::LoadLongI(addr) => return RawInstruction::with_operand(0xF000, addr),
+            Instruction::JumpPlusZero(addr) => 0xB000 | nnn(addr),
+            Instruction::Random(vx, kk) => 0xC000 | xkk(vx, kk),
+            Instruction::Draw(vx, vy, n) => 0xD000 | xy(vx, vy) | (n as u16 & 0xF),
+            Instruction::SkipIfPressed(vx) => 0xE09E | x(vx),
+            Instruction::SkipIfNotPressed(vx) => 0xE0A1 | x(vx),
+            Instruction::LoadDelayTimer(vx) => 0xF007 | x(vx),
+            Instruction::WaitForKeyPress(vx) => 0xF00A | x(vx),
+            Instruction::SelectPlanes(n) => 0xF001 | x(n),
+            Instruction::LoadAudio => 0xF002,
+            Instruction::SetPitch(vx) => 0xF03A | x(vx),
+            Instruction::SetDelayTimer(vx) => 0xF015 | x(vx),
+            Instruction::SetSoundTimer(vx) => 0xF018 | x(vx),
+            Instruction::AddToI(vx) => 0xF01E | x(vx),
+            Instruction::LoadSprite(vx) => 0xF029 | x(vx),
+            Instruction::LoadBigSprite(vx) => 0xF030 | x(vx),
+            Instruction::BCDRepresentation(vx) => 0xF033 | x(vx),
+            Instruction::StoreRegisters(vx) => 0xF055 | x(vx),
+            Instruction::LoadRegisters(vx) => 0xF065 | x(vx),
+            Instruction::StoreFlags(vx) => 0xF075 | x(vx),
+            Instruction::LoadFlags(vx) => 0xF085 | x(vx),
+        };
+        RawInstruction::new(value)
+    }
 }
 
 pub struct RawInstruction {
     value: u16,
+    /// The word following `F000`, which is part of the XO-CHIP long I load
+    operand: Option<u16>,
 }
 
 impl RawInstruction {
     pub fn new(value: u16) -> RawInstruction {
-        RawInstruction { value }
+        RawInstruction {
+            value,
+            operand: None,
+        }
+    }
+
+    /// An instruction together with the word following it
+    pub fn with_operand(value: u16, operand: u16) -> RawInstruction {
+        RawInstruction {
+            value,
+            operand: Some(operand),
+        }
+    }
+
+    pub fn value(&self) -> u16 {
+        self.value
+    }
+
+    pub fn operand(&self) -> Option<u16> {
+        self.operand
+    }
+
+    /// Whether the instruction takes up the following word as well
+    pub fn is_long(&self) -> bool {
+        self.value == 0xF000
     }
 
     pub fn to_instruction(&self) -> Option<Instruction> {
@@ -53,13 +191,25 @@ impl RawInstruction {
             0x0 => match self.ooxx() {
                 0xE0 => Some(Instruction::ClearDisplay),
                 0xEE => Some(Instruction::Return),
+                0xC0..=0xCF => Some(Instruction::ScrollDown(self.ooox())),
+                0xD0..=0xDF => Some(Instruction::ScrollUp(self.ooox())),
+                0xFB => Some(Instruction::ScrollRight),
+                0xFC => Some(Instruction::ScrollLeft),
+                0xFD => Some(Instruction::Exit),
+                0xFE => Some(Instruction::LowRes),
+                0xFF => Some(Instruction::HighRes),
                 _ => None,
             },
             0x1 => Some(Instruction::Jump(self.oxxx())),
             0x2 => Some(Instruction::Call(self.oxxx())),
             0x3 => Some(Instruction::SkipIfEqualsByte(self.oxoo(), self.ooxx())),
             0x4 => Some(Instruction::SkipIfNotEqualsByte(self.oxoo(), self.ooxx())),
-            0x5 => Some(Instruction::SkipIfEqual(self.oxoo(), self.ooxo())),
+            0x5 => match self.ooox() {
+                0x0 => Some(Instruction::SkipIfEqual(self.oxoo(), self.ooxo())),
+                0x2 => Some(Instruction::SaveRange(self.oxoo(), self.ooxo())),
+                0x3 => Some(Instruction::LoadRange(self.oxoo(), self.ooxo())),
+                _ => None,
+            },
             0x6 => Some(Instruction::LoadByte(self.oxoo(), self.ooxx())),
             0x7 => Some(Instruction::AddByte(self.oxoo(), self.ooxx())),
             0x8 => match self.ooox() {
@@ -69,9 +219,9 @@ impl RawInstruction {
                 0x3 => Some(Instruction::Xor(self.oxoo(), self.ooxo())),
                 0x4 => Some(Instruction::Add(self.oxoo(), self.ooxo())),
                 0x5 => Some(Instruction::Sub(self.oxoo(), self.ooxo())),
-                0x6 => Some(Instruction::ShiftRight(self.oxoo())),
+                0x6 => Some(Instruction::ShiftRight(self.oxoo(), self.ooxo())),
                 0x7 => Some(Instruction::ReverseSub(self.oxoo(), self.ooxo())),
-                0xE => Some(Instruction::ShiftLeft(self.oxoo())),
+                0xE => Some(Instruction::ShiftLeft(self.oxoo(), self.ooxo())),
                 _ => None,
             },
             0x9 => Some(Instruction::SkipIfNotEqual(self.oxoo(), self.ooxo())),
@@ -85,15 +235,22 @@ impl RawInstruction {
                 _ => None,
             },
             0xF => match self.ooxx() {
+                0x00 if self.is_long() => self.operand.map(Instruction::LoadLongI),
+                0x01 => Some(Instruction::SelectPlanes(self.oxoo())),
+                0x02 if self.oxoo() == 0 => Some(Instruction::LoadAudio),
                 0x07 => Some(Instruction::LoadDelayTimer(self.oxoo())),
                 0x0A => Some(Instruction::WaitForKeyPress(self.oxoo())),
                 0x15 => Some(Instruction::SetDelayTimer(self.oxoo())),
                 0x18 => Some(Instruction::SetSoundTimer(self.oxoo())),
                 0x1E => Some(Instruction::AddToI(self.oxoo())),
                 0x29 => Some(Instruction::LoadSprite(self.oxoo())),
+                0x30 => Some(Instruction::LoadBigSprite(self.oxoo())),
                 0x33 => Some(Instruction::BCDRepresentation(self.oxoo())),
+                0x3A => Some(Instruction::SetPitch(self.oxoo())),
                 0x55 => Some(Instruction::StoreRegisters(self.oxoo())),
                 0x65 => Some(Instruction::LoadRegisters(self.oxoo())),
+                0x75 => Some(Instruction::StoreFlags(self.oxoo())),
+                0x85 => Some(Instruction::LoadFlags(self.oxoo())),
                 _ => None,
             },
             _ => None,
@@ -1,16 +1,42 @@
 #![no_std]
+#[cfg(feature = "alloc")]
+extern crate alloc;
+
+pub mod debugger;
+pub mod disasm;
 pub mod display;
+pub mod error;
+pub mod flicker;
 pub mod instruction;
+#[cfg(feature = "alloc")]
+pub mod movie;
 pub mod output;
+pub mod quirks;
+pub mod random;
+#[cfg(feature = "alloc")]
+pub mod rewind;
+pub mod runner;
+pub mod state;
+pub mod trace;
 
 use core::fmt;
+use core::ops::Range;
 
-use crate::display::{Display, SPRITES};
+use crate::display::{Display, BIG_SPRITES, SPRITES};
+use crate::error::{Chip8Error, ErrorKind, StepOutcome};
 use crate::instruction::{Instruction, RawInstruction};
+use crate::quirks::{IndexIncrement, Quirks};
+#[cfg(feature = "alloc")]
+use crate::trace::Tracer;
 use random_trait::Random;
 
 const NUM_GENERAL_PURPOSE_REGS: usize = 16;
+#[cfg(feature = "xo-chip")]
+const MEMORY_SIZE: usize = 64 * 1024;
+#[cfg(not(feature = "xo-chip"))]
 const MEMORY_SIZE: usize = 4 * 1024;
+/// The memory CHIP-8 and SUPER-CHIP programs can address
+const CLASSIC_MEMORY_SIZE: usize = 4 * 1024;
 const NUM_STACK_FRAMES: usize = 16;
 const PROGRAM_CODE_OFFSET: usize = 0x200;
 // Seems to generally be 1000-500 hz
@@ -18,6 +44,33 @@ const PROGRAM_CODE_OFFSET: usize = 0x200;
 pub const INSTRUCTION_RATE: u32 = 800;
 pub const TIMER_RATE: u32 = 60;
 const NUM_KEYS: usize = 16;
+const NUM_FLAG_REGS: usize = 16;
+const BIG_SPRITES_OFFSET: usize = SPRITES.len();
+const AUDIO_PATTERN_SIZE: usize = 16;
+/// The XO-CHIP pitch at which audio patterns play back at 4000 bits per second
+const DEFAULT_PITCH: u8 = 64;
+
+/// The instruction set the machine executes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Mode {
+    Chip8,
+    /// SUPER-CHIP 1.1 with the high resolution display
+    SuperChip,
+    /// Octo's XO-CHIP extensions on top of SUPER-CHIP, only gets the full
+    /// 64 KiB of memory with the `xo-chip` feature
+    XoChip,
+}
+
+impl Mode {
+    /// How much memory programs can load into and address, 4 KiB unless
+    /// it's XO-CHIP
+    pub fn memory_size(self) -> usize {
+        match self {
+            Mode::Chip8 | Mode::SuperChip => CLASSIC_MEMORY_SIZE,
+            Mode::XoChip => MEMORY_SIZE,
+        }
+    }
+}
 
 pub struct Chip8<RANDOM>
 where
@@ -32,8 +85,28 @@ where
     memory: [u8; MEMORY_SIZE],
     stack: [u16; NUM_STACK_FRAMES],
     key_to_wait_for: Option<u8>,
+    /// The key pressed during a `WaitForKeyPress` that waits for its release
+    key_to_release: Option<u8>,
     keyboard: [bool; NUM_KEYS],
     random: RANDOM,
+    mode: Mode,
+    quirks: Quirks,
+    /// The HP48 RPL user flags saved and restored by `FX75`/`FX85`
+    flag_regs: [u8; NUM_FLAG_REGS],
+    /// Set by the SUPER-CHIP exit instruction
+    exited: bool,
+    /// Cleared by `Draw` when waiting for the display, set again by the next timer tick
+    vblank: bool,
+    audio_pattern: [u8; AUDIO_PATTERN_SIZE],
+    pitch: u8,
+    /// Number of cycles run so far
+    cycles: u64,
+    #[cfg(feature = "alloc")]
+    history: Option<rewind::History>,
+    #[cfg(feature = "alloc")]
+    tracer: Option<alloc::boxed::Box<dyn Tracer + Send>>,
+    #[cfg(feature = "alloc")]
+    recording: Option<movie::Movie>,
     pub display: Display,
 }
 
@@ -41,11 +114,33 @@ impl<RANDOM> Chip8<RANDOM>
 where
     RANDOM: Random,
 {
-    pub fn new(program: &[u8], random: RANDOM) -> Self {
+    pub fn new(program: &[u8], random: RANDOM) -> Result<Self, Chip8Error> {
+        Chip8::with_quirks(program, random, Quirks::default())
+    }
+
+    pub fn with_quirks(program: &[u8], random: RANDOM, quirks: Quirks) -> Result<Self, Chip8Error> {
+        Chip8::with_mode(program, random, Mode::Chip8, quirks)
+    }
+
+    pub fn with_mode(
+        program: &[u8],
+        random: RANDOM,
+        mode: Mode,
+        quirks: Quirks,
+    ) -> Result<Self, Chip8Error> {
+        if program.len() > mode.memory_size() - PROGRAM_CODE_OFFSET {
+            return Err(Chip8Error::new(
+                ErrorKind::RomTooLarge(program.len()),
+                PROGRAM_CODE_OFFSET as u16,
+                0,
+            ));
+        }
         let mut memory = [0; MEMORY_SIZE];
         memory[PROGRAM_CODE_OFFSET..PROGRAM_CODE_OFFSET + program.len()].copy_from_slice(program);
         memory[0..SPRITES.len()].copy_from_slice(&SPRITES);
-        Chip8 {
+        memory[BIG_SPRITES_OFFSET..BIG_SPRITES_OFFSET + BIG_SPRITES.len()]
+            .copy_from_slice(&BIG_SPRITES);
+        Ok(Chip8 {
             regs: [0; NUM_GENERAL_PURPOSE_REGS],
             i_reg: 0,
             delay_timer_reg: 0,
@@ -55,255 +150,633 @@ where
             memory,
             stack: [0; NUM_STACK_FRAMES],
             key_to_wait_for: None,
+            key_to_release: None,
             keyboard: [false; NUM_KEYS],
             random,
+            mode,
+            quirks,
+            flag_regs: [0; NUM_FLAG_REGS],
+            exited: false,
+            vblank: true,
+            audio_pattern: [0; AUDIO_PATTERN_SIZE],
+            pitch: DEFAULT_PITCH,
+            cycles: 0,
+            #[cfg(feature = "alloc")]
+            history: None,
+            #[cfg(feature = "alloc")]
+            tracer: None,
+            #[cfg(feature = "alloc")]
+            recording: None,
             display: Display::new(),
+        })
+    }
+
+    pub fn run_cycle(&mut self) -> Result<StepOutcome, Chip8Error> {
+        #[cfg(feature = "alloc")]
+        if let Some(mut tracer) = self.tracer.take() {
+            let outcome = self.run_cycle_traced(&mut *tracer);
+            self.tracer = Some(tracer);
+            return outcome;
         }
+        let outcome = self.execute_cycle()?;
+        self.end_cycle();
+        Ok(outcome)
     }
 
-    pub fn run_cycle(&mut self) {
-        if self.key_to_wait_for == None {
-            let instruction = self.instruction();
-            self.program_counter_reg = self.run_instruction(&instruction);
+    fn end_cycle(&mut self) {
+        self.cycles += 1;
+        #[cfg(feature = "alloc")]
+        {
+            self.checkpoint_if_due();
+            self.checksum_if_due();
+        }
+    }
+
+    fn execute_cycle(&mut self) -> Result<StepOutcome, Chip8Error> {
+        if self.exited {
+            return Ok(StepOutcome::Halted);
+        }
+        if self.key_to_wait_for.is_some() {
+            return Ok(StepOutcome::WaitingForKey);
         }
+        let pc = self.program_counter_reg;
+        let (instruction, opcode) = self.fetch()?;
+        match instruction {
+            Instruction::Jump(addr) if addr == pc => return Ok(StepOutcome::Halted),
+            Instruction::Draw(..) if self.quirks.display_wait && !self.vblank => {
+                return Ok(StepOutcome::WaitingForVBlank)
+            }
+            _ => {}
+        }
+        self.program_counter_reg = self
+            .run_instruction(&instruction)
+            .map_err(|kind| Chip8Error::new(kind, pc, opcode))?;
+        Ok(StepOutcome::Ran)
+    }
+
+    /// Decodes the instruction at the program counter without running it
+    pub fn current_instruction(&self) -> Result<Instruction, Chip8Error> {
+        self.fetch().map(|(instruction, _)| instruction)
+    }
+
+    fn fetch(&self) -> Result<(Instruction, u16), Chip8Error> {
+        let pc = self.program_counter_reg;
+        let opcode = self
+            .read_word(pc as usize)
+            .map_err(|kind| Chip8Error::new(kind, pc, 0))?;
+        let mut raw_instruction = RawInstruction::new(opcode);
+        if raw_instruction.is_long() && self.mode >= Mode::XoChip {
+            let operand = self
+                .read_word(pc as usize + 2)
+                .map_err(|kind| Chip8Error::new(kind, pc, opcode))?;
+            raw_instruction = RawInstruction::with_operand(opcode, operand);
+        }
+        raw_instruction
+            .to_instruction()
+            .filter(|instruction| instruction.mode() <= self.mode)
+            .map(|instruction| (instruction, opcode))
+            .ok_or_else(|| Chip8Error::new(ErrorKind::UnknownInstruction, pc, opcode))
+    }
+
+    pub fn regs(&self) -> &[u8; NUM_GENERAL_PURPOSE_REGS] {
+        &self.regs
+    }
+
+    pub fn i_reg(&self) -> u16 {
+        self.i_reg
+    }
+
+    pub fn program_counter(&self) -> u16 {
+        self.program_counter_reg
+    }
+
+    /// Addresses of the `Call` instructions that haven't returned yet, innermost last
+    pub fn stack(&self) -> &[u16] {
+        &self.stack[..self.stack_pointer_reg as usize]
+    }
+
+    pub fn memory(&self) -> &[u8] {
+        &self.memory
+    }
+
+    pub fn delay_timer(&self) -> u8 {
+        self.delay_timer_reg
+    }
+
+    pub fn sound_timer(&self) -> u8 {
+        self.sound_timer_reg
+    }
+
+    /// Whether the machine is blocked in `WaitForKeyPress`
+    pub fn is_waiting_for_key(&self) -> bool {
+        self.key_to_wait_for.is_some()
+    }
+
+    /// Number of successful `run_cycle` calls, including the ones that waited
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    pub fn quirks(&self) -> Quirks {
+        self.quirks
+    }
+
+    pub fn set_quirks(&mut self, quirks: Quirks) {
+        self.quirks = quirks;
     }
 
     pub fn timer_tick(&mut self) {
+        #[cfg(feature = "alloc")]
+        self.record(rewind::Input::TimerTick);
+        self.vblank = true;
         if self.delay_timer_reg > 0 {
             self.delay_timer_reg -= 1;
         }
+        if self.sound_timer_reg > 0 {
+            self.sound_timer_reg -= 1;
+        }
     }
-    fn run_instruction(&mut self, instruction: &Instruction) -> u16 {
-        match *instruction {
+
+    /// Whether the buzzer should currently be sounding
+    pub fn is_sound_active(&self) -> bool {
+        self.sound_timer_reg > 0
+    }
+
+    /// The XO-CHIP 1-bit audio samples played while the sound timer runs
+    pub fn audio_pattern(&self) -> &[u8; AUDIO_PATTERN_SIZE] {
+        &self.audio_pattern
+    }
+
+    /// The XO-CHIP pitch, the pattern plays at `4000 * 2^((pitch - 64) / 48)`
+    /// bits per second
+    pub fn pitch(&self) -> u8 {
+        self.pitch
+    }
+
+    fn run_instruction(&mut self, instruction: &Instruction) -> Result<u16, ErrorKind> {
+        let next = match *instruction {
             Instruction::ClearDisplay => {
                 self.display.clear();
-                self.program_counter_reg + 2
+                self.next_instruction()
             }
             Instruction::Return => {
-                let addr = self.stack[(self.stack_pointer_reg - 1) as usize];
+                if self.stack_pointer_reg == 0 {
+                    return Err(ErrorKind::StackUnderflow);
+                }
                 self.stack_pointer_reg -= 1;
-                addr + 2
+                self.stack[self.stack_pointer_reg as usize].wrapping_add(2)
+            }
+            Instruction::ScrollDown(rows) => {
+                self.display.scroll_down(rows as usize);
+                self.next_instruction()
+            }
+            Instruction::ScrollUp(rows) => {
+                self.display.scroll_up(rows as usize);
+                self.next_instruction()
+            }
+            Instruction::ScrollRight => {
+                self.display.scroll_right();
+                self.next_instruction()
+            }
+            Instruction::ScrollLeft => {
+                self.display.scroll_left();
+                self.next_instruction()
+            }
+            Instruction::Exit => {
+                self.exited = true;
+                self.program_counter_reg
+            }
+            Instruction::LowRes => {
+                self.display.set_hires(false);
+                self.next_instruction()
+            }
+            Instruction::HighRes => {
+                if !cfg!(feature = "hires") {
+                    return Err(ErrorKind::HiresUnsupported);
+                }
+                self.display.set_hires(true);
+                self.next_instruction()
             }
             Instruction::Jump(addr) => addr,
             Instruction::Call(addr) => {
+                if self.stack_pointer_reg as usize == NUM_STACK_FRAMES {
+                    return Err(ErrorKind::StackOverflow);
+                }
+                self.stack[self.stack_pointer_reg as usize] = self.program_counter_reg;
                 self.stack_pointer_reg += 1;
-                self.stack[(self.stack_pointer_reg - 1) as usize] = self.program_counter_reg;
                 addr
             }
             Instruction::SkipIfEqualsByte(reg, value) => {
                 if self.read_reg(reg) == value {
-                    self.program_counter_reg + 4
+                    self.skip_instruction()
                 } else {
-                    self.program_counter_reg + 2
+                    self.next_instruction()
                 }
             }
             Instruction::SkipIfNotEqualsByte(reg, value) => {
                 if self.read_reg(reg) != value {
-                    self.program_counter_reg + 4
+                    self.skip_instruction()
                 } else {
-                    self.program_counter_reg + 2
+                    self.next_instruction()
                 }
             }
             Instruction::SkipIfEqual(reg1, reg2) => {
                 if self.read_reg(reg1) == self.read_reg(reg2) {
-                    self.program_counter_reg + 4
+                    self.skip_instruction()
                 } else {
-                    self.program_counter_reg + 2
+                    self.next_instruction()
                 }
             }
+            Instruction::SaveRange(first, last) => {
+                let range = self.memory_range(self.i_reg as usize, register_count(first, last))?;
+                for (address, reg) in range.zip(register_range(first, last)) {
+                    self.memory[address] = self.read_reg(reg);
+                }
+                self.next_instruction()
+            }
+            Instruction::LoadRange(first, last) => {
+                let range = self.memory_range(self.i_reg as usize, register_count(first, last))?;
+                for (address, reg) in range.zip(register_range(first, last)) {
+                    self.load_reg(reg, self.memory[address]);
+                }
+                self.next_instruction()
+            }
             Instruction::LoadByte(reg, value) => {
                 self.load_reg(reg, value);
-                self.program_counter_reg + 2
+                self.next_instruction()
             }
             Instruction::AddByte(reg_number, value) => {
                 let reg_value = self.read_reg(reg_number);
                 self.load_reg(reg_number, value.wrapping_add(reg_value));
-                self.program_counter_reg + 2
+                self.next_instruction()
             }
             Instruction::Move(reg1, reg2) => {
                 let value = self.read_reg(reg2);
                 self.load_reg(reg1, value);
-                self.program_counter_reg + 2
+                self.next_instruction()
             }
-            Instruction::Or(_, _) => {
-                panic!("Not yet implemented: {:?}", instruction);
+            Instruction::Or(reg1, reg2) => {
+                let first = self.read_reg(reg1);
+                let second = self.read_reg(reg2);
+                self.load_reg(reg1, first | second);
+                self.reset_vf();
+                self.next_instruction()
             }
             Instruction::And(reg1, reg2) => {
                 let first = self.read_reg(reg1);
                 let second = self.read_reg(reg2);
                 self.load_reg(reg1, first & second);
-                self.program_counter_reg + 2
+                self.reset_vf();
+                self.next_instruction()
             }
             Instruction::Xor(reg1, reg2) => {
                 let first = self.read_reg(reg1);
                 let second = self.read_reg(reg2);
                 self.load_reg(reg1, first ^ second);
-                self.program_counter_reg + 2
+                self.reset_vf();
+                self.next_instruction()
             }
             Instruction::Add(reg1, reg2) => {
                 let first = self.read_reg(reg1) as u16;
                 let second = self.read_reg(reg2) as u16;
                 let answer = first + second;
-                self.load_reg(0xF, (answer > 255) as u8);
                 self.load_reg(reg1, answer as u8);
-                self.program_counter_reg + 2
+                self.load_reg(0xF, (answer > 255) as u8);
+                self.next_instruction()
             }
             Instruction::Sub(reg1, reg2) => {
                 let first = self.read_reg(reg1);
                 let second = self.read_reg(reg2);
-                self.load_reg(0xF, (first > second) as u8);
                 self.load_reg(reg1, first.wrapping_sub(second));
-                self.program_counter_reg + 2
+                self.load_reg(0xF, (first >= second) as u8);
+                self.next_instruction()
             }
-            Instruction::ShiftRight(reg) => {
-                let value = self.read_reg(reg);
+            Instruction::ShiftRight(reg1, reg2) => {
+                let value = self.read_reg(self.shift_source(reg1, reg2));
+                self.load_reg(reg1, value >> 1);
                 self.load_reg(0xF, value & 0b1);
-                self.load_reg(reg, value >> 1);
-                self.program_counter_reg + 2
+                self.next_instruction()
             }
-            Instruction::ReverseSub(_, _) => {
-                panic!("Not yet implemeneted: {:?}", instruction);
+            Instruction::ReverseSub(reg1, reg2) => {
+                let first = self.read_reg(reg1);
+                let second = self.read_reg(reg2);
+                self.load_reg(reg1, second.wrapping_sub(first));
+                self.load_reg(0xF, (second >= first) as u8);
+                self.next_instruction()
             }
-            Instruction::ShiftLeft(reg) => {
-                let value = self.read_reg(reg);
+            Instruction::ShiftLeft(reg1, reg2) => {
+                let value = self.read_reg(self.shift_source(reg1, reg2));
+                self.load_reg(reg1, value << 1);
                 self.load_reg(0xF, value >> 7);
-                self.load_reg(reg, value << 1);
-                self.program_counter_reg + 2
+                self.next_instruction()
             }
             Instruction::SkipIfNotEqual(reg1, reg2) => {
                 let first = self.read_reg(reg1);
                 let second = self.read_reg(reg2);
                 if first != second {
-                    self.program_counter_reg + 4
+                    self.skip_instruction()
                 } else {
-                    self.program_counter_reg + 2
+                    self.next_instruction()
                 }
             }
             Instruction::LoadI(value) => {
                 self.i_reg = value;
-                self.program_counter_reg + 2
+                self.next_instruction()
             }
-            Instruction::JumpPlusZero(_) => {
-                panic!("Not yet implemented: {:?}", instruction);
+            Instruction::LoadLongI(addr) => {
+                self.i_reg = addr;
+                self.program_counter_reg.wrapping_add(instruction.size())
+            }
+            Instruction::JumpPlusZero(addr) => {
+                let reg = if self.quirks.jump_uses_vx {
+                    (addr >> 8) as u8
+                } else {
+                    0x0
+                };
+                addr + self.read_reg(reg) as u16
             }
             Instruction::Random(reg, value) => {
-                let rand_number = self.random.get_u8();
+                let rand_number = self.random_byte();
 
                 self.load_reg(reg, rand_number & value);
-                self.program_counter_reg + 2
+                self.next_instruction()
             }
             Instruction::Draw(reg1, reg2, n) => {
                 let x = self.read_reg(reg1);
                 let y = self.read_reg(reg2);
-                let from = self.i_reg as usize;
-                let to = from + (n as usize);
-
-                self.regs[0xF] = self.display.draw(x, y, &self.memory[from..to]) as u8;
-                self.program_counter_reg + 2
+                let clip = self.quirks.clip_sprites;
+                // Every selected XO-CHIP plane gets its own sprite
+                let planes = self.display.selected_planes().count_ones() as usize;
+                let colliding_rows = if n == 0 && self.mode >= Mode::SuperChip {
+                    let sprite = self.memory_range(self.i_reg as usize, 32 * planes)?;
+                    self.display.draw_wide(x, y, &self.memory[sprite], clip)
+                } else {
+                    let sprite = self.memory_range(self.i_reg as usize, n as usize * planes)?;
+                    self.display.draw(x, y, &self.memory[sprite], clip)
+                };
+                // SUPER-CHIP reports the number of colliding rows in high resolution
+                self.regs[0xF] = if self.mode == Mode::SuperChip && self.display.is_hires() {
+                    colliding_rows
+                } else {
+                    (colliding_rows > 0) as u8
+                };
+                self.vblank = false;
+                self.next_instruction()
             }
             Instruction::SkipIfPressed(reg) => {
                 let value = self.read_reg(reg);
-                let pressed = self.keyboard[value as usize];
+                let pressed = self.keyboard[(value & 0xF) as usize];
                 if pressed {
-                    self.program_counter_reg + 4
+                    self.skip_instruction()
                 } else {
-                    self.program_counter_reg + 2
+                    self.next_instruction()
                 }
             }
             Instruction::SkipIfNotPressed(reg) => {
                 let value = self.read_reg(reg);
-                let pressed = self.keyboard[value as usize];
+                let pressed = self.keyboard[(value & 0xF) as usize];
                 if !pressed {
-                    self.program_counter_reg + 4
+                    self.skip_instruction()
                 } else {
-                    self.program_counter_reg + 2
+                    self.next_instruction()
                 }
             }
             Instruction::LoadDelayTimer(reg) => {
                 let delay_value = self.delay_timer_reg;
                 self.load_reg(reg, delay_value);
-                self.program_counter_reg + 2
+                self.next_instruction()
             }
             Instruction::WaitForKeyPress(reg) => {
                 // TODO rename key_to_wait_for
                 self.key_to_wait_for = Some(reg);
-                self.program_counter_reg + 2
+                self.next_instruction()
+            }
+            Instruction::SelectPlanes(planes) => {
+                self.display.select_planes(planes);
+                self.next_instruction()
+            }
+            Instruction::LoadAudio => {
+                let pattern = self.memory_range(self.i_reg as usize, AUDIO_PATTERN_SIZE)?;
+                self.audio_pattern.copy_from_slice(&self.memory[pattern]);
+                self.next_instruction()
+            }
+            Instruction::SetPitch(reg) => {
+                self.pitch = self.read_reg(reg);
+                self.next_instruction()
             }
             Instruction::SetDelayTimer(reg) => {
                 let value = self.read_reg(reg);
                 self.delay_timer_reg = value;
-                self.program_counter_reg + 2
+                self.next_instruction()
             }
-            Instruction::SetSoundTimer(_) => {
-                // TODO: set sound timer
-                self.program_counter_reg + 2
+            Instruction::SetSoundTimer(reg) => {
+                let value = self.read_reg(reg);
+                self.sound_timer_reg = value;
+                self.next_instruction()
             }
             Instruction::AddToI(reg) => {
                 let value = self.read_reg(reg) as u16;
-                self.i_reg = self.i_reg + value;
-                self.program_counter_reg + 2
+                self.i_reg = self.i_reg.wrapping_add(value);
+                if self.quirks.add_to_i_sets_vf {
+                    let overflow = self.i_reg > 0xFFF;
+                    self.load_reg(0xF, overflow as u8);
+                }
+                self.next_instruction()
             }
             Instruction::LoadSprite(reg) => {
                 let digit = self.read_reg(reg);
-                self.i_reg = (digit * 5) as u16;
-                self.program_counter_reg + 2
+                self.i_reg = (digit & 0xF) as u16 * 5;
+                self.next_instruction()
+            }
+            Instruction::LoadBigSprite(reg) => {
+                let digit = self.read_reg(reg);
+                self.i_reg = (BIG_SPRITES_OFFSET + (digit & 0xF) as usize * 10) as u16;
+                self.next_instruction()
             }
             Instruction::BCDRepresentation(reg) => {
                 let value = self.read_reg(reg);
-                self.memory[self.i_reg as usize] = (value / 100) % 10;
-                self.memory[(self.i_reg + 1) as usize] = (value / 10) % 10;
-                self.memory[(self.i_reg + 2) as usize] = value % 10;
-                self.program_counter_reg + 2
+                let digits = self.memory_range(self.i_reg as usize, 3)?;
+                self.memory[digits].copy_from_slice(&[value / 100, (value / 10) % 10, value % 10]);
+                self.next_instruction()
             }
             Instruction::StoreRegisters(highest_reg) => {
-                let i = self.i_reg;
-                for reg_number in 0..(highest_reg + 1) {
-                    self.memory[(i + reg_number as u16) as usize] = self.read_reg(reg_number);
-                }
-                self.program_counter_reg + 2
+                let count = highest_reg as usize + 1;
+                let range = self.memory_range(self.i_reg as usize, count)?;
+                self.memory[range].copy_from_slice(&self.regs[..count]);
+                self.increment_i(highest_reg);
+                self.next_instruction()
             }
             Instruction::LoadRegisters(highest_reg) => {
-                let i = self.i_reg;
-                for reg_number in 0..(highest_reg + 1) {
-                    let value = self.memory[(i + reg_number as u16) as usize];
-                    self.load_reg(reg_number, value);
-                }
-                self.program_counter_reg + 2
-            }
-        }
+                let count = highest_reg as usize + 1;
+                let range = self.memory_range(self.i_reg as usize, count)?;
+                self.regs[..count].copy_from_slice(&self.memory[range]);
+                self.increment_i(highest_reg);
+                self.next_instruction()
+            }
+            Instruction::StoreFlags(highest_reg) => {
+                let count = highest_reg as usize + 1;
+                self.flag_regs[..count].copy_from_slice(&self.regs[..count]);
+                self.next_instruction()
+            }
+            Instruction::LoadFlags(highest_reg) => {
+                let count = highest_reg as usize + 1;
+                self.regs[..count].copy_from_slice(&self.flag_regs[..count]);
+                self.next_instruction()
+            }
+        };
+        Ok(next)
     }
 
     pub fn handle_key_press(&mut self, key: u8) {
+        if key as usize >= NUM_KEYS {
+            return;
+        }
+        #[cfg(feature = "alloc")]
+        self.record(rewind::Input::KeyPress(key));
         self.keyboard[key as usize] = true;
         if let Some(reg) = self.key_to_wait_for {
-            self.load_reg(reg, key);
-            self.key_to_wait_for = None;
+            if !self.quirks.wait_for_release {
+                self.load_reg(reg, key);
+                self.key_to_wait_for = None;
+            } else if self.key_to_release.is_none() {
+                self.key_to_release = Some(key);
+            }
         }
     }
 
     pub fn handle_key_release(&mut self, key: u8) {
+        if key as usize >= NUM_KEYS {
+            return;
+        }
+        #[cfg(feature = "alloc")]
+        self.record(rewind::Input::KeyRelease(key));
         self.keyboard[key as usize] = false;
+        if let (Some(reg), true) = (self.key_to_wait_for, self.key_to_release == Some(key)) {
+            self.load_reg(reg, key);
+            self.key_to_wait_for = None;
+            self.key_to_release = None;
+        }
     }
 
-    fn instruction(&self) -> Instruction {
-        let pc = self.program_counter_reg;
-        let higher_order = (self.memory[pc as usize] as u16) << 8;
-        let lower_order = self.memory[(pc + 1) as usize] as u16;
-        RawInstruction::new(higher_order + lower_order)
-            .to_instruction()
-            .expect("Unrecognized instruction")
+    /// Sets which keys are held, one bit per key with key 0 in the lowest
+    /// bit, as read from a keypad matrix. Keys that changed since the last
+    /// call are released or pressed, in that order.
+    pub fn set_keypad(&mut self, keys: u16) {
+        let changed = keys ^ self.keypad();
+        for key in 0..NUM_KEYS as u8 {
+            if changed & !keys & (1 << key) != 0 {
+                self.handle_key_release(key);
+            }
+        }
+        for key in 0..NUM_KEYS as u8 {
+            if changed & keys & (1 << key) != 0 {
+                self.handle_key_press(key);
+            }
+        }
+    }
+
+    /// The keys held, in the layout `set_keypad` takes
+    pub fn keypad(&self) -> u16 {
+        self.keyboard
+            .iter()
+            .enumerate()
+            .fold(0, |keys, (key, &pressed)| keys | (pressed as u16) << key)
+    }
+
+    fn random_byte(&mut self) -> u8 {
+        #[cfg(feature = "alloc")]
+        {
+            if let Some(history) = self.history.as_mut() {
+                return history.random(self.cycles, &mut self.random);
+            }
+        }
+        self.random.get_u8()
+    }
+
+    fn shift_source(&self, reg1: u8, reg2: u8) -> u8 {
+        if self.quirks.shift_uses_vy {
+            reg2
+        } else {
+            reg1
+        }
+    }
+
+    fn reset_vf(&mut self) {
+        if self.quirks.vf_reset {
+            self.load_reg(0xF, 0);
+        }
+    }
+
+    fn increment_i(&mut self, highest_reg: u8) {
+        let increment = match self.quirks.load_store_increment {
+            IndexIncrement::Unchanged => 0,
+            IndexIncrement::X => highest_reg as u16,
+            IndexIncrement::XPlusOne => highest_reg as u16 + 1,
+        };
+        self.i_reg = self.i_reg.wrapping_add(increment);
+    }
+
+    fn next_instruction(&self) -> u16 {
+        self.program_counter_reg.wrapping_add(2)
+    }
+
+    /// Address after the next instruction, which takes up two words if it's
+    /// an XO-CHIP long I load
+    fn skip_instruction(&self) -> u16 {
+        let next = self.next_instruction();
+        match self.read_word(next as usize) {
+            Ok(opcode) if self.mode >= Mode::XoChip && RawInstruction::new(opcode).is_long() => {
+                next.wrapping_add(4)
+            }
+            _ => next.wrapping_add(2),
+        }
+    }
+
+    fn read_word(&self, address: usize) -> Result<u16, ErrorKind> {
+        let bytes = self.memory_range(address, 2)?;
+        let higher_order = (self.memory[bytes.start] as u16) << 8;
+        let lower_order = self.memory[bytes.start + 1] as u16;
+        Ok(higher_order + lower_order)
+    }
+
+    /// Bounds checked range of `len` bytes of memory starting at `start`,
+    /// within the memory of the current mode
+    fn memory_range(&self, start: usize, len: usize) -> Result<Range<usize>, ErrorKind> {
+        let size = self.mode.memory_size();
+        if start + len > size {
+            Err(ErrorKind::MemoryOutOfBounds {
+                start,
+                address: start.max(size),
+            })
+        } else {
+            Ok(start..start + len)
+        }
     }
 
     fn read_reg(&self, reg_number: u8) -> u8 {
-        self.regs[(reg_number as usize)]
+        self.regs[reg_number as usize]
     }
 
     fn load_reg(&mut self, reg_number: u8, value: u8) {
-        self.regs[(reg_number as usize)] = value;
+        self.regs[reg_number as usize] = value;
     }
 }
 
-impl<'a, RANDOM> fmt::Debug for Chip8<RANDOM>
+fn register_count(first: u8, last: u8) -> usize {
+    (first as isize - last as isize).unsigned_abs() + 1
+}
+
+/// The registers from `first` to `last`, counting down if `last` is smaller
+fn register_range(first: u8, last: u8) -> impl Iterator<Item = u8> {
+    let step: i8 = if last < first { -1 } else { 1 };
+    (0..register_count(first, last) as i8).map(move |n| (first as i8 + n * step) as u8)
+}
+
+impl<RANDOM> fmt::Debug for Chip8<RANDOM>
 where
     RANDOM: Random,
 {
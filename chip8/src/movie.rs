@@ -0,0 +1,383 @@
+//! Movies: recorded input that replays a session exactly.
+//!
+//! The machine is deterministic once its random number generator is seeded,
+//! so a movie holds the seed, the mode and quirks, and every key press, key
+//! release and timer tick with the cycle it arrived after. Every
+//! `CHECKSUM_INTERVAL` cycles it also stores a CRC-32 of the machine state,
+//! which playback compares against to notice when it went off track.
+//!
+//! The file format is the magic `R8MV`, a version byte and the movie in
+//! little endian, followed by a CRC-32 of everything before it.
+
+use alloc::vec::Vec;
+use random_trait::Random;
+
+use crate::error::{MovieError, StepOutcome};
+use crate::quirks::{IndexIncrement, Quirks};
+use crate::state::{crc32, RandomState, STATE_SIZE};
+use crate::{Chip8, Mode, INSTRUCTION_RATE};
+
+const MAGIC: [u8; 4] = *b"R8MV";
+pub const MOVIE_VERSION: u8 = 1;
+/// Once per second of emulated time
+pub const CHECKSUM_INTERVAL: u64 = INSTRUCTION_RATE as u64;
+const CHECKSUM_SIZE: usize = 4;
+/// Event codes above the sixteen key presses and sixteen key releases
+const TIMER_TICK: u8 = 0x20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Input {
+    KeyPress(u8),
+    KeyRelease(u8),
+    TimerTick,
+}
+
+/// An input that arrived after `cycle` cycles had run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Event {
+    pub cycle: u64,
+    pub input: Input,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Movie {
+    pub mode: Mode,
+    pub quirks: Quirks,
+    /// The state of the random number generator before the first cycle
+    pub seed: u64,
+    /// CRC-32 of the ROM the movie was recorded with
+    pub rom_checksum: u32,
+    pub events: Vec<Event>,
+    /// Checksums of the machine state after every `checksum_interval` cycles
+    pub checksum_interval: u64,
+    pub checksums: Vec<u32>,
+    /// The number of cycles recorded
+    pub length: u64,
+}
+
+impl Movie {
+    /// Creates a machine in the state the recording started from, which
+    /// `Player` can then drive
+    pub fn machine<RANDOM>(
+        &self,
+        rom: &[u8],
+        mut random: RANDOM,
+    ) -> Result<Chip8<RANDOM>, MovieError>
+    where
+        RANDOM: Random + RandomState,
+    {
+        if crc32(rom) != self.rom_checksum {
+            return Err(MovieError::WrongRom);
+        }
+        random.set_state(self.seed);
+        Chip8::with_mode(rom, random, self.mode, self.quirks).map_err(MovieError::Machine)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&MAGIC);
+        bytes.push(MOVIE_VERSION);
+        bytes.push(self.mode as u8);
+        bytes.push(quirk_flags(&self.quirks));
+        bytes.push(self.quirks.load_store_increment as u8);
+        bytes.extend_from_slice(&self.seed.to_le_bytes());
+        bytes.extend_from_slice(&self.rom_checksum.to_le_bytes());
+        bytes.extend_from_slice(&self.length.to_le_bytes());
+        bytes.extend_from_slice(&self.checksum_interval.to_le_bytes());
+
+        // Most events are timer ticks a dozen cycles apart, so cycles are
+        // stored as the distance to the previous event
+        write_varint(&mut bytes, self.events.len() as u64);
+        let mut cycle = 0;
+        for event in &self.events {
+            write_varint(&mut bytes, event.cycle - cycle);
+            cycle = event.cycle;
+            bytes.push(match event.input {
+                Input::KeyPress(key) => key,
+                Input::KeyRelease(key) => 0x10 | key,
+                Input::TimerTick => TIMER_TICK,
+            });
+        }
+        write_varint(&mut bytes, self.checksums.len() as u64);
+        for checksum in &self.checksums {
+            bytes.extend_from_slice(&checksum.to_le_bytes());
+        }
+
+        let checksum = crc32(&bytes);
+        bytes.extend_from_slice(&checksum.to_le_bytes());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Movie, MovieError> {
+        if bytes.len() < MAGIC.len() + 1 || bytes[..MAGIC.len()] != MAGIC {
+            return Err(MovieError::NotAMovie);
+        }
+        let version = bytes[MAGIC.len()];
+        if version != MOVIE_VERSION {
+            return Err(MovieError::UnsupportedVersion(version));
+        }
+        if bytes.len() < MAGIC.len() + 1 + CHECKSUM_SIZE {
+            return Err(MovieError::Corrupt);
+        }
+        let (movie, checksum) = bytes.split_at(bytes.len() - CHECKSUM_SIZE);
+        if crc32(movie).to_le_bytes() != checksum {
+            return Err(MovieError::ChecksumMismatch);
+        }
+
+        let mut reader = Reader {
+            bytes: &movie[MAGIC.len() + 1..],
+        };
+        let mode = match reader.u8()? {
+            0 => Mode::Chip8,
+            1 => Mode::SuperChip,
+            2 => Mode::XoChip,
+            _ => return Err(MovieError::Corrupt),
+        };
+        let flags = reader.u8()?;
+        let load_store_increment = match reader.u8()? {
+            0 => IndexIncrement::Unchanged,
+            1 => IndexIncrement::X,
+            2 => IndexIncrement::XPlusOne,
+            _ => return Err(MovieError::Corrupt),
+        };
+        let quirks = Quirks {
+            shift_uses_vy: flags & 1 != 0,
+            load_store_increment,
+            vf_reset: flags & 2 != 0,
+            clip_sprites: flags & 4 != 0,
+            jump_uses_vx: flags & 8 != 0,
+            add_to_i_sets_vf: flags & 16 != 0,
+            display_wait: flags & 32 != 0,
+            wait_for_release: flags & 64 != 0,
+        };
+        let seed = reader.u64()?;
+        let rom_checksum = reader.u32()?;
+        let length = reader.u64()?;
+        let checksum_interval = reader.u64()?;
+
+        let count = reader.varint()?;
+        let mut events = Vec::new();
+        let mut cycle = 0u64;
+        for _ in 0..count {
+            cycle = cycle
+                .checked_add(reader.varint()?)
+                .ok_or(MovieError::Corrupt)?;
+            let input = match reader.u8()? {
+                code @ 0x00..=0x0F => Input::KeyPress(code),
+                code @ 0x10..=0x1F => Input::KeyRelease(code & 0xF),
+                TIMER_TICK => Input::TimerTick,
+                _ => return Err(MovieError::Corrupt),
+            };
+            events.push(Event { cycle, input });
+        }
+        let count = reader.varint()?;
+        let mut checksums = Vec::new();
+        for _ in 0..count {
+            checksums.push(reader.u32()?);
+        }
+        if !reader.bytes.is_empty() || checksum_interval == 0 {
+            return Err(MovieError::Corrupt);
+        }
+
+        Ok(Movie {
+            mode,
+            quirks,
+            seed,
+            rom_checksum,
+            events,
+            checksum_interval,
+            checksums,
+            length,
+        })
+    }
+
+    pub(crate) fn record(&mut self, cycle: u64, input: Input) {
+        self.events.push(Event { cycle, input });
+    }
+}
+
+/// Plays a movie back on the machine created by `Movie::machine`
+pub struct Player {
+    movie: Movie,
+    next_event: usize,
+}
+
+impl Player {
+    pub fn new(movie: Movie) -> Player {
+        Player {
+            movie,
+            next_event: 0,
+        }
+    }
+
+    pub fn movie(&self) -> &Movie {
+        &self.movie
+    }
+
+    /// Whether every recorded cycle has been played
+    pub fn is_finished<RANDOM: Random>(&self, chip8: &Chip8<RANDOM>) -> bool {
+        chip8.cycles() >= self.movie.length
+    }
+
+    /// Feeds the machine the input recorded for this point and runs a cycle,
+    /// checking the state against the recording whenever a checksum is due
+    pub fn run_cycle<RANDOM: Random>(
+        &mut self,
+        chip8: &mut Chip8<RANDOM>,
+    ) -> Result<StepOutcome, MovieError> {
+        while let Some(event) = self.movie.events.get(self.next_event) {
+            if event.cycle > chip8.cycles() {
+                break;
+            }
+            match event.input {
+                Input::KeyPress(key) => chip8.handle_key_press(key),
+                Input::KeyRelease(key) => chip8.handle_key_release(key),
+                Input::TimerTick => chip8.timer_tick(),
+            }
+            self.next_event += 1;
+        }
+        let outcome = chip8.run_cycle().map_err(MovieError::Machine)?;
+
+        let cycle = chip8.cycles();
+        if cycle.is_multiple_of(self.movie.checksum_interval) {
+            let index = (cycle / self.movie.checksum_interval) as usize;
+            let recorded = index
+                .checked_sub(1)
+                .and_then(|index| self.movie.checksums.get(index));
+            if recorded.is_some_and(|&checksum| checksum != chip8.state_checksum()) {
+                return Err(MovieError::Desync(cycle));
+            }
+        }
+        Ok(outcome)
+    }
+}
+
+impl<RANDOM> Chip8<RANDOM>
+where
+    RANDOM: Random + RandomState,
+{
+    /// Starts recording a movie of everything from the first cycle on.
+    /// Rewinding is refused while recording, and loading a save state makes
+    /// the movie unplayable.
+    pub fn start_recording(&mut self, rom: &[u8]) -> Result<(), MovieError> {
+        if self.cycles != 0 {
+            return Err(MovieError::NotAtStart);
+        }
+        self.recording = Some(Movie {
+            mode: self.mode,
+            quirks: self.quirks,
+            seed: self.random.state(),
+            rom_checksum: crc32(rom),
+            events: Vec::new(),
+            checksum_interval: CHECKSUM_INTERVAL,
+            checksums: Vec::new(),
+            length: 0,
+        });
+        Ok(())
+    }
+}
+
+impl<RANDOM> Chip8<RANDOM>
+where
+    RANDOM: Random,
+{
+    pub fn is_recording(&self) -> bool {
+        self.recording.is_some()
+    }
+
+    /// Ends the recording, returning the movie if there was one
+    pub fn stop_recording(&mut self) -> Option<Movie> {
+        let mut movie = self.recording.take()?;
+        movie.length = self.cycles;
+        Some(movie)
+    }
+
+    pub(crate) fn checksum_if_due(&mut self) {
+        let due = self
+            .recording
+            .as_ref()
+            .is_some_and(|movie| self.cycles.is_multiple_of(movie.checksum_interval));
+        if due {
+            let checksum = self.state_checksum();
+            if let Some(movie) = self.recording.as_mut() {
+                movie.checksums.push(checksum);
+            }
+        }
+    }
+
+    /// The checksum of a save state, without the random number generator
+    fn state_checksum(&self) -> u32 {
+        let mut state = alloc::vec![0; STATE_SIZE];
+        let _ = self.write_state(&mut state, 0);
+        let mut checksum = [0; CHECKSUM_SIZE];
+        checksum.copy_from_slice(&state[STATE_SIZE - CHECKSUM_SIZE..]);
+        u32::from_le_bytes(checksum)
+    }
+}
+
+fn quirk_flags(quirks: &Quirks) -> u8 {
+    [
+        quirks.shift_uses_vy,
+        quirks.vf_reset,
+        quirks.clip_sprites,
+        quirks.jump_uses_vx,
+        quirks.add_to_i_sets_vf,
+        quirks.display_wait,
+        quirks.wait_for_release,
+    ]
+    .iter()
+    .enumerate()
+    .fold(0, |flags, (bit, &set)| flags | (set as u8) << bit)
+}
+
+/// LEB128, seven bits per byte with the top bit marking that more follow
+fn write_varint(bytes: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        bytes.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    bytes.push(value as u8);
+}
+
+/// Reads from a movie whose checksum was already checked, running out of
+/// bytes means it was written wrongly
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take<const N: usize>(&mut self) -> Result<[u8; N], MovieError> {
+        if self.bytes.len() < N {
+            return Err(MovieError::Corrupt);
+        }
+        let (taken, rest) = self.bytes.split_at(N);
+        self.bytes = rest;
+        let mut array = [0; N];
+        array.copy_from_slice(taken);
+        Ok(array)
+    }
+
+    fn u8(&mut self) -> Result<u8, MovieError> {
+        Ok(self.take::<1>()?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, MovieError> {
+        Ok(u32::from_le_bytes(self.take()?))
+    }
+
+    fn u64(&mut self) -> Result<u64, MovieError> {
+        Ok(u64::from_le_bytes(self.take()?))
+    }
+
+    fn varint(&mut self) -> Result<u64, MovieError> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.u8()?;
+            value |= ((byte & 0x7F) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(MovieError::Corrupt)
+    }
+}
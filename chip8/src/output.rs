@@ -1,69 +1,665 @@
-use crate::display::{Buffer, HEIGHT, WIDTH};
+//! Streams the buffer as pixels for a display panel.
+//!
+//! A `Panel` describes the panel's size, how the buffer is scaled, placed and
+//! turned on it, and the colours. `OutputData` yields every pixel of the
+//! panel and `OutputRegion` only the pixels covering part of the buffer, both
+//! in the order the panel is written, row by row.
+//!
+//! The pixels come from a `Screen`, the display itself or a layer over it
+//! like `flicker::Flicker`. On the way to the panel, a `Filter` can smooth
+//! the edges of the pixels and an `Effect` can draw lines between them.
+
+use core::fmt;
+use core::str::FromStr;
+
+use crate::display::{Buffer, Display, Region, HEIGHT, LORES_WIDTH, WIDTH};
+
+/// Size of the ILI9341 panel of the embedded frontend, the default panel
 pub const DISPLAY_HEIGHT: u32 = 240;
 pub const DISPLAY_WIDTH: u32 = 320;
-const SCALE_X: u32 = DISPLAY_WIDTH as u32 / WIDTH as u32;
-const SCALE_Y: u32 = DISPLAY_HEIGHT as u32 / HEIGHT as u32;
-// Maybe use a consisten scale?
-//const SCALE: u16 = if SCALE_Y < SCALE_X {SCALE_Y} else {SCALE_X};
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Color {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+impl Color {
+    pub const BLACK: Color = Color::rgb(0x00, 0x00, 0x00);
+    pub const WHITE: Color = Color::rgb(0xFF, 0xFF, 0xFF);
+
+    pub const fn rgb(r: u8, g: u8, b: u8) -> Color {
+        Color { r, g, b }
+    }
+
+    /// At half the brightness
+    pub fn dim(self) -> Color {
+        Color::rgb(self.r / 2, self.g / 2, self.b / 2)
+    }
+
+    /// `level` 255ths of the way from this colour to `other`
+    pub fn mix(self, other: Color, level: u8) -> Color {
+        let mix = |from: u8, to: u8| {
+            (from as i32 + (to as i32 - from as i32) * level as i32 / 0xFF) as u8
+        };
+        Color::rgb(
+            mix(self.r, other.r),
+            mix(self.g, other.g),
+            mix(self.b, other.b),
+        )
+    }
+
+    pub fn to_pixel(self, format: PixelFormat) -> u32 {
+        let (r, g, b) = (self.r as u32, self.g as u32, self.b as u32);
+        match format {
+            PixelFormat::Rgb565 => (r >> 3) << 11 | (g >> 2) << 5 | b >> 3,
+            PixelFormat::Rgb888 => r << 16 | g << 8 | b,
+        }
+    }
+}
+
+/// A buffer pixel as the output draws it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Shade {
+    /// Index into the palette, as `Buffer::pixel` returns it
+    pub color: u8,
+    /// How far the pixel is lit from the background colour to `color`, in
+    /// 255ths
+    pub level: u8,
+}
+
+impl Shade {
+    pub const BACKGROUND: Shade = Shade {
+        color: 0,
+        level: 0xFF,
+    };
+}
+
+/// What the output draws
+pub trait Screen {
+    fn shade(&self, x: usize, y: usize) -> Shade;
+
+    /// Filters and effects work on the pixels of the current resolution
+    fn is_hires(&self) -> bool;
+}
+
+impl Screen for Display {
+    fn shade(&self, x: usize, y: usize) -> Shade {
+        self.buffer().shade(x, y)
+    }
+
+    fn is_hires(&self) -> bool {
+        Display::is_hires(self)
+    }
+}
+
+/// A bare buffer is drawn at the high resolution
+impl Screen for Buffer {
+    fn shade(&self, x: usize, y: usize) -> Shade {
+        Shade {
+            color: self.pixel(x, y),
+            level: 0xFF,
+        }
+    }
+
+    fn is_hires(&self) -> bool {
+        true
+    }
+}
+
+/// How the pixels are packed into the `u32`s the outputs yield
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PixelFormat {
+    /// 16 bits, in the low half
+    Rgb565,
+    /// 24 bits, red in the highest byte of the three
+    Rgb888,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scaling {
+    /// The same whole number of panel pixels per pixel of the current
+    /// resolution across and down, falls back to `Aspect` on panels smaller
+    /// than the screen
+    Integer,
+    /// Whole numbers of panel pixels per pixel of the current resolution, as
+    /// many as fit across and down separately
+    IntegerPerAxis,
+    /// As large as fits while keeping the pixels square
+    Aspect,
+    /// Fills the whole panel
+    Stretch,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Placement {
+    TopLeft,
+    /// Centred, with borders on the sides the image doesn't reach
+    Center,
+}
+
+/// Clockwise turn of the image on the panel, quarter turns let a landscape
+/// image fill a portrait panel
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rotation {
+    None,
+    Quarter,
+    Half,
+    ThreeQuarters,
+}
+
+/// Pixel art upscalers, which round off diagonal edges. They work on the
+/// pixels of the current resolution and need the panel to show each of them
+/// at least two or three panel pixels across to have an effect.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Filter {
+    /// Every pixel is a solid block
+    None,
+    /// Scale2x, also known as EPX, splits every pixel in 2x2
+    Scale2x,
+    /// Scale3x splits every pixel in 3x3
+    Scale3x,
+}
+
+impl Filter {
+    pub const ALL: [Filter; 3] = [Filter::None, Filter::Scale2x, Filter::Scale3x];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Filter::None => "none",
+            Filter::Scale2x => "scale2x",
+            Filter::Scale3x => "scale3x",
+        }
+    }
+
+    fn factor(self) -> usize {
+        match self {
+            Filter::None => 1,
+            Filter::Scale2x => 2,
+            Filter::Scale3x => 3,
+        }
+    }
+}
+
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnknownFilter;
+
+impl fmt::Display for UnknownFilter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("unknown filter, expected one of none, scale2x or scale3x")
+    }
+}
+
+impl FromStr for Filter {
+    type Err = UnknownFilter;
+
+    fn from_str(name: &str) -> Result<Filter, UnknownFilter> {
+        Filter::ALL
+            .iter()
+            .copied()
+            .find(|filter| filter.name().eq_ignore_ascii_case(name))
+            .ok_or(UnknownFilter)
+    }
+}
+
+/// Lines between the pixels of the current resolution, drawn at half
+/// brightness over the last panel row or column of each pixel. Pixels only
+/// one panel pixel large have no room for them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Effect {
+    None,
+    /// Between the rows, like the gaps on a CRT
+    Scanlines,
+    /// Between the rows and the columns, like an LCD
+    Grid,
+}
+
+impl Effect {
+    pub const ALL: [Effect; 3] = [Effect::None, Effect::Scanlines, Effect::Grid];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Effect::None => "none",
+            Effect::Scanlines => "scanlines",
+            Effect::Grid => "grid",
+        }
+    }
+}
+
+impl fmt::Display for Effect {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnknownEffect;
+
+impl fmt::Display for UnknownEffect {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("unknown effect, expected one of none, scanlines or grid")
+    }
+}
+
+impl FromStr for Effect {
+    type Err = UnknownEffect;
+
+    fn from_str(name: &str) -> Result<Effect, UnknownEffect> {
+        Effect::ALL
+            .iter()
+            .copied()
+            .find(|effect| effect.name().eq_ignore_ascii_case(name))
+            .ok_or(UnknownEffect)
+    }
+}
+
+/// The panel the buffer is drawn on.
+///
+/// The default is the 320x240 RGB565 panel of the embedded frontend, with
+/// the buffer scaled 2x3 in the top left corner.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Panel {
+    pub width: u32,
+    pub height: u32,
+    pub scaling: Scaling,
+    pub placement: Placement,
+    pub rotation: Rotation,
+    /// Flips the image left to right after turning it
+    pub mirror: bool,
+    pub format: PixelFormat,
+    /// Indexed by the planes a pixel is set in, the first is the background
+    /// and the second the foreground of games that only use one plane
+    pub colors: [Color; 4],
+    /// The part of the panel outside the image
+    pub border: Color,
+    pub filter: Filter,
+    pub effect: Effect,
+}
+
+impl Default for Panel {
+    fn default() -> Panel {
+        Panel {
+            width: DISPLAY_WIDTH,
+            height: DISPLAY_HEIGHT,
+            scaling: Scaling::IntegerPerAxis,
+            placement: Placement::TopLeft,
+            rotation: Rotation::None,
+            mirror: false,
+            format: PixelFormat::Rgb565,
+            // Two greys for the pixels set in the second XO-CHIP plane
+            colors: [
+                Color::BLACK,
+                Color::WHITE,
+                Color::rgb(0xAA, 0xAA, 0xAA),
+                Color::rgb(0x55, 0x55, 0x55),
+            ],
+            border: Color::BLACK,
+            filter: Filter::None,
+            effect: Effect::None,
+        }
+    }
+}
+
+/// The position of a part of a pixel, as split up by the filter, and its
+/// colour index
+type Part = (usize, usize, Shade);
+
+/// Where the buffer ends up on a panel. The image is placed on the panel as
+/// if it was upright, turning and mirroring come after.
+#[derive(Debug, Clone, Copy)]
+struct Layout {
+    width: u32,
+    height: u32,
+    rotation: Rotation,
+    mirror: bool,
+    image: (u32, u32, u32, u32),
+    /// Buffer pixels per pixel of the current resolution, across and down
+    cell: usize,
+    filter: Filter,
+    effect: Effect,
+    palette: [Color; 4],
+    format: PixelFormat,
+    colors: [u32; 4],
+    dim_colors: [u32; 4],
+    border: u32,
+}
+
+impl Layout {
+    fn new(panel: &Panel, cell: usize) -> Layout {
+        let (width, height) = (panel.width.max(1), panel.height.max(1));
+        let (upright_width, upright_height) = match panel.rotation {
+            Rotation::Quarter | Rotation::ThreeQuarters => (height, width),
+            Rotation::None | Rotation::Half => (width, height),
+        };
+        // Whole numbers of panel pixels per pixel of the current resolution,
+        // so lores games aren't shrunk to fit the hires buffer
+        let (screen_width, screen_height) = ((WIDTH / cell) as u32, (HEIGHT / cell) as u32);
+        let aspect = || {
+            let image_width = upright_width.min(upright_height * screen_width / screen_height);
+            (image_width, image_width * screen_height / screen_width)
+        };
+        let (image_width, image_height) = match panel.scaling {
+            Scaling::Integer => {
+                let scale = (upright_width / screen_width).min(upright_height / screen_height);
+                if scale == 0 {
+                    aspect()
+                } else {
+                    (screen_width * scale, screen_height * scale)
+                }
+            }
+            Scaling::IntegerPerAxis => (
+                screen_width * (upright_width / screen_width).max(1),
+                screen_height * (upright_height / screen_height).max(1),
+            ),
+            Scaling::Aspect => aspect(),
+            Scaling::Stretch => (upright_width, upright_height),
+        };
+        let (x, y) = match panel.placement {
+            Placement::TopLeft => (0, 0),
+            Placement::Center => (
+                upright_width.saturating_sub(image_width) / 2,
+                upright_height.saturating_sub(image_height) / 2,
+            ),
+        };
+        let format = panel.format;
+        Layout {
+            width,
+            height,
+            rotation: panel.rotation,
+            mirror: panel.mirror,
+            image: (x, y, image_width.max(1), image_height.max(1)),
+            cell,
+            filter: panel.filter,
+            effect: panel.effect,
+            palette: panel.colors,
+            format,
+            colors: panel.colors.map(|color| color.to_pixel(format)),
+            dim_colors: panel.colors.map(|color| color.dim().to_pixel(format)),
+            border: panel.border.to_pixel(format),
+        }
+    }
+
+    /// The position of a panel pixel on the upright image
+    fn upright(&self, x: u32, y: u32) -> (u32, u32) {
+        let x = if self.mirror { self.width - 1 - x } else { x };
+        match self.rotation {
+            Rotation::None => (x, y),
+            Rotation::Quarter => (y, self.width - 1 - x),
+            Rotation::Half => (self.width - 1 - x, self.height - 1 - y),
+            Rotation::ThreeQuarters => (self.height - 1 - y, x),
+        }
+    }
+
+    /// The position of a pixel of the upright image on the panel
+    fn turned(&self, x: u32, y: u32) -> (u32, u32) {
+        let (x, y) = match self.rotation {
+            Rotation::None => (x, y),
+            Rotation::Quarter => (self.width - 1 - y, x),
+            Rotation::Half => (self.width - 1 - x, self.height - 1 - y),
+            Rotation::ThreeQuarters => (y, self.height - 1 - x),
+        };
+        let x = if self.mirror { self.width - 1 - x } else { x };
+        (x, y)
+    }
+
+    /// The colour of a panel pixel. Neighbouring panel pixels mostly show
+    /// the same part of a pixel, `last` keeps the part last looked at and
+    /// its colour index.
+    fn pixel(&self, screen: &dyn Screen, x: u32, y: u32, last: &mut Option<Part>) -> u32 {
+        let (x, y) = self.upright(x, y);
+        let (image_x, image_y, image_width, image_height) = self.image;
+        if x < image_x || y < image_y {
+            return self.border;
+        }
+        let (x, y) = (x - image_x, y - image_y);
+        let (width, height) = (WIDTH / self.cell, HEIGHT / self.cell);
+        // In pixels of the current resolution, split up by the filter
+        let factor = self.filter.factor();
+        let split_x = (x * (width * factor) as u32 / image_width) as usize;
+        let split_y = (y * (height * factor) as u32 / image_height) as usize;
+        let (pixel_x, pixel_y) = (split_x / factor, split_y / factor);
+        if pixel_x >= width || pixel_y >= height {
+            return self.border;
+        }
+
+        let shade = match *last {
+            Some((x, y, shade)) if (x, y) == (split_x, split_y) => shade,
+            _ => {
+                let at = |dx: isize, dy: isize| {
+                    let x = (pixel_x as isize + dx).clamp(0, width as isize - 1) as usize;
+                    let y = (pixel_y as isize + dy).clamp(0, height as isize - 1) as usize;
+                    screen.shade(x * self.cell, y * self.cell)
+                };
+                let (part_x, part_y) = (split_x % factor, split_y % factor);
+                let shade = match self.filter {
+                    Filter::None => at(0, 0),
+                    Filter::Scale2x => scale2x(at, part_x + 2 * part_y),
+                    Filter::Scale3x => scale3x(at, part_x + 3 * part_y),
+                };
+                *last = Some((split_x, split_y, shade));
+                shade
+            }
+        };
+
+        // Whether the next panel pixel shows another pixel
+        let ends = |position: u32, size: usize, image_size: u32, pixel: usize| {
+            image_size as usize >= 2 * size
+                && ((position + 1) * size as u32 / image_size) as usize != pixel
+        };
+        let on_line = match self.effect {
+            Effect::None => false,
+            Effect::Scanlines => ends(y, height, image_height, pixel_y),
+            Effect::Grid => {
+                ends(y, height, image_height, pixel_y) || ends(x, width, image_width, pixel_x)
+            }
+        };
+        let color = shade.color as usize;
+        if shade.level == 0xFF {
+            return if on_line {
+                self.dim_colors[color]
+            } else {
+                self.colors[color]
+            };
+        }
+        let mixed = self.palette[0].mix(self.palette[color], shade.level);
+        let mixed = if on_line { mixed.dim() } else { mixed };
+        mixed.to_pixel(self.format)
+    }
+
+    /// The corners of the panel pixels showing a region, both inclusive.
+    /// `None` when the image is shrunk so far that none do.
+    fn window(&self, region: Region) -> Option<(u32, u32, u32, u32)> {
+        // The image changes size with the resolution, which marks the whole
+        // buffer, so the border is redrawn along with it
+        if region.width >= WIDTH && region.height >= HEIGHT {
+            return Some((0, 0, self.width - 1, self.height - 1));
+        }
+        let (image_x, image_y, image_width, image_height) = self.image;
+        // The first upright pixel showing buffer pixel `pixel` or later
+        let start = |pixel: usize, size: usize, image_size: u32| {
+            (pixel as u64 * image_size as u64).div_ceil(size as u64) as u32
+        };
+        let (upright_width, upright_height) = self.upright_size();
+        let x0 = image_x + start(region.x, WIDTH, image_width);
+        let y0 = image_y + start(region.y, HEIGHT, image_height);
+        let x1 = (image_x + start(region.x + region.width, WIDTH, image_width)).min(upright_width);
+        let y1 =
+            (image_y + start(region.y + region.height, HEIGHT, image_height)).min(upright_height);
+        if x1 <= x0 || y1 <= y0 {
+            return None;
+        }
+        let (ax, ay) = self.turned(x0, y0);
+        let (bx, by) = self.turned(x1 - 1, y1 - 1);
+        Some((ax.min(bx), ay.min(by), ax.max(bx), ay.max(by)))
+    }
+
+    /// The size of the panel with the image turned upright
+    fn upright_size(&self) -> (u32, u32) {
+        match self.rotation {
+            Rotation::Quarter | Rotation::ThreeQuarters => (self.height, self.width),
+            Rotation::None | Rotation::Half => (self.width, self.height),
+        }
+    }
+}
+
+/// Every pixel of the panel, the border included
 pub struct OutputData<'a> {
-    buffer: &'a Buffer,
-    buffer_pos: (u32, u32),
-    count: (u32, u32),
+    screen: &'a dyn Screen,
+    layout: Layout,
+    last: Option<Part>,
+    pos: (u32, u32),
 }
 
 impl<'a> OutputData<'a> {
+    /// Output for the default panel
     pub fn new(buffer: &'a Buffer) -> OutputData<'a> {
         OutputData {
-            buffer,
-            buffer_pos: (0, 0),
-            count: (0, 0),
+            screen: buffer,
+            layout: Layout::new(&Panel::default(), 1),
+            last: None,
+            pos: (0, 0),
+        }
+    }
+
+    /// Filters and effects work on the pixels of the screen's resolution
+    pub fn with_panel(screen: &'a dyn Screen, panel: &Panel) -> OutputData<'a> {
+        OutputData {
+            screen,
+            layout: Layout::new(panel, cell(screen)),
+            last: None,
+            pos: (0, 0),
         }
     }
 }
+
 impl<'a> Iterator for OutputData<'a> {
-    type Item = u16;
+    type Item = u32;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.count.0 < SCALE_X {
-            self.count.0 += 1;
-        } else if self.buffer_pos.0 + 1 < WIDTH as u32 {
-            // Begin next x buffer pixel
-            self.buffer_pos.0 += 1;
-            self.count.0 = 1;
-        } else {
-            // We're in the x overflow area
-            let x_pos = self.buffer_pos.0 * SCALE_X + self.count.0;
-            if x_pos + 1 < DISPLAY_HEIGHT {
-                self.count.0 += 1;
-                return Some(0x0000);
-            }
-            // Next line
-            else if self.count.1 + 1 < SCALE_Y {
-                self.buffer_pos.0 = 0;
-                self.count.0 = 1;
-                self.count.1 += 1;
-            } else if self.buffer_pos.1 + 1 < HEIGHT as u32 {
-                // Begin next y buffer pixel
-                self.buffer_pos.0 = 0;
-                self.count.0 = 1;
-                self.count.1 = 0;
-                self.buffer_pos.1 += 1;
-            } else {
-                // We're in the y overflow area;
-                let overflow_pixels = (DISPLAY_HEIGHT - HEIGHT as u32 * SCALE_Y) * DISPLAY_WIDTH;
-                if self.count.1 + 1 < overflow_pixels {
-                    self.count.1 += 1;
-                    return Some(0x0000);
-                } else {
-                    return None;
-                }
-            }
+        let (x, y) = self.pos;
+        if y >= self.layout.height {
+            return None;
         }
-        if self.buffer[self.buffer_pos.1 as usize][self.buffer_pos.0 as usize] {
-            Some(0xFFFF)
+        self.pos = if x + 1 < self.layout.width {
+            (x + 1, y)
         } else {
-            Some(0x0000)
+            (0, y + 1)
+        };
+        Some(self.layout.pixel(self.screen, x, y, &mut self.last))
+    }
+}
+
+/// The pixels of part of the buffer, laid out like `OutputData`, for
+/// redrawing only what changed
+pub struct OutputRegion<'a> {
+    screen: &'a dyn Screen,
+    layout: Layout,
+    window: Option<(u32, u32, u32, u32)>,
+    last: Option<Part>,
+    pos: (u32, u32),
+}
+
+impl<'a> OutputRegion<'a> {
+    /// Output for the default panel
+    pub fn new(buffer: &'a Buffer, region: Region) -> OutputRegion<'a> {
+        let layout = Layout::new(&Panel::default(), 1);
+        OutputRegion {
+            screen: buffer,
+            layout,
+            window: layout.window(region),
+            last: None,
+            pos: (0, 0),
         }
     }
+
+    /// Filters look at the neighbours of every pixel, so the pixels around
+    /// the region are included when one is used
+    pub fn with_panel(screen: &'a dyn Screen, region: Region, panel: &Panel) -> OutputRegion<'a> {
+        let layout = Layout::new(panel, cell(screen));
+        let region = match panel.filter {
+            Filter::None => region,
+            Filter::Scale2x | Filter::Scale3x => region.grow(layout.cell),
+        };
+        OutputRegion {
+            screen,
+            layout,
+            window: layout.window(region),
+            last: None,
+            pos: (0, 0),
+        }
+    }
+
+    /// The corners of the region on the panel, both inclusive. `None` when
+    /// no panel pixel shows the region.
+    pub fn window(&self) -> Option<(u16, u16, u16, u16)> {
+        self.window
+            .map(|(x0, y0, x1, y1)| (x0 as u16, y0 as u16, x1 as u16, y1 as u16))
+    }
+}
+
+impl<'a> Iterator for OutputRegion<'a> {
+    type Item = u32;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (x0, y0, x1, y1) = self.window?;
+        let (x, y) = self.pos;
+        if y > y1 - y0 {
+            return None;
+        }
+        self.pos = if x0 + x < x1 { (x + 1, y) } else { (0, y + 1) };
+        Some(
+            self.layout
+                .pixel(self.screen, x0 + x, y0 + y, &mut self.last),
+        )
+    }
+}
+
+/// Buffer pixels per pixel of the screen's resolution
+fn cell(screen: &dyn Screen) -> usize {
+    if screen.is_hires() {
+        1
+    } else {
+        WIDTH / LORES_WIDTH
+    }
+}
+
+/// Part `part` of the 2x2 a pixel is split into, counted row by row. `at`
+/// gives the pixels relative to the one split.
+fn scale2x<F: Fn(isize, isize) -> Shade>(at: F, part: usize) -> Shade {
+    let (p, a, b, c, d) = (at(0, 0), at(0, -1), at(1, 0), at(-1, 0), at(0, 1));
+    match part {
+        0 if c == a && c != d && a != b => a,
+        1 if a == b && a != c && b != d => b,
+        2 if d == c && d != b && c != a => c,
+        3 if b == d && b != a && d != c => d,
+        _ => p,
+    }
+}
+
+/// Part `part` of the 3x3 a pixel is split into, like `scale2x`
+fn scale3x<F: Fn(isize, isize) -> Shade>(at: F, part: usize) -> Shade {
+    let e = at(0, 0);
+    let (b, d, f, h) = (at(0, -1), at(-1, 0), at(1, 0), at(0, 1));
+    if b == h || d == f {
+        return e;
+    }
+    let (a, c, g, i) = (at(-1, -1), at(1, -1), at(-1, 1), at(1, 1));
+    match part {
+        0 if d == b => d,
+        1 if (d == b && e != c) || (b == f && e != a) => b,
+        2 if b == f => f,
+        3 if (d == b && e != g) || (d == h && e != a) => d,
+        5 if (b == f && e != i) || (h == f && e != c) => f,
+        6 if d == h => d,
+        7 if (d == h && e != i) || (h == f && e != g) => h,
+        8 if h == f => f,
+        _ => e,
+    }
 }
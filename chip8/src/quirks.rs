@@ -0,0 +1,171 @@
+use core::fmt;
+use core::str::FromStr;
+
+use crate::Mode;
+
+/// How `StoreRegisters`/`LoadRegisters` leave the I register behind
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IndexIncrement {
+    /// I is left untouched
+    Unchanged,
+    /// I is incremented by X (CHIP-48)
+    X,
+    /// I points past the last register accessed (COSMAC VIP)
+    XPlusOne,
+}
+
+/// Behaviour of the opcodes the various interpreters disagree on.
+///
+/// The default is the behaviour this crate always had, the presets match
+/// the interpreters most ROMs were written for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Quirks {
+    /// `8XY6`/`8XYE` shift VY into VX instead of shifting VX in place
+    pub shift_uses_vy: bool,
+    pub load_store_increment: IndexIncrement,
+    /// `8XY1`/`8XY2`/`8XY3` reset VF to zero
+    pub vf_reset: bool,
+    /// Sprites are clipped at the screen edges instead of wrapping around
+    pub clip_sprites: bool,
+    /// `BXNN` jumps to XNN + VX instead of `BNNN` jumping to NNN + V0
+    pub jump_uses_vx: bool,
+    /// `FX1E` sets VF when I overflows past 0xFFF
+    pub add_to_i_sets_vf: bool,
+    /// `DXYN` waits for the next timer tick, so at most one sprite is drawn per frame
+    pub display_wait: bool,
+    /// `FX0A` waits for the key to be released again, instead of finishing
+    /// as soon as it's pressed
+    pub wait_for_release: bool,
+}
+
+impl Quirks {
+    pub const COSMAC_VIP: Quirks = Quirks {
+        shift_uses_vy: true,
+        load_store_increment: IndexIncrement::XPlusOne,
+        vf_reset: true,
+        clip_sprites: true,
+        jump_uses_vx: false,
+        add_to_i_sets_vf: false,
+        display_wait: true,
+        wait_for_release: true,
+    };
+
+    pub const CHIP48: Quirks = Quirks {
+        shift_uses_vy: false,
+        load_store_increment: IndexIncrement::X,
+        vf_reset: false,
+        clip_sprites: true,
+        jump_uses_vx: true,
+        add_to_i_sets_vf: false,
+        display_wait: false,
+        wait_for_release: false,
+    };
+
+    pub const SCHIP: Quirks = Quirks {
+        shift_uses_vy: false,
+        load_store_increment: IndexIncrement::Unchanged,
+        vf_reset: false,
+        clip_sprites: true,
+        jump_uses_vx: true,
+        add_to_i_sets_vf: false,
+        display_wait: false,
+        wait_for_release: false,
+    };
+
+    pub const OCTO: Quirks = Quirks {
+        shift_uses_vy: true,
+        load_store_increment: IndexIncrement::XPlusOne,
+        vf_reset: false,
+        clip_sprites: false,
+        jump_uses_vx: false,
+        add_to_i_sets_vf: false,
+        display_wait: false,
+        wait_for_release: true,
+    };
+}
+
+impl Default for Quirks {
+    fn default() -> Quirks {
+        Quirks {
+            shift_uses_vy: false,
+            load_store_increment: IndexIncrement::Unchanged,
+            vf_reset: false,
+            clip_sprites: false,
+            jump_uses_vx: false,
+            add_to_i_sets_vf: false,
+            display_wait: false,
+            wait_for_release: false,
+        }
+    }
+}
+
+/// Named platforms, mostly useful for picking quirks from a frontend
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Preset {
+    CosmacVip,
+    Chip48,
+    SuperChip,
+    Octo,
+}
+
+impl Preset {
+    pub const ALL: [Preset; 4] = [
+        Preset::CosmacVip,
+        Preset::Chip48,
+        Preset::SuperChip,
+        Preset::Octo,
+    ];
+
+    pub fn quirks(self) -> Quirks {
+        match self {
+            Preset::CosmacVip => Quirks::COSMAC_VIP,
+            Preset::Chip48 => Quirks::CHIP48,
+            Preset::SuperChip => Quirks::SCHIP,
+            Preset::Octo => Quirks::OCTO,
+        }
+    }
+
+    pub fn mode(self) -> Mode {
+        match self {
+            Preset::CosmacVip | Preset::Chip48 => Mode::Chip8,
+            Preset::SuperChip => Mode::SuperChip,
+            Preset::Octo => Mode::XoChip,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Preset::CosmacVip => "vip",
+            Preset::Chip48 => "chip48",
+            Preset::SuperChip => "schip",
+            Preset::Octo => "octo",
+        }
+    }
+}
+
+impl fmt::Display for Preset {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnknownPreset;
+
+impl fmt::Display for UnknownPreset {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("unknown preset, expected one of vip, chip48, schip or octo")
+    }
+}
+
+impl FromStr for Preset {
+    type Err = UnknownPreset;
+
+    fn from_str(name: &str) -> Result<Preset, UnknownPreset> {
+        Preset::ALL
+            .iter()
+            .copied()
+            .find(|preset| preset.name().eq_ignore_ascii_case(name))
+            .ok_or(UnknownPreset)
+    }
+}
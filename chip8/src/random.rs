@@ -0,0 +1,52 @@
+//! Seedable random number generators for `Chip8`.
+//!
+//! `XorShift` keeps its whole state in a `u64` through `RandomState`, so the
+//! same seed draws the same numbers in every frontend, and save states and
+//! movies can capture it.
+//!
+//! There's no emulation of the COSMAC VIP interpreter's own CXNN routine. It
+//! draws its numbers from the bytes of the interpreter itself, which isn't
+//! part of this crate, so the sequence can't be reproduced without it.
+
+use random_trait::Random;
+
+use crate::state::RandomState;
+
+/// Marsaglia's xorshift64, fast and good enough for games
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct XorShift {
+    state: u64,
+}
+
+impl XorShift {
+    pub fn new(seed: u64) -> XorShift {
+        // Xorshift never leaves the all zero state
+        XorShift { state: seed | 1 }
+    }
+}
+
+impl Random for XorShift {
+    type Error = ();
+    fn try_fill_bytes(&mut self, buf: &mut [u8]) -> Result<(), Self::Error> {
+        for e in buf.iter_mut() {
+            // Taken from https://en.wikipedia.org/wiki/Xorshift
+            let mut x = self.state;
+            x ^= x << 13;
+            x ^= x >> 7;
+            x ^= x << 17;
+            self.state = x;
+            *e = x as u8;
+        }
+        Ok(())
+    }
+}
+
+impl RandomState for XorShift {
+    fn state(&self) -> u64 {
+        self.state
+    }
+
+    fn set_state(&mut self, state: u64) {
+        self.state = state.max(1);
+    }
+}
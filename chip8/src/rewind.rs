@@ -0,0 +1,266 @@
+//! Rewinding and stepping backwards through a bounded history.
+//!
+//! Apart from its random number generator the machine is deterministic, so
+//! the history only needs occasional checkpoints and a log of everything that
+//! came from outside: key presses, timer ticks and the random numbers drawn.
+//! Going back restores the closest checkpoint and replays the log up to the
+//! requested cycle.
+
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use random_trait::Random;
+
+use crate::movie;
+use crate::state::STATE_SIZE;
+use crate::Chip8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Input {
+    KeyPress(u8),
+    KeyRelease(u8),
+    TimerTick,
+    /// The output of the generator for the `Random` run in this cycle
+    Random(u8),
+}
+
+/// An input that arrived after `cycle` cycles had run
+#[derive(Debug, Clone, Copy)]
+struct Event {
+    cycle: u64,
+    input: Input,
+}
+
+/// The state after `cycle` cycles, run length encoded
+struct Checkpoint {
+    cycle: u64,
+    state: Vec<u8>,
+}
+
+pub(crate) struct History {
+    interval: u64,
+    capacity: usize,
+    checkpoints: VecDeque<Checkpoint>,
+    events: VecDeque<Event>,
+    replaying: bool,
+    /// The recorded random number for the cycle being replayed
+    replayed_random: Option<u8>,
+}
+
+impl History {
+    fn record(&mut self, cycle: u64, input: Input) {
+        if !self.replaying {
+            self.events.push_back(Event { cycle, input });
+        }
+    }
+
+    pub(crate) fn random<R: Random>(&mut self, cycle: u64, random: &mut R) -> u8 {
+        if self.replaying {
+            if let Some(value) = self.replayed_random.take() {
+                return value;
+            }
+        }
+        let value = random.get_u8();
+        self.record(cycle, Input::Random(value));
+        value
+    }
+}
+
+impl<RANDOM> Chip8<RANDOM>
+where
+    RANDOM: Random,
+{
+    /// Starts recording a history to rewind through, with a checkpoint every
+    /// `interval` cycles. Once there are `capacity` checkpoints the oldest one
+    /// is dropped, which bounds how far back the machine can go.
+    pub fn enable_rewind(&mut self, interval: u64, capacity: usize) {
+        self.history = Some(History {
+            interval: interval.max(1),
+            capacity: capacity.max(1),
+            checkpoints: VecDeque::new(),
+            events: VecDeque::new(),
+            replaying: false,
+            replayed_random: None,
+        });
+        self.checkpoint();
+    }
+
+    pub fn disable_rewind(&mut self) {
+        self.history = None;
+    }
+
+    /// The earliest cycle the machine can be rewound to
+    pub fn rewind_limit(&self) -> Option<u64> {
+        self.history
+            .as_ref()
+            .and_then(|history| history.checkpoints.front())
+            .map(|checkpoint| checkpoint.cycle)
+    }
+
+    /// Undoes the last cycle, returning whether there was one to undo
+    pub fn step_back(&mut self) -> bool {
+        self.cycles > 0 && self.rewind_to(self.cycles - 1)
+    }
+
+    /// Goes back by up to `cycles` cycles, stopping at the rewind limit.
+    /// Returns whether the machine moved at all.
+    pub fn rewind(&mut self, cycles: u64) -> bool {
+        match self.rewind_limit() {
+            Some(limit) if limit < self.cycles => {
+                self.rewind_to(self.cycles.saturating_sub(cycles).max(limit))
+            }
+            _ => false,
+        }
+    }
+
+    /// Puts the machine into the state it had after `cycle` cycles, before
+    /// any input arrived. Everything recorded after that is forgotten.
+    /// Refused while a movie is being recorded.
+    pub fn rewind_to(&mut self, cycle: u64) -> bool {
+        if cycle > self.cycles || self.recording.is_some() {
+            return false;
+        }
+        let history = match self.history.as_mut() {
+            Some(history) => history,
+            None => return false,
+        };
+        let checkpoint = match history.checkpoints.iter().rev().find(|c| c.cycle <= cycle) {
+            Some(checkpoint) => checkpoint,
+            None => return false,
+        };
+        let start = checkpoint.cycle;
+        let mut state = alloc::vec![0; STATE_SIZE];
+        decompress(&checkpoint.state, &mut state);
+        let mut next = history.events.partition_point(|event| event.cycle < start);
+        history.replaying = true;
+        // Checkpoints are written by us, so they're always valid
+        let _ = self.read_state(&state);
+        self.cycles = start;
+
+        while self.cycles < cycle {
+            while let Some(event) = self.event(next).filter(|e| e.cycle == self.cycles) {
+                next += 1;
+                match event.input {
+                    Input::KeyPress(key) => self.handle_key_press(key),
+                    Input::KeyRelease(key) => self.handle_key_release(key),
+                    Input::TimerTick => self.timer_tick(),
+                    Input::Random(value) => {
+                        if let Some(history) = self.history.as_mut() {
+                            history.replayed_random = Some(value);
+                        }
+                    }
+                }
+            }
+            // The cycle succeeded the first time around, so it does again
+            if self.execute_cycle().is_err() {
+                break;
+            }
+            self.cycles += 1;
+        }
+
+        let cycles = self.cycles;
+        if let Some(history) = self.history.as_mut() {
+            history.replaying = false;
+            history.replayed_random = None;
+            let kept = history.events.partition_point(|event| event.cycle < cycles);
+            history.events.truncate(kept);
+            while history
+                .checkpoints
+                .back()
+                .is_some_and(|checkpoint| checkpoint.cycle > cycles)
+            {
+                history.checkpoints.pop_back();
+            }
+        }
+        true
+    }
+
+    /// Drops the history, e.g. because a save state was loaded
+    pub(crate) fn reset_history(&mut self) {
+        if let Some(history) = self.history.as_mut() {
+            history.checkpoints.clear();
+            history.events.clear();
+            self.checkpoint();
+        }
+    }
+
+    pub(crate) fn record(&mut self, input: Input) {
+        let cycles = self.cycles;
+        if let Some(history) = self.history.as_mut() {
+            history.record(cycles, input);
+        }
+        // Movies get the same inputs, the random numbers follow from the seed
+        if let Some(movie) = self.recording.as_mut() {
+            let input = match input {
+                Input::KeyPress(key) => movie::Input::KeyPress(key),
+                Input::KeyRelease(key) => movie::Input::KeyRelease(key),
+                Input::TimerTick => movie::Input::TimerTick,
+                Input::Random(_) => return,
+            };
+            movie.record(cycles, input);
+        }
+    }
+
+    pub(crate) fn checkpoint_if_due(&mut self) {
+        let due = self
+            .history
+            .as_ref()
+            .is_some_and(|history| self.cycles.is_multiple_of(history.interval));
+        if due {
+            self.checkpoint();
+        }
+    }
+
+    fn checkpoint(&mut self) {
+        let mut state = alloc::vec![0; STATE_SIZE];
+        // The random number generator isn't rewound, its outputs are replayed instead
+        let _ = self.write_state(&mut state, 0);
+        let cycle = self.cycles;
+        if let Some(history) = self.history.as_mut() {
+            if history.checkpoints.back().is_some_and(|c| c.cycle == cycle) {
+                history.checkpoints.pop_back();
+            }
+            if history.checkpoints.len() == history.capacity {
+                history.checkpoints.pop_front();
+                if let Some(oldest) = history.checkpoints.front() {
+                    let dropped = history.events.partition_point(|e| e.cycle < oldest.cycle);
+                    history.events.drain(..dropped);
+                }
+            }
+            history.checkpoints.push_back(Checkpoint {
+                cycle,
+                state: compress(&state),
+            });
+        }
+    }
+
+    fn event(&self, index: usize) -> Option<Event> {
+        self.history
+            .as_ref()
+            .and_then(|history| history.events.get(index).copied())
+    }
+}
+
+/// Run length encodes the state as pairs of count and byte, most of the
+/// memory and display is blank
+fn compress(state: &[u8]) -> Vec<u8> {
+    let mut compressed = Vec::new();
+    let mut bytes = state.iter().peekable();
+    while let Some(&byte) = bytes.next() {
+        let mut count = 1u8;
+        while count < u8::MAX && bytes.next_if_eq(&&byte).is_some() {
+            count += 1;
+        }
+        compressed.push(count);
+        compressed.push(byte);
+    }
+    compressed
+}
+
+fn decompress(compressed: &[u8], state: &mut [u8]) {
+    let mut pos = 0;
+    for run in compressed.chunks(2) {
+        let count = run[0] as usize;
+        state[pos..pos + count].fill(run[1]);
+        pos += count;
+    }
+}
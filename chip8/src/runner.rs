@@ -0,0 +1,247 @@
+//! Pacing a machine against the clock.
+//!
+//! A `Runner` turns elapsed time, or a count of frames, into cycles and
+//! timer ticks. It keeps the remainders between calls, so instructions and
+//! timers stay in step however unevenly the frontend calls it.
+
+use core::convert::TryFrom;
+use core::time::Duration;
+
+use random_trait::Random;
+
+use crate::error::Chip8Error;
+use crate::{Chip8, INSTRUCTION_RATE, TIMER_RATE};
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+/// About six frames, anything longer was a stall rather than slow frames
+pub const DEFAULT_MAX_CATCH_UP: Duration = Duration::from_millis(100);
+
+/// How many instructions run between timer ticks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Speed {
+    /// Instructions run at this rate, independent of the timers
+    InstructionsPerSecond(u32),
+    /// This many instructions run before every timer tick, as in Octo
+    InstructionsPerFrame(u32),
+}
+
+impl Default for Speed {
+    fn default() -> Speed {
+        Speed::InstructionsPerSecond(INSTRUCTION_RATE)
+    }
+}
+
+/// What a runner asks the machine to do
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Step {
+    Cycle,
+    TimerTick,
+}
+
+/// How much a call to the runner ran
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Progress {
+    pub cycles: u32,
+    /// Timer ticks, the display is worth redrawing when this isn't zero
+    pub frames: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct Runner {
+    speed: Speed,
+    paused: bool,
+    fast_forward: u32,
+    max_catch_up: Duration,
+    /// Time owed to the machine in nanoseconds, times the rate of whatever
+    /// `speed` counts: instructions or frames
+    owed: u64,
+    /// Progress towards the next timer tick when instructions run at a fixed
+    /// rate, in units of `TIMER_RATE`. It can hold several ticks after frames
+    /// at rates below `TIMER_RATE`.
+    tick_progress: u32,
+}
+
+impl Default for Runner {
+    fn default() -> Runner {
+        Runner::new(Speed::default())
+    }
+}
+
+impl Runner {
+    pub fn new(speed: Speed) -> Runner {
+        let mut runner = Runner {
+            speed,
+            paused: false,
+            fast_forward: 1,
+            max_catch_up: DEFAULT_MAX_CATCH_UP,
+            owed: 0,
+            tick_progress: 0,
+        };
+        runner.set_speed(speed);
+        runner
+    }
+
+    pub fn speed(&self) -> Speed {
+        self.speed
+    }
+
+    /// Changes the speed, time already owed to the machine is dropped
+    pub fn set_speed(&mut self, speed: Speed) {
+        self.speed = match speed {
+            Speed::InstructionsPerSecond(rate) => Speed::InstructionsPerSecond(rate.max(1)),
+            Speed::InstructionsPerFrame(count) => Speed::InstructionsPerFrame(count),
+        };
+        self.owed = 0;
+        self.tick_progress = 0;
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    /// Time passing while paused is dropped, not caught up on later
+    pub fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+        self.owed = 0;
+    }
+
+    pub fn fast_forward(&self) -> u32 {
+        self.fast_forward
+    }
+
+    /// Runs `factor` times faster than normal, 1 is normal speed
+    pub fn set_fast_forward(&mut self, factor: u32) {
+        self.fast_forward = factor.max(1);
+    }
+
+    /// The longest stretch of time a single call catches up on. When the
+    /// frontend falls further behind, say because drawing was slow, the
+    /// rest is dropped instead of running in one burst.
+    pub fn set_max_catch_up(&mut self, max_catch_up: Duration) {
+        self.max_catch_up = max_catch_up;
+    }
+
+    /// Runs the machine for `elapsed` time
+    pub fn advance<RANDOM: Random>(
+        &mut self,
+        chip8: &mut Chip8<RANDOM>,
+        elapsed: Duration,
+    ) -> Result<Progress, Chip8Error> {
+        self.advance_with(elapsed, |step| run_step(chip8, step))
+    }
+
+    /// Like `advance`, letting `run` carry out every step, e.g. to replay a
+    /// movie. The first error is passed on and nothing runs after it.
+    pub fn advance_with<E, F>(&mut self, elapsed: Duration, run: F) -> Result<Progress, E>
+    where
+        F: FnMut(Step) -> Result<(), E>,
+    {
+        if self.paused {
+            return Ok(Progress::default());
+        }
+        let elapsed = nanos(elapsed.min(self.max_catch_up));
+        let rate = match self.speed {
+            Speed::InstructionsPerSecond(rate) => rate,
+            Speed::InstructionsPerFrame(_) => TIMER_RATE,
+        } as u64;
+        self.owed += elapsed
+            .saturating_mul(rate)
+            .saturating_mul(self.fast_forward as u64);
+        let due = self.owed / NANOS_PER_SECOND;
+        self.owed %= NANOS_PER_SECOND;
+        match self.speed {
+            Speed::InstructionsPerSecond(_) => self.run_cycles(due, run),
+            Speed::InstructionsPerFrame(_) => self.run_frames_inner(due, run),
+        }
+    }
+
+    /// Runs `frames` frames' worth of instructions and timer ticks, for
+    /// frontends driven by a 60 Hz interrupt or vsync
+    pub fn run_frames<RANDOM: Random>(
+        &mut self,
+        chip8: &mut Chip8<RANDOM>,
+        frames: u32,
+    ) -> Result<Progress, Chip8Error> {
+        self.run_frames_with(frames, |step| run_step(chip8, step))
+    }
+
+    /// Like `run_frames`, letting `run` carry out every step
+    pub fn run_frames_with<E, F>(&mut self, frames: u32, run: F) -> Result<Progress, E>
+    where
+        F: FnMut(Step) -> Result<(), E>,
+    {
+        if self.paused {
+            return Ok(Progress::default());
+        }
+        let max_frames = (nanos(self.max_catch_up).saturating_mul(TIMER_RATE as u64))
+            .div_ceil(NANOS_PER_SECOND)
+            .max(1);
+        let frames = (frames as u64).min(max_frames) * self.fast_forward as u64;
+        self.run_frames_inner(frames, run)
+    }
+
+    fn run_frames_inner<E, F>(&mut self, frames: u64, mut run: F) -> Result<Progress, E>
+    where
+        F: FnMut(Step) -> Result<(), E>,
+    {
+        let mut progress = Progress::default();
+        for _ in 0..frames {
+            match self.speed {
+                Speed::InstructionsPerSecond(rate) => {
+                    // Cycles run until the next tick is due. Below `TIMER_RATE`
+                    // a cycle spans several ticks, and the later ones are left
+                    // for the following frames.
+                    while self.tick_progress < rate {
+                        run(Step::Cycle)?;
+                        progress.cycles += 1;
+                        self.tick_progress += TIMER_RATE;
+                    }
+                    self.tick_progress -= rate;
+                }
+                Speed::InstructionsPerFrame(count) => {
+                    let result = self.run_cycles(count as u64, &mut run)?;
+                    progress.cycles += result.cycles;
+                }
+            }
+            run(Step::TimerTick)?;
+            progress.frames += 1;
+        }
+        Ok(progress)
+    }
+
+    /// Runs `cycles` cycles, ticking the timers in between when instructions
+    /// run at a fixed rate
+    fn run_cycles<E, F>(&mut self, cycles: u64, mut run: F) -> Result<Progress, E>
+    where
+        F: FnMut(Step) -> Result<(), E>,
+    {
+        let mut progress = Progress::default();
+        for _ in 0..cycles {
+            run(Step::Cycle)?;
+            progress.cycles += 1;
+            if let Speed::InstructionsPerSecond(rate) = self.speed {
+                self.tick_progress += TIMER_RATE;
+                while self.tick_progress >= rate {
+                    self.tick_progress -= rate;
+                    run(Step::TimerTick)?;
+                    progress.frames += 1;
+                }
+            }
+        }
+        Ok(progress)
+    }
+}
+
+fn nanos(duration: Duration) -> u64 {
+    u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX)
+}
+
+fn run_step<RANDOM: Random>(chip8: &mut Chip8<RANDOM>, step: Step) -> Result<(), Chip8Error> {
+    match step {
+        Step::Cycle => chip8.run_cycle().map(|_| ()),
+        Step::TimerTick => {
+            chip8.timer_tick();
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,373 @@
+//! Save states in a versioned, checksummed binary format.
+//!
+//! A state is laid out as the magic `R8ST`, a version byte and the machine
+//! state in little endian, followed by a CRC-32 of everything before it.
+
+#[cfg(feature = "alloc")]
+use alloc::vec::Vec;
+use random_trait::Random;
+
+use crate::display::Display;
+use crate::error::StateError;
+use crate::{
+    Chip8, Mode, AUDIO_PATTERN_SIZE, MEMORY_SIZE, NUM_FLAG_REGS, NUM_GENERAL_PURPOSE_REGS,
+    NUM_KEYS, NUM_STACK_FRAMES,
+};
+
+const MAGIC: [u8; 4] = *b"R8ST";
+pub const STATE_VERSION: u8 = 2;
+const HEADER_SIZE: usize = MAGIC.len() + 1;
+const CHECKSUM_SIZE: usize = 4;
+/// Marks that no `WaitForKeyPress` is pending, or no key is to be released
+const NO_KEY: u8 = 0xFF;
+
+/// Size of a save state in bytes
+pub const STATE_SIZE: usize = HEADER_SIZE
+    + 1 // mode
+    + 4 // memory size
+    + NUM_GENERAL_PURPOSE_REGS
+    + 2 // i
+    + 2 // delay and sound timer
+    + 1 // stack pointer
+    + 2 // program counter
+    + NUM_STACK_FRAMES * 2
+    + 1 // key to wait for
+    + 1 // key to release
+    + 2 // keyboard
+    + NUM_FLAG_REGS
+    + 2 // exited and vblank
+    + AUDIO_PATTERN_SIZE
+    + 1 // pitch
+    + Display::STATE_SIZE
+    + 8 // random
+    + MEMORY_SIZE
+    + CHECKSUM_SIZE;
+
+/// A random number generator whose state can be saved and restored
+pub trait RandomState {
+    fn state(&self) -> u64;
+    fn set_state(&mut self, state: u64);
+}
+
+impl<RANDOM> Chip8<RANDOM>
+where
+    RANDOM: Random + RandomState,
+{
+    /// Writes the complete machine state into `buf`, returning the number of
+    /// bytes written. The quirks aren't part of the state.
+    pub fn save_state(&self, buf: &mut [u8]) -> Result<usize, StateError> {
+        self.write_state(buf, self.random.state())
+    }
+
+    /// Restores a state written by `save_state`. The machine is left untouched
+    /// if the state is invalid.
+    pub fn load_state(&mut self, buf: &[u8]) -> Result<(), StateError> {
+        let random = self.read_state(buf)?;
+        self.random.set_state(random);
+        #[cfg(feature = "alloc")]
+        self.reset_history();
+        Ok(())
+    }
+
+    /// The state of the random number generator, to reproduce a run with
+    pub fn random_state(&self) -> u64 {
+        self.random.state()
+    }
+
+    pub fn set_random_state(&mut self, state: u64) {
+        self.random.set_state(state);
+    }
+
+    #[cfg(feature = "alloc")]
+    pub fn snapshot(&self) -> SaveState {
+        let mut bytes = alloc::vec![0; STATE_SIZE];
+        // The buffer has the exact size, so this can't fail
+        let _ = self.save_state(&mut bytes);
+        SaveState(bytes)
+    }
+
+    #[cfg(feature = "alloc")]
+    pub fn restore(&mut self, state: &SaveState) -> Result<(), StateError> {
+        self.load_state(&state.0)
+    }
+}
+
+impl<RANDOM> Chip8<RANDOM>
+where
+    RANDOM: Random,
+{
+    /// Saves everything but the random number generator, whose state is
+    /// passed in instead
+    pub(crate) fn write_state(&self, buf: &mut [u8], random: u64) -> Result<usize, StateError> {
+        if buf.len() < STATE_SIZE {
+            return Err(StateError::BufferTooSmall(STATE_SIZE));
+        }
+        let mut writer = Writer::new(&mut buf[..STATE_SIZE]);
+        writer.bytes(&MAGIC);
+        writer.u8(STATE_VERSION);
+        writer.u8(self.mode as u8);
+        writer.u32(MEMORY_SIZE as u32);
+        writer.bytes(&self.regs);
+        writer.u16(self.i_reg);
+        writer.u8(self.delay_timer_reg);
+        writer.u8(self.sound_timer_reg);
+        writer.u8(self.stack_pointer_reg);
+        writer.u16(self.program_counter_reg);
+        for &frame in &self.stack {
+            writer.u16(frame);
+        }
+        writer.u8(self.key_to_wait_for.unwrap_or(NO_KEY));
+        writer.u8(self.key_to_release.unwrap_or(NO_KEY));
+        writer.u16(self.keypad());
+        writer.bytes(&self.flag_regs);
+        writer.u8(self.exited as u8);
+        writer.u8(self.vblank as u8);
+        writer.bytes(&self.audio_pattern);
+        writer.u8(self.pitch);
+        self.display.save_state(&mut writer);
+        writer.u64(random);
+        writer.bytes(&self.memory);
+        let checksum = crc32(&buf[..STATE_SIZE - CHECKSUM_SIZE]);
+        buf[STATE_SIZE - CHECKSUM_SIZE..STATE_SIZE].copy_from_slice(&checksum.to_le_bytes());
+        Ok(STATE_SIZE)
+    }
+
+    /// Restores everything but the random number generator, returning the
+    /// generator's saved state
+    pub(crate) fn read_state(&mut self, buf: &[u8]) -> Result<u64, StateError> {
+        let state = check(buf)?;
+        let mut reader = Reader::new(&state[HEADER_SIZE..]);
+        let mode = match reader.u8() {
+            0 => Mode::Chip8,
+            1 => Mode::SuperChip,
+            2 => Mode::XoChip,
+            _ => return Err(StateError::Corrupt),
+        };
+        let memory_size = reader.u32() as usize;
+        if memory_size != MEMORY_SIZE {
+            return Err(StateError::MemorySizeMismatch(memory_size));
+        }
+        let mut regs = [0; NUM_GENERAL_PURPOSE_REGS];
+        regs.copy_from_slice(reader.bytes(NUM_GENERAL_PURPOSE_REGS));
+        let i_reg = reader.u16();
+        let delay_timer_reg = reader.u8();
+        let sound_timer_reg = reader.u8();
+        let stack_pointer_reg = reader.u8();
+        let program_counter_reg = reader.u16();
+        let mut stack = [0; NUM_STACK_FRAMES];
+        for frame in stack.iter_mut() {
+            *frame = reader.u16();
+        }
+        let key_to_wait_for = match reader.u8() {
+            NO_KEY => None,
+            reg if (reg as usize) < NUM_GENERAL_PURPOSE_REGS => Some(reg),
+            _ => return Err(StateError::Corrupt),
+        };
+        let key_to_release = match reader.u8() {
+            NO_KEY => None,
+            key if (key as usize) < NUM_KEYS => Some(key),
+            _ => return Err(StateError::Corrupt),
+        };
+        let keyboard = reader.u16();
+        let mut flag_regs = [0; NUM_FLAG_REGS];
+        flag_regs.copy_from_slice(reader.bytes(NUM_FLAG_REGS));
+        let exited = reader.u8() != 0;
+        let vblank = reader.u8() != 0;
+        let mut audio_pattern = [0; AUDIO_PATTERN_SIZE];
+        audio_pattern.copy_from_slice(reader.bytes(AUDIO_PATTERN_SIZE));
+        let pitch = reader.u8();
+        if stack_pointer_reg as usize > NUM_STACK_FRAMES {
+            return Err(StateError::Corrupt);
+        }
+        // Nothing can fail past this point
+        self.display.load_state(&mut reader);
+        let random = reader.u64();
+        self.memory.copy_from_slice(reader.bytes(MEMORY_SIZE));
+
+        self.mode = mode;
+        self.regs = regs;
+        self.i_reg = i_reg;
+        self.delay_timer_reg = delay_timer_reg;
+        self.sound_timer_reg = sound_timer_reg;
+        self.stack_pointer_reg = stack_pointer_reg;
+        self.program_counter_reg = program_counter_reg;
+        self.stack = stack;
+        self.key_to_wait_for = key_to_wait_for;
+        self.key_to_release = key_to_release;
+        for (key, pressed) in self.keyboard.iter_mut().enumerate().take(NUM_KEYS) {
+            *pressed = keyboard & (1 << key) != 0;
+        }
+        self.flag_regs = flag_regs;
+        self.exited = exited;
+        self.vblank = vblank;
+        self.audio_pattern = audio_pattern;
+        self.pitch = pitch;
+        Ok(random)
+    }
+}
+
+/// Checks the header and checksum, returning the state without its checksum
+fn check(buf: &[u8]) -> Result<&[u8], StateError> {
+    if buf.len() < HEADER_SIZE || buf[..MAGIC.len()] != MAGIC {
+        return Err(StateError::NotASaveState);
+    }
+    let version = buf[MAGIC.len()];
+    if version != STATE_VERSION {
+        return Err(StateError::UnsupportedVersion(version));
+    }
+    if buf.len() < STATE_SIZE {
+        return Err(StateError::BufferTooSmall(STATE_SIZE));
+    }
+    let (state, checksum) = buf[..STATE_SIZE].split_at(STATE_SIZE - CHECKSUM_SIZE);
+    if crc32(state).to_le_bytes() != checksum {
+        return Err(StateError::ChecksumMismatch);
+    }
+    Ok(state)
+}
+
+/// A save state owning its bytes, serializable with the `serde` feature
+#[cfg(feature = "alloc")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SaveState(Vec<u8>);
+
+#[cfg(feature = "alloc")]
+impl SaveState {
+    /// Checks the header and checksum of a state read from somewhere else
+    pub fn from_bytes(bytes: Vec<u8>) -> Result<SaveState, StateError> {
+        check(&bytes)?;
+        Ok(SaveState(bytes))
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for SaveState {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(&self.0)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for SaveState {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<SaveState, D::Error> {
+        use core::fmt;
+        use serde::de::{self, SeqAccess, Visitor};
+
+        struct BytesVisitor;
+
+        impl<'de> Visitor<'de> for BytesVisitor {
+            type Value = Vec<u8>;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a save state")
+            }
+
+            fn visit_bytes<E: de::Error>(self, bytes: &[u8]) -> Result<Vec<u8>, E> {
+                Ok(bytes.to_vec())
+            }
+
+            fn visit_byte_buf<E: de::Error>(self, bytes: Vec<u8>) -> Result<Vec<u8>, E> {
+                Ok(bytes)
+            }
+
+            // Formats without a byte type write them as a sequence
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Vec<u8>, A::Error> {
+                let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+                while let Some(byte) = seq.next_element()? {
+                    bytes.push(byte);
+                }
+                Ok(bytes)
+            }
+        }
+
+        let bytes = deserializer.deserialize_byte_buf(BytesVisitor)?;
+        SaveState::from_bytes(bytes).map_err(de::Error::custom)
+    }
+}
+
+pub(crate) struct Writer<'a> {
+    buf: &'a mut [u8],
+    pos: usize,
+}
+
+impl<'a> Writer<'a> {
+    fn new(buf: &'a mut [u8]) -> Writer<'a> {
+        Writer { buf, pos: 0 }
+    }
+
+    pub(crate) fn bytes(&mut self, bytes: &[u8]) {
+        self.buf[self.pos..self.pos + bytes.len()].copy_from_slice(bytes);
+        self.pos += bytes.len();
+    }
+
+    pub(crate) fn u8(&mut self, value: u8) {
+        self.bytes(&[value]);
+    }
+
+    fn u16(&mut self, value: u16) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    fn u64(&mut self, value: u64) {
+        self.bytes(&value.to_le_bytes());
+    }
+}
+
+/// Reads from a state whose size was already checked
+pub(crate) struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(buf: &'a [u8]) -> Reader<'a> {
+        Reader { buf, pos: 0 }
+    }
+
+    pub(crate) fn bytes(&mut self, len: usize) -> &'a [u8] {
+        let bytes = &self.buf[self.pos..self.pos + len];
+        self.pos += len;
+        bytes
+    }
+
+    pub(crate) fn u8(&mut self) -> u8 {
+        self.bytes(1)[0]
+    }
+
+    fn u16(&mut self) -> u16 {
+        let mut bytes = [0; 2];
+        bytes.copy_from_slice(self.bytes(2));
+        u16::from_le_bytes(bytes)
+    }
+
+    fn u32(&mut self) -> u32 {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.bytes(4));
+        u32::from_le_bytes(bytes)
+    }
+
+    fn u64(&mut self) -> u64 {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.bytes(8));
+        u64::from_le_bytes(bytes)
+    }
+}
+
+/// CRC-32 as used by zlib and PNG
+pub(crate) fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xEDB8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
@@ -0,0 +1,125 @@
+//! Per-instruction execution traces.
+//!
+//! A `TraceEntry` formats as one line of text, which is meant to stay stable
+//! so traces can be diffed against earlier runs or other emulators:
+//!
+//! ```text
+//! CYCLE PC: OPCODE V=V0..VF I=I -> V=V0..VF I=I ; MNEMONIC
+//! 42 022A: 6A02 V=00010000000000000000000000000000 I=0300 -> V=00010000000000000000020000000000 I=0300 ; LD VA, 0x02
+//! ```
+//!
+//! The cycle is in decimal, everything else in upper case hex. The registers
+//! are written as 16 bytes without separators, once before and once after
+//! the instruction. For long XO-CHIP instructions only the first word is
+//! written as the opcode.
+
+use core::fmt;
+
+use random_trait::Random;
+
+use crate::error::{Chip8Error, StepOutcome};
+use crate::instruction::Instruction;
+use crate::{Chip8, NUM_GENERAL_PURPOSE_REGS};
+
+/// The registers an instruction can change
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Registers {
+    pub v: [u8; NUM_GENERAL_PURPOSE_REGS],
+    pub i: u16,
+}
+
+/// An instruction that ran, and how it changed the registers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceEntry {
+    /// The number of cycles run before this one
+    pub cycle: u64,
+    pub pc: u16,
+    pub opcode: u16,
+    pub instruction: Instruction,
+    pub before: Registers,
+    pub after: Registers,
+}
+
+impl fmt::Display for TraceEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {:04X}: {:04X} {} -> {} ; {}",
+            self.cycle, self.pc, self.opcode, self.before, self.after, self.instruction
+        )
+    }
+}
+
+impl fmt::Display for Registers {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("V=")?;
+        for value in &self.v {
+            write!(f, "{:02X}", value)?;
+        }
+        write!(f, " I={:04X}", self.i)
+    }
+}
+
+/// Receives an entry for every instruction that runs
+pub trait Tracer {
+    fn trace(&mut self, entry: &TraceEntry);
+}
+
+impl<F> Tracer for F
+where
+    F: FnMut(&TraceEntry),
+{
+    fn trace(&mut self, entry: &TraceEntry) {
+        self(entry)
+    }
+}
+
+impl<RANDOM> Chip8<RANDOM>
+where
+    RANDOM: Random,
+{
+    /// Runs a cycle like `run_cycle`, passing the instruction to `tracer` if
+    /// one ran. Cycles spent waiting or halted aren't traced.
+    pub fn run_cycle_traced<T>(&mut self, tracer: &mut T) -> Result<StepOutcome, Chip8Error>
+    where
+        T: Tracer + ?Sized,
+    {
+        let cycle = self.cycles;
+        let pc = self.program_counter_reg;
+        let before = self.registers();
+        // Fetched up front, the instruction might overwrite itself
+        let fetched = self.fetch().ok();
+        let outcome = self.execute_cycle()?;
+        self.end_cycle();
+        if let (StepOutcome::Ran, Some((instruction, opcode))) = (outcome, fetched) {
+            tracer.trace(&TraceEntry {
+                cycle,
+                pc,
+                opcode,
+                instruction,
+                before,
+                after: self.registers(),
+            });
+        }
+        Ok(outcome)
+    }
+
+    /// Traces every instruction run by `run_cycle` from now on
+    #[cfg(feature = "alloc")]
+    pub fn set_tracer(&mut self, tracer: alloc::boxed::Box<dyn Tracer + Send>) {
+        self.tracer = Some(tracer);
+    }
+
+    /// Stops tracing, handing back the tracer
+    #[cfg(feature = "alloc")]
+    pub fn take_tracer(&mut self) -> Option<alloc::boxed::Box<dyn Tracer + Send>> {
+        self.tracer.take()
+    }
+
+    fn registers(&self) -> Registers {
+        Registers {
+            v: self.regs,
+            i: self.i_reg,
+        }
+    }
+}